@@ -0,0 +1,162 @@
+//! Extraction of ROM images from .zip, .gz and .7z archives.
+
+use anyhow::{anyhow, bail, Result};
+use log::warn;
+use std::io::{Cursor, Read};
+
+const ZIP_MAGIC: &[u8] = b"PK\x03\x04";
+const GZIP_MAGIC: &[u8] = b"\x1F\x8B";
+const SEVENZ_MAGIC: &[u8] = b"7z\xBC\xAF\x27\x1C";
+
+// Largest cartridge ROM, anything bigger is not a GBA image
+const MAX_ROM_SIZE: u64 = 32 * 1024 * 1024;
+
+/// Returns the ROM image in `data`, extracting it if `data` is an archive.
+/// Archives must contain a `.gba` file; if there are several, the first one
+/// is used.
+pub fn extract_rom(data: &[u8]) -> Result<Vec<u8>> {
+    if data.starts_with(ZIP_MAGIC) {
+        extract_zip(data)
+    } else if data.starts_with(GZIP_MAGIC) {
+        read_limited(flate2::read::GzDecoder::new(data))
+    } else if data.starts_with(SEVENZ_MAGIC) {
+        extract_7z(data)
+    } else {
+        Ok(data.to_vec())
+    }
+}
+
+fn is_rom_name(name: &str) -> bool {
+    name.to_ascii_lowercase().ends_with(".gba")
+}
+
+fn pick_one<T>(mut found: Vec<(String, T)>) -> Result<T> {
+    if found.len() > 1 {
+        warn!(
+            "Archive contains multiple ROMs, using {}: {:?}",
+            found[0].0,
+            found.iter().map(|(name, _)| name).collect::<Vec<_>>()
+        );
+    }
+    if found.is_empty() {
+        bail!("No .gba file in archive");
+    }
+    Ok(found.swap_remove(0).1)
+}
+
+fn read_limited(r: impl Read) -> Result<Vec<u8>> {
+    let mut ret = vec![];
+    r.take(MAX_ROM_SIZE + 1).read_to_end(&mut ret)?;
+    if ret.len() as u64 > MAX_ROM_SIZE {
+        bail!("ROM is larger than {MAX_ROM_SIZE} bytes");
+    }
+    Ok(ret)
+}
+
+fn extract_zip(data: &[u8]) -> Result<Vec<u8>> {
+    let mut zip = zip::ZipArchive::new(Cursor::new(data))?;
+
+    let found = (0..zip.len())
+        .filter_map(|i| {
+            let name = zip.name_for_index(i)?;
+            is_rom_name(name).then(|| (name.to_string(), i))
+        })
+        .collect();
+    let index = pick_one(found)?;
+
+    let ret = read_limited(zip.by_index(index)?)?;
+    Ok(ret)
+}
+
+fn extract_7z(data: &[u8]) -> Result<Vec<u8>> {
+    let mut archive = sevenz_rust::SevenZReader::new(
+        Cursor::new(data),
+        data.len() as u64,
+        sevenz_rust::Password::empty(),
+    )?;
+
+    // Entries can only be read in archive order, so take the first match
+    let mut found = None;
+    archive.for_each_entries(|entry, r| {
+        if entry.is_directory() || !is_rom_name(entry.name()) {
+            return Ok(true);
+        }
+        if entry.size() > MAX_ROM_SIZE {
+            warn!("Skipping oversized entry: {}", entry.name());
+            return Ok(true);
+        }
+        let mut buf = vec![];
+        r.read_to_end(&mut buf)?;
+        found = Some(buf);
+        Ok(false)
+    })?;
+
+    found.ok_or_else(|| anyhow!("No .gba file in archive"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    fn zip(files: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut zip = zip::ZipWriter::new(Cursor::new(vec![]));
+        for (name, data) in files {
+            zip.start_file(*name, zip::write::SimpleFileOptions::default())
+                .unwrap();
+            zip.write_all(data).unwrap();
+        }
+        zip.finish().unwrap().into_inner()
+    }
+
+    #[test]
+    fn raw_rom() {
+        assert_eq!(extract_rom(b"rom image").unwrap(), b"rom image");
+    }
+
+    #[test]
+    fn zip_picks_first_rom() {
+        let data = zip(&[
+            ("readme.txt", b"text"),
+            ("game.GBA", b"first"),
+            ("game (alt).gba", b"second"),
+        ]);
+        assert_eq!(extract_rom(&data).unwrap(), b"first");
+    }
+
+    #[test]
+    fn zip_without_rom() {
+        let data = zip(&[("readme.txt", b"text")]);
+        assert!(extract_rom(&data).is_err());
+    }
+
+    #[test]
+    fn gzip() {
+        let mut enc = flate2::write::GzEncoder::new(vec![], flate2::Compression::fast());
+        enc.write_all(b"rom image").unwrap();
+        let data = enc.finish().unwrap();
+        assert_eq!(extract_rom(&data).unwrap(), b"rom image");
+    }
+
+    #[test]
+    fn gzip_too_large() {
+        let mut enc = flate2::write::GzEncoder::new(vec![], flate2::Compression::fast());
+        enc.write_all(&vec![0; MAX_ROM_SIZE as usize + 1]).unwrap();
+        let data = enc.finish().unwrap();
+        assert!(extract_rom(&data).is_err());
+    }
+
+    #[test]
+    fn sevenz() {
+        // LZMA2 archive with readme.txt and game.gba ("rom image")
+        let data = include_bytes!("../testdata/rom.7z");
+        assert_eq!(extract_rom(data).unwrap(), b"rom image");
+    }
+
+    #[test]
+    fn broken_7z() {
+        let mut data = SEVENZ_MAGIC.to_vec();
+        data.extend_from_slice(&[0; 26]);
+        assert!(extract_rom(&data).is_err());
+    }
+}
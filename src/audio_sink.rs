@@ -0,0 +1,197 @@
+//! Push-based audio output, and a ring buffer that keeps a frontend's
+//! audio device fed without drifting.
+//!
+//! The emulator produces audio at the rate of its own clock, which is
+//! never exactly that of the audio device. `AudioRing` compensates with
+//! dynamic rate control: samples are resampled by a ratio slightly above or
+//! below 1 depending on how full the ring is, which keeps it half full
+//! without audible pitch changes.
+
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+};
+
+use crate::interface::AudioSample;
+
+/// Receives audio as the emulator produces it
+pub trait AudioSink {
+    fn push_samples(&mut self, samples: &[AudioSample]);
+}
+
+/// Largest adjustment of the resampling ratio by default, 0.5%
+pub const DEFAULT_MAX_RATE_DELTA: f64 = 0.005;
+
+/// Ring buffer shared between the emulator, which writes to it through
+/// `AudioRingWriter`, and the audio device callback, which reads from it
+#[derive(Clone)]
+pub struct AudioRing {
+    inner: Arc<Mutex<VecDeque<AudioSample>>>,
+    capacity: usize,
+}
+
+impl AudioRing {
+    /// `capacity` in samples; the ring is kept half full, so half of it is
+    /// the added latency
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(VecDeque::with_capacity(capacity))),
+            capacity,
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Fill level in 0.0..=1.0
+    pub fn fill_level(&self) -> f64 {
+        self.len() as f64 / self.capacity as f64
+    }
+
+    /// Fills `out`, with silence on underrun. Returns the number of samples
+    /// taken from the ring.
+    pub fn read(&self, out: &mut [AudioSample]) -> usize {
+        let mut ring = self.inner.lock().unwrap();
+        let n = out.len().min(ring.len());
+        for (o, s) in out.iter_mut().zip(ring.drain(..n)) {
+            *o = s;
+        }
+        for o in &mut out[n..] {
+            *o = AudioSample::new(0, 0);
+        }
+        n
+    }
+
+    pub fn writer(&self) -> AudioRingWriter {
+        AudioRingWriter {
+            ring: self.clone(),
+            max_delta: DEFAULT_MAX_RATE_DELTA,
+            pos: 0.0,
+            prev: [0.0; 2],
+            scratch: vec![],
+        }
+    }
+}
+
+/// Writing end of an `AudioRing`, to be passed to `Agb::set_audio_sink`
+pub struct AudioRingWriter {
+    ring: AudioRing,
+    max_delta: f64,
+    // Position of the next output sample, relative to `prev`
+    pos: f64,
+    prev: [f64; 2],
+    // Resampled output, kept to avoid allocating on every push
+    scratch: Vec<AudioSample>,
+}
+
+impl AudioRingWriter {
+    pub fn set_max_rate_delta(&mut self, max_delta: f64) {
+        self.max_delta = max_delta;
+    }
+}
+
+impl AudioSink for AudioRingWriter {
+    fn push_samples(&mut self, samples: &[AudioSample]) {
+        // The ring is only locked to read its level and to append, so the
+        // audio callback is never blocked while resampling
+        let step = rate_step(self.ring.fill_level(), self.max_delta);
+
+        // Linear interpolation is enough for ratios this close to 1
+        self.scratch.clear();
+        for s in samples {
+            let cur = [s.right as f64, s.left as f64];
+            while self.pos < 1.0 {
+                let [r, l] = [0, 1].map(|i| self.prev[i] + (cur[i] - self.prev[i]) * self.pos);
+                self.scratch
+                    .push(AudioSample::new(r.round() as i16, l.round() as i16));
+                self.pos += step;
+            }
+            self.pos -= 1.0;
+            self.prev = cur;
+        }
+
+        // On overrun the newest samples are dropped
+        let mut ring = self.ring.inner.lock().unwrap();
+        let room = self.ring.capacity.saturating_sub(ring.len());
+        ring.extend(self.scratch.iter().take(room).copied());
+    }
+}
+
+// Input samples consumed per output sample: above 1 shrinks the output to
+// drain a ring that is more than half full
+fn rate_step(fill: f64, max_delta: f64) -> f64 {
+    1.0 + (2.0 * fill.min(1.0) - 1.0) * max_delta
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CHUNK: usize = 512;
+
+    fn chunk() -> Vec<AudioSample> {
+        (0..CHUNK)
+            .map(|i| AudioSample::new(i as i16, -(i as i16)))
+            .collect()
+    }
+
+    #[test]
+    fn step_within_max_delta() {
+        for fill in [0.0, 0.25, 0.5, 0.75, 1.0, 1.5] {
+            let step = rate_step(fill, DEFAULT_MAX_RATE_DELTA);
+            assert!(
+                (step - 1.0).abs() <= DEFAULT_MAX_RATE_DELTA + 1e-12,
+                "{fill}"
+            );
+        }
+        assert_eq!(rate_step(0.5, DEFAULT_MAX_RATE_DELTA), 1.0);
+        assert!(rate_step(0.0, DEFAULT_MAX_RATE_DELTA) < 1.0);
+        assert!(rate_step(1.0, DEFAULT_MAX_RATE_DELTA) > 1.0);
+    }
+
+    // Emulator and audio device at the same rate, starting from the given
+    // fill level
+    fn settle(initial: usize) -> f64 {
+        let ring = AudioRing::new(4 * CHUNK);
+        ring.inner
+            .lock()
+            .unwrap()
+            .extend(std::iter::repeat_n(AudioSample::new(0, 0), initial));
+        let mut writer = ring.writer();
+        let mut out = vec![AudioSample::new(0, 0); CHUNK];
+
+        for _ in 0..4_000 {
+            writer.push_samples(&chunk());
+            ring.read(&mut out);
+        }
+        ring.fill_level()
+    }
+
+    #[test]
+    fn converges_to_half_full() {
+        for initial in [0, 4 * CHUNK] {
+            let fill = settle(initial);
+            assert!((fill - 0.5).abs() < 0.02, "{initial}: {fill}");
+        }
+    }
+
+    #[test]
+    fn resampled_length() {
+        let ring = AudioRing::new(16 * CHUNK);
+        let mut writer = ring.writer();
+        writer.set_max_rate_delta(0.01);
+        // Empty ring: 1% more samples come out than go in
+        writer.push_samples(&chunk());
+        let n = ring.len() as f64;
+        assert!((n - CHUNK as f64 / 0.99).abs() <= 1.0, "{n}");
+    }
+}
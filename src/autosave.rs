@@ -0,0 +1,117 @@
+use anyhow::{Context as _, Result};
+use std::{
+    fs,
+    io::Write,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use crate::Agb;
+
+#[derive(Clone, Debug)]
+pub struct AutosaveConfig {
+    /// Write once the backup has not changed for this long
+    pub settle: Duration,
+    /// Write at least this often while the game keeps modifying the backup
+    pub interval: Option<Duration>,
+}
+
+impl Default for AutosaveConfig {
+    fn default() -> Self {
+        Self {
+            settle: Duration::from_secs(1),
+            interval: Some(Duration::from_secs(30)),
+        }
+    }
+}
+
+/// Keeps a save file in sync with the backup memory of an `Agb`.
+///
+/// Call `update` after every frame and `flush` before exiting. The file is
+/// replaced atomically, so a crash leaves either the old or the new save.
+pub struct Autosave {
+    path: PathBuf,
+    config: AutosaveConfig,
+    // Times of the first and the latest change not written yet
+    pending: Option<(Instant, Instant)>,
+}
+
+impl Autosave {
+    pub fn new(path: impl Into<PathBuf>, config: AutosaveConfig) -> Self {
+        Self {
+            path: path.into(),
+            config,
+            pending: None,
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Whether there are changes not written yet
+    pub fn is_pending(&self) -> bool {
+        self.pending.is_some()
+    }
+
+    /// Returns true if the file was written
+    pub fn update(&mut self, agb: &mut Agb) -> Result<bool> {
+        let now = Instant::now();
+
+        if agb.backup_dirty() {
+            agb.clear_backup_dirty();
+            let first = self.pending.map_or(now, |(first, _)| first);
+            self.pending = Some((first, now));
+        }
+
+        let Some((first, last)) = self.pending else {
+            return Ok(false);
+        };
+        let settled = now - last >= self.config.settle;
+        let overdue = self
+            .config
+            .interval
+            .is_some_and(|interval| now - first >= interval);
+        if !(settled || overdue) {
+            return Ok(false);
+        }
+
+        self.flush(agb)?;
+        Ok(true)
+    }
+
+    /// Writes the backup now if it has changes
+    pub fn flush(&mut self, agb: &mut Agb) -> Result<()> {
+        if agb.backup_dirty() {
+            agb.clear_backup_dirty();
+        } else if self.pending.is_none() {
+            return Ok(());
+        }
+
+        if let Some(data) = agb.backup() {
+            if let Err(e) = write_atomic(&self.path, &data) {
+                // Retry on the next update
+                let now = Instant::now();
+                self.pending.get_or_insert((now, now));
+                return Err(e);
+            }
+        }
+        self.pending = None;
+        Ok(())
+    }
+}
+
+fn write_atomic(path: &Path, data: &[u8]) -> Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+
+    let mut f =
+        fs::File::create(&tmp).with_context(|| format!("Failed to create {}", tmp.display()))?;
+    f.write_all(data)?;
+    f.sync_all()?;
+    drop(f);
+
+    fs::rename(&tmp, path).with_context(|| format!("Failed to replace {}", path.display()))?;
+    Ok(())
+}
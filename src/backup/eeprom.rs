@@ -1,10 +1,18 @@
 use log::{debug, error, trace, warn};
 use serde::{Deserialize, Serialize};
 
+use crate::consts::SYSTEM_CLOCK;
+
+// Typical write cycle time of the EEPROM chips used in cartridges
+const WRITE_TIME: u64 = SYSTEM_CLOCK * 65 / 10_000;
+
 #[derive(Serialize, Deserialize)]
 pub struct Eeprom {
     state: EepromState,
     data: Vec<u64>,
+    // Modified since the owner last persisted the data
+    #[serde(skip)]
+    dirty: bool,
 }
 
 #[derive(Debug)]
@@ -19,6 +27,8 @@ pub enum EepromState {
     WaitForAddr { read: bool, addr: u32, pos: u32 },
     Reading { addr: u32, pos: u32, ready: bool },
     Writing { addr: u32, pos: u32, buf: u64 },
+    // Writing to the cell array until the given cycle, reads return 0
+    Busy { until: u64 },
 }
 
 impl Eeprom {
@@ -38,6 +48,7 @@ impl Eeprom {
                 },
             ),
             state: EepromState::WaitForCommand { step: 0 },
+            dirty: false,
         }
     }
 
@@ -68,7 +79,18 @@ impl Eeprom {
             );
         }
 
-        self.data.resize(words, 0);
+        if self.data.len() != words {
+            self.data.resize(words, 0);
+            self.dirty = true;
+        }
+    }
+
+    pub fn dirty(&self) -> bool {
+        self.dirty
+    }
+
+    pub fn set_dirty(&mut self, dirty: bool) {
+        self.dirty = dirty;
     }
 
     pub fn data(&self) -> Vec<u8> {
@@ -88,14 +110,26 @@ impl Eeprom {
         }
     }
 
-    pub fn read(&mut self) -> bool {
+    fn update_busy(&mut self, now: u64) {
+        if let EepromState::Busy { until } = self.state {
+            if now >= until {
+                debug!("Write command done");
+                self.state = EepromState::WaitForCommand { step: 0 };
+            }
+        }
+    }
+
+    pub fn read(&mut self, now: u64) -> bool {
+        self.update_busy(now);
+
         let data = match &mut self.state {
             EepromState::WaitForCommand { .. } => true,
             EepromState::WaitForAddr { .. } => false,
-            EepromState::Reading { addr, pos, ready } => {
-                if !*ready {
-                    panic!("Not ready for reading");
-                }
+            EepromState::Reading { ready: false, .. } => {
+                warn!("Read before the end of read command");
+                true
+            }
+            EepromState::Reading { addr, pos, .. } => {
                 if *pos < 4 {
                     *pos += 1;
                     false
@@ -110,15 +144,18 @@ impl Eeprom {
                 }
             }
             EepromState::Writing { .. } => false,
+            EepromState::Busy { .. } => false,
         };
 
         trace!("Read {:?}", data as u8);
         data
     }
 
-    pub fn write(&mut self, data: bool) {
+    pub fn write(&mut self, data: bool, now: u64) {
         trace!("Write {:?}", data as u8);
 
+        self.update_busy(now);
+
         let addr_len = self.addr_len();
 
         match &mut self.state {
@@ -168,7 +205,8 @@ impl Eeprom {
                     }
                     *ready = true;
                 } else {
-                    panic!("Invalid write while read command");
+                    warn!("Write while read command, aborting read");
+                    self.state = EepromState::WaitForCommand { step: data as u32 };
                 }
             }
             EepromState::Writing { addr, pos, buf } => {
@@ -178,12 +216,18 @@ impl Eeprom {
                     }
                     debug!("Write 0x{:03X} = 0x{:016X}", *addr, *buf);
                     self.data[*addr as usize] = *buf;
-                    self.state = EepromState::WaitForCommand { step: 0 };
+                    self.dirty = true;
+                    self.state = EepromState::Busy {
+                        until: now + WRITE_TIME,
+                    };
                 } else {
                     *buf |= (data as u64) << (63 - *pos);
                     *pos += 1;
                 }
             }
+            EepromState::Busy { .. } => {
+                warn!("Write while busy: {}", data as u8);
+            }
         }
     }
 }
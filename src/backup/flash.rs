@@ -1,6 +1,12 @@
 use log::{debug, error, trace, warn};
 use serde::{Deserialize, Serialize};
 
+use crate::consts::SYSTEM_CLOCK;
+
+const fn us(n: u64) -> u64 {
+    n * SYSTEM_CLOCK / 1_000_000
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct Flash {
     state: State,
@@ -8,6 +14,111 @@ pub struct Flash {
     bank: u32,
     #[serde(with = "serde_bytes")]
     data: Vec<u8>,
+    // Part of the cartridge configuration rather than the emulation state,
+    // restored by the owner after deserialization
+    #[serde(skip)]
+    chip: Option<FlashChip>,
+    // Modified since the owner last persisted the data
+    #[serde(skip)]
+    dirty: bool,
+}
+
+// ID     Name       Size  Sectors  AverageTimings  Timeouts/ms   Waits
+// D4BFh  SST        64K   16x4K    20us?,?,?       10,  40, 200  3,2
+// 1CC2h  Macronix   64K   16x4K    ?,?,?           10,2000,2000  8,3
+// 1B32h  Panasonic  64K   16x4K    ?,?,?           10, 500, 500  4,2
+// 3D1Fh  Atmel      64K   512x128  ?,?,?           ...40..,  40  8,8
+// 1362h  Sanyo      128K  ?        ?,?,?           ?    ?    ?    ?
+// 09C2h  Macronix   128K  ?        ?,?,?           ?    ?    ?    ?
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum FlashChip {
+    Sst,
+    Macronix64K,
+    Panasonic,
+    Atmel,
+    Sanyo,
+    Macronix128K,
+}
+
+impl FlashChip {
+    pub const ALL: [FlashChip; 6] = [
+        FlashChip::Sst,
+        FlashChip::Macronix64K,
+        FlashChip::Panasonic,
+        FlashChip::Atmel,
+        FlashChip::Sanyo,
+        FlashChip::Macronix128K,
+    ];
+
+    /// The chips emulated when none is configured
+    pub fn default_for_size(size: usize) -> FlashChip {
+        if size == 64 * 1024 {
+            FlashChip::Sst
+        } else {
+            FlashChip::Sanyo
+        }
+    }
+
+    pub fn from_id(id: u16) -> Option<FlashChip> {
+        FlashChip::ALL.into_iter().find(|c| c.id() == id)
+    }
+
+    /// Device code in the high byte, manufacturer in the low byte
+    pub fn id(&self) -> u16 {
+        match self {
+            FlashChip::Sst => 0xD4BF,
+            FlashChip::Macronix64K => 0x1CC2,
+            FlashChip::Panasonic => 0x1B32,
+            FlashChip::Atmel => 0x3D1F,
+            FlashChip::Sanyo => 0x1362,
+            FlashChip::Macronix128K => 0x09C2,
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        match self {
+            FlashChip::Sanyo | FlashChip::Macronix128K => 128 * 1024,
+            _ => 64 * 1024,
+        }
+    }
+
+    /// Unit of the sector erase command. Atmel chips are organized in
+    /// 128 byte pages and are written a page at a time.
+    pub fn sector_size(&self) -> usize {
+        match self {
+            FlashChip::Atmel => 128,
+            _ => 0x1000,
+        }
+    }
+
+    // Busy periods in cycles, typical values from the datasheets.
+    // Atmel programs a whole page at once.
+
+    fn program_time(&self) -> u64 {
+        match self {
+            FlashChip::Atmel => us(10_000),
+            _ => us(20),
+        }
+    }
+
+    fn sector_erase_time(&self) -> u64 {
+        match self {
+            FlashChip::Atmel => us(10_000),
+            _ => us(25_000),
+        }
+    }
+
+    // An Atmel page is programmed when no byte follows within this time
+    fn page_load_timeout(&self) -> u64 {
+        us(150)
+    }
+
+    fn chip_erase_time(&self) -> u64 {
+        match self {
+            FlashChip::Atmel => us(20_000),
+            _ => us(100_000),
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -15,6 +126,21 @@ enum State {
     WaitForCommand(usize, CommandContext),
     WriteSingleByte,
     BankChange,
+    // Atmel page write: number of bytes written so far, and the cycle and
+    // value of the last one
+    WritePage {
+        count: usize,
+        last_write: u64,
+        last_data: u8,
+    },
+    // Programming or erasing until the given cycle. Reads return status
+    // bits instead of data: DQ7 is the inverse of bit 7 of `expect` and DQ6
+    // toggles on every read.
+    Busy {
+        until: u64,
+        expect: u8,
+        toggle: bool,
+    },
 }
 
 #[derive(PartialEq, Eq, Debug, Serialize, Deserialize)]
@@ -30,7 +156,8 @@ enum ReadMode {
 }
 
 impl Flash {
-    pub fn new(size: usize, backup: Option<Vec<u8>>) -> Self {
+    pub fn new(chip: FlashChip, backup: Option<Vec<u8>>) -> Self {
+        let size = chip.size();
         let data = if let Some(backup) = backup {
             if backup.len() != size {
                 error!(
@@ -51,9 +178,27 @@ impl Flash {
             read_mode: ReadMode::Data,
             bank: 0,
             data,
+            chip: Some(chip),
+            dirty: false,
         }
     }
 
+    pub fn chip(&self) -> FlashChip {
+        self.chip
+            .unwrap_or_else(|| FlashChip::default_for_size(self.data.len()))
+    }
+
+    pub fn set_chip(&mut self, chip: FlashChip) {
+        if chip.size() != self.data.len() {
+            warn!(
+                "Flash chip {chip:?} does not match the backup size: {} bytes",
+                self.data.len()
+            );
+            return;
+        }
+        self.chip = Some(chip);
+    }
+
     pub fn backup_type(&self) -> &'static str {
         if self.data.len() == 64 * 1024 {
             "FLASH (512K)"
@@ -64,47 +209,102 @@ impl Flash {
         }
     }
 
+    pub fn size(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn dirty(&self) -> bool {
+        self.dirty
+    }
+
+    pub fn set_dirty(&mut self, dirty: bool) {
+        self.dirty = dirty;
+    }
+
     pub fn data(&self) -> Vec<u8> {
         self.data.clone()
     }
 
-    pub fn read(&mut self, addr: u32) -> u8 {
+    fn start_busy(&mut self, now: u64, time: u64, expect: u8) {
+        self.state = State::Busy {
+            until: now + time,
+            expect,
+            toggle: false,
+        };
+    }
+
+    /// Returns true while a program or erase operation is in progress
+    fn update_busy(&mut self, now: u64) -> bool {
+        if let State::WritePage {
+            count,
+            last_write,
+            last_data,
+        } = self.state
+        {
+            let timeout = last_write + self.chip().page_load_timeout();
+            if now >= timeout {
+                debug!("Write partial page: {count} bytes");
+                self.start_busy(timeout, self.chip().program_time(), last_data);
+            }
+        }
+
+        match self.state {
+            State::Busy { until, .. } if now >= until => {
+                trace!("Flash ready");
+                self.state = State::WaitForCommand(0, CommandContext::None);
+                false
+            }
+            State::Busy { .. } => true,
+            _ => false,
+        }
+    }
+
+    /// Reads the memory array regardless of the chip state
+    pub fn peek(&self, addr: u32) -> u8 {
+        self.data[self.bank as usize * 0x10000 + (addr as usize & 0xFFFF)]
+    }
+
+    /// Writes the memory array directly, bypassing the command protocol
+    pub fn poke(&mut self, addr: u32, data: u8) {
+        let ix = self.bank as usize * 0x10000 + (addr as usize & 0xFFFF);
+        if self.data[ix] != data {
+            self.data[ix] = data;
+            self.dirty = true;
+        }
+    }
+
+    pub fn read(&mut self, addr: u32, now: u64) -> u8 {
+        if self.update_busy(now) {
+            if let State::Busy { expect, toggle, .. } = &mut self.state {
+                *toggle = !*toggle;
+                return (!*expect & 0x80) | (*toggle as u8) << 6;
+            }
+        }
+
         let addr = addr & 0xFFFF;
         match &mut self.read_mode {
             ReadMode::ChipId => {
-                // ID     Name       Size  Sectors  AverageTimings  Timeouts/ms   Waits
-                // D4BFh  SST        64K   16x4K    20us?,?,?       10,  40, 200  3,2
-                // 1CC2h  Macronix   64K   16x4K    ?,?,?           10,2000,2000  8,3
-                // 1B32h  Panasonic  64K   16x4K    ?,?,?           10, 500, 500  4,2
-                // 3D1Fh  Atmel      64K   512x128  ?,?,?           ...40..,  40  8,8
-                // 1362h  Sanyo      128K  ?        ?,?,?           ?    ?    ?    ?
-                // 09C2h  Macronix   128K  ?        ?,?,?           ?    ?    ?    ?
-
-                if self.data.len() == 64 * 1024 {
-                    // Emulate SST for 64KB Flash
-                    match addr {
-                        0x0000 => 0xBF,
-                        0x0001 => 0xD4,
-                        _ => 0,
-                    }
-                } else {
-                    // Emulate Sanyo for 128KB Flash
-                    match addr {
-                        0x0000 => 0x62,
-                        0x0001 => 0x13,
-                        _ => 0,
-                    }
+                let id = self.chip().id();
+                match addr {
+                    0x0000 => id as u8,
+                    0x0001 => (id >> 8) as u8,
+                    _ => 0,
                 }
             }
-            ReadMode::Data => self.data[self.bank as usize * 0x10000 + (addr as usize & 0xFFFF)],
+            ReadMode::Data => self.peek(addr),
         }
     }
 
-    pub fn write(&mut self, addr: u32, data: u8) {
+    pub fn write(&mut self, addr: u32, data: u8, now: u64) {
         let addr = addr & 0xFFFF;
 
         trace!("Write Flash: 0x{addr:04X} = 0x{data:02X}");
 
+        if self.update_busy(now) {
+            warn!("Write while busy: 0x{addr:04X} = 0x{data:02X}");
+            return;
+        }
+
         match &mut self.state {
             State::WaitForCommand(step, ctx) => match (*step, addr, data) {
                 (0, 0x5555, 0xAA) => *step = 1,
@@ -131,16 +331,27 @@ impl Flash {
                 (2, 0x5555, 0x10) if *ctx == CommandContext::Erase => {
                     debug!("Erase entire chip");
                     self.data.fill(0xFF);
-                    self.state = State::WaitForCommand(0, CommandContext::None);
+                    self.dirty = true;
+                    self.start_busy(now, self.chip().chip_erase_time(), 0xFF);
                 }
                 (2, _, 0x30) if *ctx == CommandContext::Erase => {
-                    let sector = (addr >> 12) as usize;
+                    let sector_size = self.chip().sector_size();
+                    let sector = addr as usize / sector_size;
                     debug!("Erase sector {sector}");
-                    let start = self.bank as usize * 0x10000 + sector * 0x1000;
-                    self.data[start..start + 0x1000].fill(0xFF);
-                    self.state = State::WaitForCommand(0, CommandContext::None);
+                    let start = self.bank as usize * 0x10000 + sector * sector_size;
+                    self.data[start..start + sector_size].fill(0xFF);
+                    self.dirty = true;
+                    self.start_busy(now, self.chip().sector_erase_time(), 0xFF);
                 }
 
+                (2, 0x5555, 0xA0) if self.chip() == FlashChip::Atmel => {
+                    trace!("Write page");
+                    self.state = State::WritePage {
+                        count: 0,
+                        last_write: now,
+                        last_data: 0xFF,
+                    };
+                }
                 (2, 0x5555, 0xA0) => {
                     trace!("Write single byte");
                     self.state = State::WriteSingleByte;
@@ -160,8 +371,30 @@ impl Flash {
                 // Only 1 -> 0 write is possible
                 let addr = self.bank as usize * 0x10000 + (addr as usize & 0xFFFF);
                 self.data[addr] &= data;
+                self.dirty = true;
                 debug!("Write single byte: 0x{addr:05X} = 0x{data:02X}");
-                self.state = State::WaitForCommand(0, CommandContext::None);
+                self.start_busy(now, self.chip().program_time(), self.data[addr]);
+            }
+
+            State::WritePage {
+                count,
+                last_write,
+                last_data,
+            } => {
+                // The page is erased before it is programmed, so any value can be written
+                let page = addr as usize & !0x7F;
+                if *count == 0 {
+                    debug!("Write page: 0x{page:04X}");
+                    self.data[page..page + 0x80].fill(0xFF);
+                }
+                self.data[addr as usize] = data;
+                self.dirty = true;
+                *count += 1;
+                *last_write = now;
+                *last_data = data;
+                if *count == 0x80 {
+                    self.start_busy(now, self.chip().program_time(), data);
+                }
             }
 
             State::BankChange => {
@@ -171,6 +404,58 @@ impl Flash {
                 self.bank = data as u32;
                 self.state = State::WaitForCommand(0, CommandContext::None);
             }
+
+            State::Busy { .. } => unreachable!(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn command(flash: &mut Flash, cmd: u8, now: u64) {
+        flash.write(0x5555, 0xAA, now);
+        flash.write(0x2AAA, 0x55, now);
+        flash.write(0x5555, cmd, now);
+    }
+
+    #[test]
+    fn atmel_full_page() {
+        let mut flash = Flash::new(FlashChip::Atmel, Some(vec![0; 64 * 1024]));
+        command(&mut flash, 0xA0, 0);
+        for i in 0..0x80 {
+            flash.write(0x100 + i, i as u8, i as u64);
         }
+        assert!(matches!(flash.state, State::Busy { .. }));
+
+        let done = 0x7F + FlashChip::Atmel.program_time();
+        assert_eq!(flash.read(0x17F, done), 0x7F);
+        assert!(matches!(flash.state, State::WaitForCommand(0, _)));
+    }
+
+    #[test]
+    fn atmel_partial_page_times_out() {
+        let mut flash = Flash::new(FlashChip::Atmel, Some(vec![0; 64 * 1024]));
+        command(&mut flash, 0xA0, 0);
+        flash.write(0x100, 0x12, 10);
+        flash.write(0x101, 0x34, 20);
+
+        // Still loading bytes just before the timeout
+        let timeout = 20 + FlashChip::Atmel.page_load_timeout();
+        flash.update_busy(timeout - 1);
+        assert!(matches!(flash.state, State::WritePage { count: 2, .. }));
+
+        // Then programs the page, the rest of which is erased
+        assert!(flash.update_busy(timeout));
+        let done = timeout + FlashChip::Atmel.program_time();
+        assert_eq!(flash.read(0x100, done), 0x12);
+        assert_eq!(flash.read(0x101, done), 0x34);
+        assert_eq!(flash.read(0x102, done), 0xFF);
+        assert_eq!(flash.read(0x180, done), 0x00);
+
+        // And accepts commands again
+        command(&mut flash, 0xA0, done);
+        assert!(matches!(flash.state, State::WritePage { count: 0, .. }));
     }
 }
@@ -1,13 +1,14 @@
 pub mod eeprom;
 pub mod flash;
+pub mod savefile;
 pub mod sram;
 
 use eeprom::Eeprom;
-use flash::Flash;
+use flash::{Flash, FlashChip};
 use log::{error, warn};
 use serde::{Deserialize, Serialize};
 
-use self::{eeprom::EepromSize, sram::Sram};
+use self::{eeprom::EepromSize, savefile::SaveKind, sram::Sram};
 
 #[derive(Serialize, Deserialize)]
 pub enum Backup {
@@ -22,7 +23,79 @@ pub enum Backup {
     Unknown,
 }
 
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum BackupType {
+    None,
+    Sram,
+    /// EEPROM, size detected from the first access
+    Eeprom,
+    Eeprom512,
+    Eeprom8K,
+    Flash512,
+    Flash1M,
+}
+
+/// Cartridge backup settings, usually from the game database
+#[derive(Clone, Copy, Default, Debug)]
+pub struct BackupConfig {
+    /// Detected from the ID string in the ROM if `None`
+    pub backup_type: Option<BackupType>,
+    /// Chip reported in flash ID mode. Also decides the size when
+    /// `backup_type` is not given.
+    pub flash_chip: Option<FlashChip>,
+}
+
 impl Backup {
+    pub fn for_rom(rom: &[u8], config: &BackupConfig, backup: Option<Vec<u8>>) -> Backup {
+        let backup_type = config.backup_type.or_else(|| {
+            config.flash_chip.map(|chip| {
+                if chip.size() == 64 * 1024 {
+                    BackupType::Flash512
+                } else {
+                    BackupType::Flash1M
+                }
+            })
+        });
+
+        let mut ret = match backup_type {
+            None => Backup::detect_backup(rom, backup),
+            Some(BackupType::None) => Backup::Unknown,
+            Some(BackupType::Sram) => Backup::Sram(Sram::new(backup)),
+            Some(BackupType::Eeprom) => Backup::Eeprom(Eeprom::new(backup)),
+            Some(ty @ (BackupType::Eeprom512 | BackupType::Eeprom8K)) => {
+                let mut eeprom = Eeprom::new(backup);
+                eeprom.set_size(if ty == BackupType::Eeprom512 {
+                    EepromSize::Size512
+                } else {
+                    EepromSize::Size8K
+                });
+                Backup::Eeprom(eeprom)
+            }
+            Some(BackupType::Flash512) => Backup::Flash(Flash::new(FlashChip::Sst, backup)),
+            Some(BackupType::Flash1M) => Backup::Flash(Flash::new(FlashChip::Sanyo, backup)),
+        };
+
+        if let Some(chip) = config.flash_chip {
+            ret.set_flash_chip(chip);
+        }
+        ret
+    }
+
+    pub fn flash_chip(&self) -> Option<FlashChip> {
+        match self {
+            Backup::Flash(flash) => Some(flash.chip()),
+            _ => None,
+        }
+    }
+
+    pub fn set_flash_chip(&mut self, chip: FlashChip) {
+        if let Backup::Flash(flash) = self {
+            flash.set_chip(chip);
+        } else {
+            warn!("Set flash chip to non-FLASH cartridge: {chip:?}");
+        }
+    }
+
     pub fn detect_backup(data: &[u8], backup: Option<Vec<u8>>) -> Backup {
         for i in 0..data.len() {
             if match_id_string(&data[i..], b"EEPROM_Vnnn") {
@@ -37,11 +110,11 @@ impl Backup {
                 || match_id_string(&data[i..], b"FLASH512_Vnnn")
             {
                 // 512Kbit (= 64KB) Flash
-                return Backup::Flash(Flash::new(64 * 1024, backup));
+                return Backup::Flash(Flash::new(FlashChip::Sst, backup));
             }
             if match_id_string(&data[i..], b"FLASH1M_Vnnn") {
                 // 1Mbit (= 128KB) Flash
-                return Backup::Flash(Flash::new(128 * 1024, backup));
+                return Backup::Flash(Flash::new(FlashChip::Sanyo, backup));
             }
         }
         if data.is_empty() {
@@ -61,6 +134,39 @@ impl Backup {
         }
     }
 
+    /// Whether the data changed since the last `set_dirty(false)`
+    pub fn dirty(&self) -> bool {
+        match self {
+            Backup::Eeprom(e) => e.dirty(),
+            Backup::Sram(s) => s.dirty(),
+            Backup::Flash(f) => f.dirty(),
+            Backup::Unknown => false,
+        }
+    }
+
+    pub fn set_dirty(&mut self, dirty: bool) {
+        match self {
+            Backup::Eeprom(e) => e.set_dirty(dirty),
+            Backup::Sram(s) => s.set_dirty(dirty),
+            Backup::Flash(f) => f.set_dirty(dirty),
+            Backup::Unknown => {}
+        }
+    }
+
+    pub fn save_kind(&self) -> Option<SaveKind> {
+        match self {
+            Backup::Eeprom(e) => Some(SaveKind::Eeprom {
+                size: e.size().map(|s| match s {
+                    EepromSize::Size512 => 512,
+                    EepromSize::Size8K => 8 * 1024,
+                }),
+            }),
+            Backup::Sram(_) => Some(SaveKind::Sram),
+            Backup::Flash(f) => Some(SaveKind::Flash { size: f.size() }),
+            Backup::Unknown => None,
+        }
+    }
+
     pub fn backup_type(&self) -> &'static str {
         match self {
             Backup::Eeprom(_) => "EEPROM",
@@ -86,27 +192,27 @@ impl Backup {
         }
     }
 
-    pub fn read_eeprom(&mut self) -> bool {
+    pub fn read_eeprom(&mut self, now: u64) -> bool {
         if let Backup::Eeprom(eeprom) = self {
-            eeprom.read()
+            eeprom.read(now)
         } else {
             warn!("Read EEPROM on non-EEPROM cartridge");
             false
         }
     }
 
-    pub fn write_eeprom(&mut self, data: bool) {
+    pub fn write_eeprom(&mut self, data: bool, now: u64) {
         if let Backup::Eeprom(eeprom) = self {
-            eeprom.write(data);
+            eeprom.write(data, now);
         } else {
             warn!("Write EEPROM on non-EEPROM cartridge: {}", data as u8);
         }
     }
 
-    pub fn read_ram(&mut self, addr: u32) -> u8 {
+    pub fn read_ram(&mut self, addr: u32, now: u64) -> u8 {
         match self {
             Backup::Sram(sram) => sram.read(addr),
-            Backup::Flash(flash) => flash.read(addr),
+            Backup::Flash(flash) => flash.read(addr, now),
             _ => {
                 warn!("Read GamePak RAM on non-SRAM cartridge: 0x{addr:08X}");
                 0
@@ -114,10 +220,28 @@ impl Backup {
         }
     }
 
-    pub fn write_ram(&mut self, addr: u32, data: u8) {
+    /// Reads the stored data without affecting the chip state
+    pub fn peek_ram(&self, addr: u32) -> Option<u8> {
+        match self {
+            Backup::Sram(sram) => Some(sram.read(addr)),
+            Backup::Flash(flash) => Some(flash.peek(addr)),
+            _ => None,
+        }
+    }
+
+    /// Writes the stored data without affecting the chip state
+    pub fn poke_ram(&mut self, addr: u32, data: u8) {
+        match self {
+            Backup::Sram(sram) => sram.write(addr, data),
+            Backup::Flash(flash) => flash.poke(addr, data),
+            _ => warn!("Poke to GamePak RAM on non-SRAM cartridge: 0x{addr:08X} = 0x{data:02X}"),
+        }
+    }
+
+    pub fn write_ram(&mut self, addr: u32, data: u8, now: u64) {
         match self {
             Backup::Sram(sram) => sram.write(addr, data),
-            Backup::Flash(flash) => flash.write(addr, data),
+            Backup::Flash(flash) => flash.write(addr, data, now),
             _ => {
                 warn!("Write GamePak RAM on non-SRAM cartridge: 0x{addr:08X} = 0x{data:02X}");
             }
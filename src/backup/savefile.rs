@@ -0,0 +1,495 @@
+//! Conversion between tgba's backup layout and .sav files of other emulators.
+//!
+//! tgba keeps SRAM as 64 KiB and EEPROM as little-endian 64-bit words.
+//! VBA-M, mGBA and no$gba store 32 KiB of SRAM, which is mirrored to 64 KiB
+//! on import, and EEPROM words in transfer order (big-endian), so tgba's own
+//! EEPROM layout is what is usually called a byte-swapped dump.
+
+use std::fmt;
+
+const SRAM_SIZE: usize = 0x8000;
+const NATIVE_SRAM_SIZE: usize = 0x10000;
+const EEPROM_SIZES: [usize; 2] = [512, 8 * 1024];
+const FLASH_SECTOR_SIZE: usize = 0x1000;
+
+// Largest backup, 1 Mbit flash
+const MAX_BACKUP_SIZE: usize = 128 * 1024;
+
+// mGBA appends the RTC state of cartridges with a clock
+const RTC_TRAILER_SIZE: usize = 16;
+
+const NOCASH_ID: &[u8] = b"NocashGbaBackupMediaSavDataFile\x1A";
+const NOCASH_SRAM_TAG: &[u8; 4] = b"SRAM";
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SaveKind {
+    Sram,
+    Flash {
+        size: usize,
+    },
+    /// `size` is `None` until the game first accesses the EEPROM
+    Eeprom {
+        size: Option<usize>,
+    },
+}
+
+impl fmt::Display for SaveKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SaveKind::Sram => write!(f, "SRAM"),
+            SaveKind::Flash { size } => write!(f, "FLASH ({} KiB)", size / 1024),
+            SaveKind::Eeprom { size: Some(size) } => write!(f, "EEPROM ({size} bytes)"),
+            SaveKind::Eeprom { size: None } => write!(f, "EEPROM"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SaveFileFormat {
+    /// `Agb::backup()` as is
+    Native,
+    VbaM,
+    Mgba,
+    /// .sav with a `NocashGbaBackupMediaSavDataFile` header
+    NoCashGba,
+}
+
+#[derive(Debug)]
+pub enum SaveFileError {
+    /// The cartridge has no backup memory
+    NoBackup,
+    UnexpectedSize {
+        kind: SaveKind,
+        format: SaveFileFormat,
+        size: usize,
+    },
+    /// Data past the expected size is not blank
+    Oversized {
+        kind: SaveKind,
+        size: usize,
+    },
+    /// The layout cannot be told from the data, the format must be given
+    AmbiguousFormat {
+        kind: SaveKind,
+    },
+    Corrupted(String),
+}
+
+impl fmt::Display for SaveFileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SaveFileError::NoBackup => write!(f, "Cartridge has no backup memory"),
+            SaveFileError::UnexpectedSize { kind, format, size } => write!(
+                f,
+                "Unexpected {format:?} save file size for {kind}: {size} bytes"
+            ),
+            SaveFileError::Oversized { kind, size } => write!(
+                f,
+                "Save file of {size} bytes is larger than {kind} and the excess is not blank"
+            ),
+            SaveFileError::AmbiguousFormat { kind } => write!(
+                f,
+                "Cannot tell the format of the {kind} save file, it must be given"
+            ),
+            SaveFileError::Corrupted(msg) => write!(f, "Save file is corrupted: {msg}"),
+        }
+    }
+}
+
+impl std::error::Error for SaveFileError {}
+
+/// Guesses the format of a save file for a cartridge with `kind` backup.
+/// Returns `None` for exact-size EEPROM files, which are the same size in
+/// tgba's layout and in that of other emulators.
+pub fn detect_save_format(data: &[u8], kind: SaveKind) -> Option<SaveFileFormat> {
+    if data.starts_with(NOCASH_ID) {
+        return Some(SaveFileFormat::NoCashGba);
+    }
+    if has_rtc_trailer(data) {
+        return Some(SaveFileFormat::Mgba);
+    }
+    Some(match kind {
+        SaveKind::Sram if data.len() == NATIVE_SRAM_SIZE => SaveFileFormat::Native,
+        SaveKind::Flash { size } if data.len() == size => SaveFileFormat::Native,
+        SaveKind::Eeprom { .. } if EEPROM_SIZES.contains(&data.len()) => return None,
+        _ => SaveFileFormat::VbaM,
+    })
+}
+
+/// Converts a save file into the layout `Agb::new` expects.
+/// The format is detected if `format` is `None`.
+pub fn import_save(
+    data: &[u8],
+    kind: SaveKind,
+    format: Option<SaveFileFormat>,
+) -> Result<Vec<u8>, SaveFileError> {
+    let format = format
+        .or_else(|| detect_save_format(data, kind))
+        .ok_or(SaveFileError::AmbiguousFormat { kind })?;
+
+    let payload = match format {
+        SaveFileFormat::NoCashGba => nocash_unpack(data)?,
+        _ => data.to_vec(),
+    };
+    let payload = match format {
+        SaveFileFormat::Mgba if has_rtc_trailer(&payload) => {
+            payload[..payload.len() - RTC_TRAILER_SIZE].to_vec()
+        }
+        _ => payload,
+    };
+
+    let size_error = |size| SaveFileError::UnexpectedSize { kind, format, size };
+
+    match kind {
+        SaveKind::Sram => match (format, payload.len()) {
+            (_, NATIVE_SRAM_SIZE) => Ok(payload),
+            (SaveFileFormat::Native, _) => Err(size_error(payload.len())),
+            (_, SRAM_SIZE) => Ok(payload.repeat(2)),
+            _ => Err(size_error(payload.len())),
+        },
+
+        SaveKind::Flash { size } => {
+            if payload.len() > size {
+                return fit_to(payload, size, kind);
+            }
+            // Truncated dumps (e.g. 64 KiB saves of 128 KiB chips) read back as erased
+            if (format == SaveFileFormat::Native && payload.len() != size)
+                || payload.is_empty()
+                || payload.len() % FLASH_SECTOR_SIZE != 0
+            {
+                return Err(size_error(payload.len()));
+            }
+            let mut ret = payload;
+            ret.resize(size, 0xFF);
+            Ok(ret)
+        }
+
+        SaveKind::Eeprom { size } => {
+            let payload = match size {
+                Some(size) if payload.len() > size => fit_to(payload, size, kind)?,
+                _ => payload,
+            };
+            if !EEPROM_SIZES.contains(&payload.len()) || size.is_some_and(|s| s != payload.len()) {
+                return Err(size_error(payload.len()));
+            }
+            Ok(match format {
+                SaveFileFormat::Native => payload,
+                _ => swap_eeprom_words(&payload),
+            })
+        }
+    }
+}
+
+/// Converts `Agb::backup()` data into a save file of `format`.
+pub fn export_save(
+    data: &[u8],
+    kind: SaveKind,
+    format: SaveFileFormat,
+) -> Result<Vec<u8>, SaveFileError> {
+    let payload = match (kind, format) {
+        (_, SaveFileFormat::Native) => return Ok(data.to_vec()),
+        (SaveKind::Sram, _) => data[..SRAM_SIZE.min(data.len())].to_vec(),
+        (SaveKind::Flash { .. }, _) => data.to_vec(),
+        (SaveKind::Eeprom { .. }, _) => swap_eeprom_words(data),
+    };
+
+    Ok(match format {
+        SaveFileFormat::NoCashGba => nocash_pack(&payload),
+        _ => payload,
+    })
+}
+
+// Every backup size is a multiple of 512 bytes
+fn has_rtc_trailer(data: &[u8]) -> bool {
+    data.len() % 512 == RTC_TRAILER_SIZE
+}
+
+fn fit_to(mut data: Vec<u8>, size: usize, kind: SaveKind) -> Result<Vec<u8>, SaveFileError> {
+    let excess = &data[size..];
+    if !(excess.iter().all(|&b| b == 0xFF) || excess.iter().all(|&b| b == 0)) {
+        return Err(SaveFileError::Oversized {
+            kind,
+            size: data.len(),
+        });
+    }
+    data.truncate(size);
+    Ok(data)
+}
+
+fn swap_eeprom_words(data: &[u8]) -> Vec<u8> {
+    data.chunks(8)
+        .flat_map(|c| c.iter().rev().copied())
+        .collect()
+}
+
+// Layout: ID (32 bytes), reserved up to 0x40, "SRAM", method: u32,
+// then for method 0: size: u32, data
+//      for method 1: packed size: u32, unpacked size: u32, RLE data
+fn nocash_unpack(data: &[u8]) -> Result<Vec<u8>, SaveFileError> {
+    let corrupted = |msg: &str| SaveFileError::Corrupted(msg.to_string());
+    let read_u32 = |p: usize| {
+        data.get(p..p + 4)
+            .map(|b| u32::from_le_bytes(b.try_into().unwrap()) as usize)
+            .ok_or_else(|| corrupted("truncated header"))
+    };
+
+    if data.get(0x40..0x44) != Some(NOCASH_SRAM_TAG) {
+        return Err(corrupted("missing SRAM tag"));
+    }
+
+    match read_u32(0x44)? {
+        0 => {
+            let size = read_u32(0x48)?;
+            data.get(0x4C..0x4C + size)
+                .map(|d| d.to_vec())
+                .ok_or_else(|| corrupted("truncated data"))
+        }
+        1 => {
+            let unpacked_size = read_u32(0x4C)?;
+            if unpacked_size > MAX_BACKUP_SIZE {
+                return Err(corrupted("unpacked size too large"));
+            }
+            let mut ret = Vec::with_capacity(unpacked_size);
+            let mut p = 0x50;
+            let byte = |p: usize| {
+                data.get(p)
+                    .copied()
+                    .ok_or_else(|| corrupted("truncated data"))
+            };
+            loop {
+                let cc = byte(p)? as usize;
+                p += 1;
+                match cc {
+                    0 => break,
+                    // Run of one byte, 16-bit length
+                    0x80 => {
+                        let b = byte(p)?;
+                        let len = byte(p + 1)? as usize | (byte(p + 2)? as usize) << 8;
+                        ret.extend(std::iter::repeat_n(b, len));
+                        p += 3;
+                    }
+                    // Run of one byte
+                    0x81.. => {
+                        ret.extend(std::iter::repeat_n(byte(p)?, cc - 0x80));
+                        p += 1;
+                    }
+                    // Literal bytes
+                    _ => {
+                        let lit = data
+                            .get(p..p + cc)
+                            .ok_or_else(|| corrupted("truncated data"))?;
+                        ret.extend_from_slice(lit);
+                        p += cc;
+                    }
+                }
+            }
+            if ret.len() != unpacked_size {
+                return Err(corrupted("unpacked size mismatch"));
+            }
+            Ok(ret)
+        }
+        method => Err(SaveFileError::Corrupted(format!(
+            "unknown compression method {method}"
+        ))),
+    }
+}
+
+fn nocash_pack(data: &[u8]) -> Vec<u8> {
+    let mut ret = NOCASH_ID.to_vec();
+    ret.resize(0x40, 0);
+    ret.extend_from_slice(NOCASH_SRAM_TAG);
+    ret.extend_from_slice(&0_u32.to_le_bytes());
+    ret.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    ret.extend_from_slice(data);
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FLASH_1M: SaveKind = SaveKind::Flash { size: 128 * 1024 };
+
+    fn pattern(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 7 + i / 256) as u8).collect()
+    }
+
+    #[test]
+    fn sram_mirroring() {
+        let data = pattern(SRAM_SIZE);
+        let native = import_save(&data, SaveKind::Sram, None).unwrap();
+        assert_eq!(native.len(), NATIVE_SRAM_SIZE);
+        assert_eq!(native[..SRAM_SIZE], data);
+        assert_eq!(native[SRAM_SIZE..], data);
+
+        let exported = export_save(&native, SaveKind::Sram, SaveFileFormat::VbaM).unwrap();
+        assert_eq!(exported, data);
+
+        // A full 64 KiB image is taken as is
+        let data = pattern(NATIVE_SRAM_SIZE);
+        assert_eq!(
+            detect_save_format(&data, SaveKind::Sram),
+            Some(SaveFileFormat::Native)
+        );
+        assert_eq!(import_save(&data, SaveKind::Sram, None).unwrap(), data);
+    }
+
+    #[test]
+    fn eeprom_word_swap() {
+        let native = pattern(512);
+        let exported = export_save(
+            &native,
+            SaveKind::Eeprom { size: Some(512) },
+            SaveFileFormat::Mgba,
+        )
+        .unwrap();
+        assert_eq!(exported[..8], [7, 6, 5, 4, 3, 2, 1, 0].map(|i| native[i]));
+
+        let imported = import_save(
+            &exported,
+            SaveKind::Eeprom { size: Some(512) },
+            Some(SaveFileFormat::Mgba),
+        )
+        .unwrap();
+        assert_eq!(imported, native);
+    }
+
+    #[test]
+    fn eeprom_ambiguous() {
+        let data = pattern(8 * 1024);
+        let kind = SaveKind::Eeprom { size: None };
+        assert_eq!(detect_save_format(&data, kind), None);
+        assert!(matches!(
+            import_save(&data, kind, None),
+            Err(SaveFileError::AmbiguousFormat { .. })
+        ));
+        // Native needs no conversion once the format is given
+        assert_eq!(
+            import_save(&data, kind, Some(SaveFileFormat::Native)).unwrap(),
+            data
+        );
+        // Size must match what the game uses
+        assert!(matches!(
+            import_save(
+                &data,
+                SaveKind::Eeprom { size: Some(512) },
+                Some(SaveFileFormat::Native)
+            ),
+            Err(SaveFileError::Oversized { .. })
+        ));
+    }
+
+    #[test]
+    fn truncated_flash() {
+        let data = pattern(64 * 1024);
+        let ret = import_save(&data, FLASH_1M, None).unwrap();
+        assert_eq!(ret.len(), 128 * 1024);
+        assert_eq!(ret[..64 * 1024], data);
+        assert!(ret[64 * 1024..].iter().all(|&b| b == 0xFF));
+
+        // Not a whole number of sectors
+        assert!(matches!(
+            import_save(&data[..1000], FLASH_1M, None),
+            Err(SaveFileError::UnexpectedSize { .. })
+        ));
+        // Native files must be complete
+        assert!(matches!(
+            import_save(&data, FLASH_1M, Some(SaveFileFormat::Native)),
+            Err(SaveFileError::UnexpectedSize { .. })
+        ));
+    }
+
+    #[test]
+    fn oversized() {
+        let kind = SaveKind::Flash { size: 64 * 1024 };
+        let mut data = pattern(64 * 1024);
+        data.resize(128 * 1024, 0xFF);
+        assert_eq!(import_save(&data, kind, None).unwrap(), data[..64 * 1024]);
+
+        data[100 * 1024] = 0;
+        assert!(matches!(
+            import_save(&data, kind, None),
+            Err(SaveFileError::Oversized { .. })
+        ));
+    }
+
+    #[test]
+    fn rtc_trailer() {
+        let mut data = pattern(SRAM_SIZE);
+        data.extend_from_slice(&[0xAA; RTC_TRAILER_SIZE]);
+        assert_eq!(
+            detect_save_format(&data, SaveKind::Sram),
+            Some(SaveFileFormat::Mgba)
+        );
+        let ret = import_save(&data, SaveKind::Sram, None).unwrap();
+        assert_eq!(ret[..SRAM_SIZE], data[..SRAM_SIZE]);
+
+        // Only mGBA writes the trailer
+        assert!(matches!(
+            import_save(&data, SaveKind::Sram, Some(SaveFileFormat::VbaM)),
+            Err(SaveFileError::UnexpectedSize { .. })
+        ));
+    }
+
+    #[test]
+    fn nocash_round_trip() {
+        let native = pattern(128 * 1024);
+        let exported = export_save(&native, FLASH_1M, SaveFileFormat::NoCashGba).unwrap();
+        assert!(exported.starts_with(NOCASH_ID));
+        assert_eq!(
+            detect_save_format(&exported, FLASH_1M),
+            Some(SaveFileFormat::NoCashGba)
+        );
+        assert_eq!(import_save(&exported, FLASH_1M, None).unwrap(), native);
+    }
+
+    fn nocash_rle(unpacked_size: u32, packed: &[u8]) -> Vec<u8> {
+        let mut ret = NOCASH_ID.to_vec();
+        ret.resize(0x40, 0);
+        ret.extend_from_slice(NOCASH_SRAM_TAG);
+        ret.extend_from_slice(&1_u32.to_le_bytes());
+        ret.extend_from_slice(&(packed.len() as u32).to_le_bytes());
+        ret.extend_from_slice(&unpacked_size.to_le_bytes());
+        ret.extend_from_slice(packed);
+        ret
+    }
+
+    #[test]
+    fn nocash_rle_unpack() {
+        let packed = [
+            // Literal "ab"
+            &[0x02, b'a', b'b'][..],
+            // Three 'c'
+            &[0x83, b'c'],
+            // 0x7FFB 0xFF, up to 32 KiB
+            &[0x80, 0xFF, 0xFB, 0x7F],
+            &[0x00],
+        ]
+        .concat();
+        let data = nocash_rle(SRAM_SIZE as u32, &packed);
+        let ret = import_save(&data, SaveKind::Sram, None).unwrap();
+
+        let mut expected = b"abccc".to_vec();
+        expected.resize(SRAM_SIZE, 0xFF);
+        assert_eq!(ret, expected.repeat(2));
+    }
+
+    #[test]
+    fn nocash_corrupted() {
+        for data in [
+            // Size does not match the header
+            nocash_rle(0x8000, &[0x02, b'a', b'b', 0x00]),
+            // Missing end marker
+            nocash_rle(2, &[0x02, b'a', b'b']),
+            // Run without its byte
+            nocash_rle(2, &[0x82]),
+            nocash_rle(u32::MAX, &[0x00]),
+            NOCASH_ID.to_vec(),
+        ] {
+            assert!(matches!(
+                import_save(&data, SaveKind::Sram, None),
+                Err(SaveFileError::Corrupted(_))
+            ));
+        }
+    }
+}
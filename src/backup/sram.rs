@@ -5,6 +5,9 @@ use serde::{Deserialize, Serialize};
 pub struct Sram {
     #[serde(with = "serde_bytes")]
     data: Vec<u8>,
+    // Modified since the owner last persisted the data
+    #[serde(skip)]
+    dirty: bool,
 }
 
 impl Sram {
@@ -21,6 +24,7 @@ impl Sram {
                     }
                 },
             ),
+            dirty: false,
         }
     }
 
@@ -33,6 +37,17 @@ impl Sram {
     }
 
     pub fn write(&mut self, addr: u32, data: u8) {
-        self.data[addr as usize] = data;
+        if self.data[addr as usize] != data {
+            self.data[addr as usize] = data;
+            self.dirty = true;
+        }
+    }
+
+    pub fn dirty(&self) -> bool {
+        self.dirty
+    }
+
+    pub fn set_dirty(&mut self, dirty: bool) {
+        self.dirty = dirty;
     }
 }
@@ -157,6 +157,15 @@ impl Bus {
         }
     }
 
+    /// Places a multiboot image at the start of EWRAM
+    pub fn load_ext_ram(&mut self, data: &[u8]) {
+        self.ext_ram[..data.len()].copy_from_slice(data);
+    }
+
+    pub fn set_post_boot(&mut self, post_boot: u8) {
+        self.post_boot = post_boot;
+    }
+
     pub fn set_pc(&mut self, pc: u32) {
         self.bios_protect = !matches!(pc >> 24, 0 | 1);
     }
@@ -192,6 +201,10 @@ impl Bus {
         self.timers.tick(ctx);
     }
 
+    pub fn cycles_to_timer_overflow(&self) -> Option<u64> {
+        self.timers.cycles_to_overflow()
+    }
+
     pub fn dma(&self, ch: usize) -> &Dma {
         &self.dma[ch]
     }
@@ -262,7 +275,7 @@ impl Bus {
 
             0xE..=0xF => {
                 ctx.elapse(self.wait_cycles.gamepak_ram_8);
-                Some(ctx.backup_mut().read_ram(addr & 0xFFFF))
+                Some(ctx.backup_read(addr & 0xFFFF))
             }
 
             _ => {
@@ -321,7 +334,7 @@ impl Bus {
 
             0xE..=0xF => {
                 ctx.elapse(self.wait_cycles.gamepak_ram_16);
-                let lo = ctx.backup_mut().read_ram(addr & 0xFFFF);
+                let lo = ctx.backup_read(addr & 0xFFFF);
                 Some((lo as u16) << 8 | lo as u16)
             }
 
@@ -394,7 +407,7 @@ impl Bus {
 
             0xE..=0xF => {
                 ctx.elapse(self.wait_cycles.gamepak_ram_32);
-                let lo = ctx.backup_mut().read_ram(addr & 0xFFFF);
+                let lo = ctx.backup_read(addr & 0xFFFF);
                 Some((lo as u32) << 24 | (lo as u32) << 16 | (lo as u32) << 8 | lo as u32)
             }
             _ => {
@@ -451,7 +464,7 @@ impl Bus {
             }
         }
 
-        ctx.gamepak_mut().read(addr & 0x01FFFFFE)
+        ctx.gamepak_read(addr & 0x01FFFFFE)
     }
 
     pub fn write8(&mut self, ctx: &mut impl Context, addr: u32, data: u8, first: bool) {
@@ -503,7 +516,7 @@ impl Bus {
 
             0xE..=0xF => {
                 ctx.elapse(self.wait_cycles.gamepak_ram_8);
-                ctx.backup_mut().write_ram(addr & 0xFFFF, data);
+                ctx.backup_write(addr & 0xFFFF, data);
             }
             _ => warn!("Write8: Bad segment: 0x{addr:08X} = 0x{data:02X}"),
         }
@@ -557,14 +570,13 @@ impl Bus {
                     self.wait_cycles.gamepak_rom_2nd[ix]
                 });
 
-                ctx.gamepak_mut().write(addr, data);
+                ctx.gamepak_write(addr, data);
             }
 
             0xE..=0xF => {
                 ctx.elapse(self.wait_cycles.gamepak_ram_16);
-                ctx.backup_mut().write_ram(addr & 0xFFFF, data as u8);
-                ctx.backup_mut()
-                    .write_ram((addr + 1) & 0xFFFF, (data >> 8) as u8);
+                ctx.backup_write(addr & 0xFFFF, data as u8);
+                ctx.backup_write((addr + 1) & 0xFFFF, (data >> 8) as u8);
             }
             _ => warn!("Write16: Bad segment: 0x{addr:08X} = 0x{data:04X}"),
         }
@@ -612,19 +624,58 @@ impl Bus {
 
             0xE..=0xF => {
                 ctx.elapse(self.wait_cycles.gamepak_ram_32);
-                ctx.backup_mut().write_ram(addr & 0xFFFF, data as u8);
-                ctx.backup_mut()
-                    .write_ram((addr + 1) & 0xFFFF, (data >> 8) as u8);
-                ctx.backup_mut()
-                    .write_ram((addr + 2) & 0xFFFF, (data >> 16) as u8);
-                ctx.backup_mut()
-                    .write_ram((addr + 3) & 0xFFFF, (data >> 24) as u8);
+                ctx.backup_write(addr & 0xFFFF, data as u8);
+                ctx.backup_write((addr + 1) & 0xFFFF, (data >> 8) as u8);
+                ctx.backup_write((addr + 2) & 0xFFFF, (data >> 16) as u8);
+                ctx.backup_write((addr + 3) & 0xFFFF, (data >> 24) as u8);
             }
             _ => warn!("Write32: Bad segment: 0x{addr:08X} = 0x{data:08X}"),
         }
     }
 }
 
+impl Bus {
+    /// Reads memory without consuming cycles or causing side effects.
+    /// I/O registers other than KEYINPUT are not accessible.
+    pub fn peek8(&mut self, ctx: &mut impl Context, addr: u32) -> Option<u8> {
+        match addr >> 24 {
+            0x0 if addr < 0x00004000 => Some(self.bios[addr as usize]),
+            0x2 => Some(self.ext_ram[(addr & 0x3FFFF) as usize]),
+            0x3 => Some(self.ram[(addr & 0x7FFF) as usize]),
+            0x4 => match addr & 0xFFFF {
+                0x130 => Some(self.key_input as u8),
+                0x131 => Some((self.key_input >> 8) as u8),
+                _ => None,
+            },
+            0x5 => Some(ctx.lcd().palette[(addr & 0x3FF) as usize]),
+            0x6 => Some(ctx.lcd().vram[vram_addr(addr)]),
+            0x7 => Some(ctx.lcd().oam[(addr & 0x3FF) as usize]),
+            0x8..=0xD => ctx
+                .gamepak()
+                .rom()
+                .data
+                .get((addr & 0x01FFFFFF) as usize)
+                .copied(),
+            0xE..=0xF => ctx.backup().peek_ram(addr & 0xFFFF),
+            _ => None,
+        }
+    }
+
+    /// Writes memory without consuming cycles. Writes to ROM, BIOS and I/O
+    /// registers are ignored.
+    pub fn poke8(&mut self, ctx: &mut impl Context, addr: u32, data: u8) {
+        match addr >> 24 {
+            0x2 => self.ext_ram[(addr & 0x3FFFF) as usize] = data,
+            0x3 => self.ram[(addr & 0x7FFF) as usize] = data,
+            0x5 => ctx.lcd_mut().palette[(addr & 0x3FF) as usize] = data,
+            0x6 => ctx.lcd_mut().vram[vram_addr(addr)] = data,
+            0x7 => ctx.lcd_mut().oam[(addr & 0x3FF) as usize] = data,
+            0xE..=0xF => ctx.backup_mut().poke_ram(addr & 0xFFFF, data),
+            _ => warn!("Poke to unsupported address: 0x{addr:08X} = 0x{data:02X}"),
+        }
+    }
+}
+
 fn vram_addr(addr: u32) -> usize {
     (if addr & 0x10000 == 0 {
         addr & 0xFFFF
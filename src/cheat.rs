@@ -0,0 +1,871 @@
+use anyhow::{anyhow, bail, Result};
+use std::collections::HashMap;
+
+use crate::context::Bus;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum CheatFormat {
+    /// GameShark / Action Replay v1, v2 (encrypted, `XXXXXXXX YYYYYYYY`)
+    GameSharkV1,
+    /// GameShark v3 / Pro Action Replay v3 (encrypted, `XXXXXXXX YYYYYYYY`)
+    GameSharkV3,
+    /// Action Replay MAX, same code format as GameSharkV3
+    ActionReplayMax,
+    /// Unencrypted CodeBreaker (`XXXXXXXX YYYY`)
+    CodeBreaker,
+    /// `AAAAAAAA:VV`, `AAAAAAAA:VVVV` or `AAAAAAAA:VVVVVVVV`
+    Raw,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Width {
+    W8,
+    W16,
+    W32,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Cond {
+    Eq,
+    Ne,
+    Gt,
+    Lt,
+    And,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+enum CheatOp {
+    Write {
+        width: Width,
+        addr: u32,
+        value: u32,
+    },
+    /// Writes `value` to `count` consecutive locations
+    Fill {
+        width: Width,
+        addr: u32,
+        value: u32,
+        count: u32,
+    },
+    Or16 {
+        addr: u32,
+        value: u16,
+    },
+    And16 {
+        addr: u32,
+        value: u16,
+    },
+    Add16 {
+        addr: u32,
+        value: u16,
+    },
+    /// Executes the next `lines` ops only if the condition holds
+    If {
+        cond: Cond,
+        width: Width,
+        addr: u32,
+        value: u32,
+        lines: usize,
+    },
+    IfKeys {
+        keys: u16,
+        lines: usize,
+    },
+    RomPatch {
+        offset: u32,
+        value: u16,
+    },
+    /// Master code: run the cheats when the CPU reaches `addr`
+    Hook {
+        addr: u32,
+    },
+}
+
+#[derive(Clone, Debug)]
+pub struct Cheat {
+    pub name: String,
+    pub enabled: bool,
+    format: CheatFormat,
+    code: String,
+    ops: Vec<CheatOp>,
+}
+
+impl Cheat {
+    pub fn parse(name: &str, format: CheatFormat, code: &str) -> Result<Self> {
+        let ops = match format {
+            CheatFormat::GameSharkV1 => parse_gsa_v1(code)?,
+            CheatFormat::GameSharkV3 | CheatFormat::ActionReplayMax => parse_gsa_v3(code)?,
+            CheatFormat::CodeBreaker => parse_codebreaker(code)?,
+            CheatFormat::Raw => parse_raw(code)?,
+        };
+        if ops.is_empty() {
+            bail!("Empty cheat code");
+        }
+
+        Ok(Self {
+            name: name.to_string(),
+            enabled: true,
+            format,
+            code: code.to_string(),
+            ops,
+        })
+    }
+
+    pub fn format(&self) -> CheatFormat {
+        self.format
+    }
+
+    pub fn code(&self) -> &str {
+        &self.code
+    }
+}
+
+#[derive(Default)]
+pub struct CheatEngine {
+    cheats: Vec<Cheat>,
+}
+
+impl CheatEngine {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cheats(&self) -> &[Cheat] {
+        &self.cheats
+    }
+
+    pub fn add(&mut self, cheat: Cheat) -> usize {
+        self.cheats.push(cheat);
+        self.cheats.len() - 1
+    }
+
+    pub fn remove(&mut self, ix: usize) -> Option<Cheat> {
+        (ix < self.cheats.len()).then(|| self.cheats.remove(ix))
+    }
+
+    pub fn clear(&mut self) {
+        self.cheats.clear();
+    }
+
+    pub fn set_enabled(&mut self, ix: usize, enabled: bool) -> Result<()> {
+        let cheat = self
+            .cheats
+            .get_mut(ix)
+            .ok_or_else(|| anyhow!("No such cheat: {ix}"))?;
+        cheat.enabled = enabled;
+        Ok(())
+    }
+
+    fn enabled_ops(&self) -> impl Iterator<Item = &CheatOp> {
+        self.cheats
+            .iter()
+            .filter(|c| c.enabled)
+            .flat_map(|c| c.ops.iter())
+    }
+
+    /// Hook addresses of the enabled master codes
+    pub fn hooks(&self) -> Vec<u32> {
+        self.enabled_ops()
+            .filter_map(|op| match op {
+                CheatOp::Hook { addr } => Some(*addr & !1),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// ROM halfwords patched by the enabled cheats, keyed by ROM offset
+    pub fn rom_patches(&self) -> HashMap<u32, u16> {
+        self.enabled_ops()
+            .filter_map(|op| match op {
+                CheatOp::RomPatch { offset, value } => Some((*offset, *value)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Performs the RAM writes of the enabled cheats.
+    pub fn apply(&self, ctx: &mut impl Bus) {
+        for cheat in self.cheats.iter().filter(|c| c.enabled) {
+            let mut skip = 0;
+            for op in &cheat.ops {
+                if skip > 0 {
+                    skip -= 1;
+                    continue;
+                }
+
+                match op {
+                    CheatOp::Write { width, addr, value } => write(ctx, *width, *addr, *value),
+                    CheatOp::Fill {
+                        width,
+                        addr,
+                        value,
+                        count,
+                    } => {
+                        for i in 0..*count {
+                            let addr = addr.wrapping_add(i * width.bytes());
+                            write(ctx, *width, addr, *value);
+                        }
+                    }
+                    CheatOp::Or16 { addr, value } => {
+                        let cur = read(ctx, Width::W16, *addr);
+                        write(ctx, Width::W16, *addr, cur | *value as u32);
+                    }
+                    CheatOp::And16 { addr, value } => {
+                        let cur = read(ctx, Width::W16, *addr);
+                        write(ctx, Width::W16, *addr, cur & *value as u32);
+                    }
+                    CheatOp::Add16 { addr, value } => {
+                        let cur = read(ctx, Width::W16, *addr);
+                        write(ctx, Width::W16, *addr, cur.wrapping_add(*value as u32));
+                    }
+                    CheatOp::If {
+                        cond,
+                        width,
+                        addr,
+                        value,
+                        lines,
+                    } => {
+                        let cur = read(ctx, *width, *addr);
+                        let ok = match cond {
+                            Cond::Eq => cur == *value,
+                            Cond::Ne => cur != *value,
+                            Cond::Gt => cur > *value,
+                            Cond::Lt => cur < *value,
+                            Cond::And => cur & *value != 0,
+                        };
+                        if !ok {
+                            skip = *lines;
+                        }
+                    }
+                    CheatOp::IfKeys { keys, lines } => {
+                        // KEYINPUT is active low
+                        let pressed = !read(ctx, Width::W16, 0x04000130) as u16 & 0x3FF;
+                        if pressed & keys != *keys {
+                            skip = *lines;
+                        }
+                    }
+                    CheatOp::RomPatch { .. } | CheatOp::Hook { .. } => {}
+                }
+            }
+        }
+    }
+}
+
+impl Width {
+    fn bytes(self) -> u32 {
+        match self {
+            Width::W8 => 1,
+            Width::W16 => 2,
+            Width::W32 => 4,
+        }
+    }
+}
+
+fn read(ctx: &mut impl Bus, width: Width, addr: u32) -> u32 {
+    (0..width.bytes()).fold(0, |acc, i| {
+        acc | (ctx.peek8(addr.wrapping_add(i)).unwrap_or(0) as u32) << (i * 8)
+    })
+}
+
+fn write(ctx: &mut impl Bus, width: Width, addr: u32, value: u32) {
+    for i in 0..width.bytes() {
+        ctx.poke8(addr.wrapping_add(i), (value >> (i * 8)) as u8);
+    }
+}
+
+fn parse_hex(s: &str, digits: usize) -> Result<u32> {
+    if s.len() != digits {
+        bail!("Expected {digits} hex digits: `{s}`");
+    }
+    u32::from_str_radix(s, 16).map_err(|_| anyhow!("Invalid hex number: `{s}`"))
+}
+
+fn parse_pairs(code: &str, digits: usize) -> Result<Vec<(u32, u32)>> {
+    let tokens = code.split_whitespace().collect::<Vec<_>>();
+    if tokens.len() % 2 != 0 {
+        bail!("Incomplete code line: {} words", tokens.len());
+    }
+    tokens
+        .chunks(2)
+        .map(|c| Ok((parse_hex(c[0], 8)?, parse_hex(c[1], digits)?)))
+        .collect()
+}
+
+const GSA_V1_SEEDS: [u32; 4] = [0x09F4FBBD, 0x9681884A, 0x352027E9, 0xF3DEE5A7];
+const GSA_V3_SEEDS: [u32; 4] = [0x7AA9648F, 0x7FAE6994, 0xC0EFAAD5, 0x42712C57];
+
+// TEA decryption used by GameShark / Action Replay
+fn decrypt_gsa(mut op1: u32, mut op2: u32, seeds: &[u32; 4]) -> (u32, u32) {
+    let mut sum = 0xC6EF3720_u32;
+    for _ in 0..32 {
+        op2 = op2.wrapping_sub(
+            (op1 << 4).wrapping_add(seeds[2])
+                ^ op1.wrapping_add(sum)
+                ^ (op1 >> 5).wrapping_add(seeds[3]),
+        );
+        op1 = op1.wrapping_sub(
+            (op2 << 4).wrapping_add(seeds[0])
+                ^ op2.wrapping_add(sum)
+                ^ (op2 >> 5).wrapping_add(seeds[1]),
+        );
+        sum = sum.wrapping_sub(0x9E3779B9);
+    }
+    (op1, op2)
+}
+
+fn parse_gsa_v1(code: &str) -> Result<Vec<CheatOp>> {
+    let mut ops = vec![];
+
+    for (a, v) in parse_pairs(code, 8)? {
+        let (a, v) = decrypt_gsa(a, v, &GSA_V1_SEEDS);
+        if a == 0xDEADFACE {
+            bail!("GameShark encryption seed change (DEADFACE) is not supported");
+        }
+
+        let addr = a & 0x0FFFFFFF;
+        ops.push(match a >> 28 {
+            0x0 => CheatOp::Write {
+                width: Width::W8,
+                addr,
+                value: v & 0xFF,
+            },
+            0x1 => CheatOp::Write {
+                width: Width::W16,
+                addr,
+                value: v & 0xFFFF,
+            },
+            0x2 => CheatOp::Write {
+                width: Width::W32,
+                addr,
+                value: v,
+            },
+            0x6 => CheatOp::RomPatch {
+                offset: (addr << 1) & 0x01FFFFFE,
+                value: v as u16,
+            },
+            0xD => CheatOp::If {
+                cond: Cond::Eq,
+                width: Width::W16,
+                addr,
+                value: v & 0xFFFF,
+                lines: 1,
+            },
+            // E0zzxxxx 0aaaaaaa: if [a] == xxxx, execute the next zz lines
+            0xE => CheatOp::If {
+                cond: Cond::Eq,
+                width: Width::W16,
+                addr: v & 0x0FFFFFFF,
+                value: a & 0xFFFF,
+                lines: ((a >> 16) & 0xFF) as usize,
+            },
+            0xF => CheatOp::Hook { addr },
+            ty => bail!("Unsupported GameShark v1 code type {ty:X}: {a:08X} {v:08X}"),
+        });
+    }
+
+    Ok(ops)
+}
+
+fn parse_gsa_v3(code: &str) -> Result<Vec<CheatOp>> {
+    let codes = parse_pairs(code, 8)?
+        .into_iter()
+        .map(|(a, v)| decrypt_gsa(a, v, &GSA_V3_SEEDS))
+        .collect::<Vec<_>>();
+
+    let mut ops = vec![];
+    let mut it = codes.iter();
+
+    while let Some(&(a, v)) = it.next() {
+        if a == 0xDEADFACE {
+            bail!("Action Replay encryption seed change (DEADFACE) is not supported");
+        }
+
+        if (a >> 24) & 0xFE == 0xC4 {
+            ops.push(CheatOp::Hook {
+                addr: (a & 0x01FFFFFF) | 0x08000000,
+            });
+            continue;
+        }
+
+        let ty = ((a >> 25) & 0x7F) | ((a >> 17) & 0x80);
+        let addr = (a & 0x00F00000) << 4 | (a & 0x0003FFFF);
+
+        let (cond, width) = match ty {
+            0x00 if a == 0 => {
+                // Special codes are identified by the value word
+                match (v >> 25) & 0x7F {
+                    0x0C..=0x0F => {
+                        let &(data, _) = it
+                            .next()
+                            .ok_or_else(|| anyhow!("ROM patch without value: {v:08X}"))?;
+                        ops.push(CheatOp::RomPatch {
+                            offset: ((v & 0x00FFFFFF) << 1) & 0x01FFFFFE,
+                            value: data as u16,
+                        });
+                        continue;
+                    }
+                    sty => bail!("Unsupported Action Replay special code {sty:02X}: {v:08X}"),
+                }
+            }
+            // Fills: the upper bits of the value give the number of
+            // locations after the first one
+            0x00 | 0x01 => {
+                let (width, value, count) = if ty == 0x00 {
+                    (Width::W8, v & 0xFF, (v >> 8) + 1)
+                } else {
+                    (Width::W16, v & 0xFFFF, (v >> 16) + 1)
+                };
+                ops.push(if count == 1 {
+                    CheatOp::Write { width, addr, value }
+                } else {
+                    CheatOp::Fill {
+                        width,
+                        addr,
+                        value,
+                        count,
+                    }
+                });
+                continue;
+            }
+            0x02 => (None, Width::W32),
+            0x04 => (Some(Cond::Eq), Width::W8),
+            0x05 => (Some(Cond::Eq), Width::W16),
+            0x06 => (Some(Cond::Eq), Width::W32),
+            0x08 => (Some(Cond::Ne), Width::W8),
+            0x09 => (Some(Cond::Ne), Width::W16),
+            0x0A => (Some(Cond::Ne), Width::W32),
+            _ => bail!("Unsupported Action Replay code type {ty:02X}: {a:08X} {v:08X}"),
+        };
+
+        let value = match width {
+            Width::W8 => v & 0xFF,
+            Width::W16 => v & 0xFFFF,
+            Width::W32 => v,
+        };
+
+        ops.push(match cond {
+            None => CheatOp::Write { width, addr, value },
+            Some(cond) => CheatOp::If {
+                cond,
+                width,
+                addr,
+                value,
+                lines: 1,
+            },
+        });
+    }
+
+    Ok(ops)
+}
+
+fn parse_codebreaker(code: &str) -> Result<Vec<CheatOp>> {
+    let mut ops = vec![];
+
+    for (a, v) in parse_pairs(code, 4)? {
+        let addr = a & 0x0FFFFFFF;
+        let value = v as u16;
+
+        let cond16 = |cond| CheatOp::If {
+            cond,
+            width: Width::W16,
+            addr,
+            value: v,
+            lines: 1,
+        };
+
+        ops.push(match a >> 28 {
+            // Game ID, only meaningful to the real device
+            0x0 => continue,
+            0x1 => CheatOp::Hook {
+                addr: (addr & 0x01FFFFFF) | 0x08000000,
+            },
+            0x2 => CheatOp::Or16 { addr, value },
+            0x3 => CheatOp::Write {
+                width: Width::W8,
+                addr,
+                value: v & 0xFF,
+            },
+            0x6 => CheatOp::And16 { addr, value },
+            0x7 => cond16(Cond::Eq),
+            0x8 => CheatOp::Write {
+                width: Width::W16,
+                addr,
+                value: v,
+            },
+            0x9 => bail!("Encrypted CodeBreaker codes are not supported"),
+            0xA => cond16(Cond::Ne),
+            0xB => cond16(Cond::Gt),
+            0xC => cond16(Cond::Lt),
+            0xD => CheatOp::IfKeys {
+                keys: value,
+                lines: 1,
+            },
+            0xE => CheatOp::Add16 { addr, value },
+            0xF => cond16(Cond::And),
+            ty => bail!("Unsupported CodeBreaker code type {ty:X}: {a:08X} {v:04X}"),
+        });
+    }
+
+    Ok(ops)
+}
+
+fn parse_raw(code: &str) -> Result<Vec<CheatOp>> {
+    let mut ops = vec![];
+
+    for patch in code.split_whitespace() {
+        let (addr, value) = patch
+            .split_once(':')
+            .ok_or_else(|| anyhow!("Expected `address:value`: `{patch}`"))?;
+        let addr =
+            u32::from_str_radix(addr, 16).map_err(|_| anyhow!("Invalid address: `{addr}`"))?;
+        let width = match value.len() {
+            2 => Width::W8,
+            4 => Width::W16,
+            8 => Width::W32,
+            _ => bail!("Value must have 2, 4 or 8 hex digits: `{value}`"),
+        };
+        let value = parse_hex(value, value.len())?;
+
+        if matches!(addr >> 24, 0x8..=0xD) {
+            if addr & 1 != 0 {
+                bail!("Unaligned ROM patch address: 0x{addr:08X}");
+            }
+            match width {
+                Width::W8 => bail!("ROM patches must be 16 or 32 bit: `{patch}`"),
+                Width::W16 => ops.push(CheatOp::RomPatch {
+                    offset: addr & 0x01FFFFFE,
+                    value: value as u16,
+                }),
+                Width::W32 => {
+                    ops.push(CheatOp::RomPatch {
+                        offset: addr & 0x01FFFFFE,
+                        value: value as u16,
+                    });
+                    ops.push(CheatOp::RomPatch {
+                        offset: (addr + 2) & 0x01FFFFFE,
+                        value: (value >> 16) as u16,
+                    });
+                }
+            }
+        } else {
+            ops.push(CheatOp::Write { width, addr, value });
+        }
+    }
+
+    Ok(ops)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Reference TEA encryption, the inverse of `decrypt_gsa`
+    fn encrypt_gsa(mut op1: u32, mut op2: u32, seeds: &[u32; 4]) -> (u32, u32) {
+        let mut sum = 0_u32;
+        for _ in 0..32 {
+            sum = sum.wrapping_add(0x9E3779B9);
+            op1 = op1.wrapping_add(
+                (op2 << 4).wrapping_add(seeds[0])
+                    ^ op2.wrapping_add(sum)
+                    ^ (op2 >> 5).wrapping_add(seeds[1]),
+            );
+            op2 = op2.wrapping_add(
+                (op1 << 4).wrapping_add(seeds[2])
+                    ^ op1.wrapping_add(sum)
+                    ^ (op1 >> 5).wrapping_add(seeds[3]),
+            );
+        }
+        (op1, op2)
+    }
+
+    fn encrypt(codes: &[(u32, u32)], seeds: &[u32; 4]) -> String {
+        codes
+            .iter()
+            .map(|&(a, v)| {
+                let (a, v) = encrypt_gsa(a, v, seeds);
+                format!("{a:08X} {v:08X}")
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    fn ops(format: CheatFormat, code: &str) -> Vec<CheatOp> {
+        Cheat::parse("test", format, code).unwrap().ops
+    }
+
+    #[test]
+    fn tea_test_vector() {
+        // Published TEA vector: all-zero key and plaintext
+        assert_eq!(decrypt_gsa(0x41EA3A0A, 0x94BAA940, &[0; 4]), (0, 0));
+        let (a, v) = encrypt_gsa(0x12345678, 0x9ABCDEF0, &GSA_V3_SEEDS);
+        assert_eq!(decrypt_gsa(a, v, &GSA_V3_SEEDS), (0x12345678, 0x9ABCDEF0));
+    }
+
+    #[test]
+    fn gsa_v1() {
+        let code = encrypt(
+            &[
+                (0x03000100, 0x000000AB),
+                (0x12000200, 0x00001234),
+                (0x23000300, 0xDEADBEEF),
+                (0x60000800, 0x00004770),
+                (0xE0021234, 0x03000400),
+                (0xF8000101, 0x0000001E),
+            ],
+            &GSA_V1_SEEDS,
+        );
+        assert_eq!(
+            ops(CheatFormat::GameSharkV1, &code),
+            [
+                CheatOp::Write {
+                    width: Width::W8,
+                    addr: 0x03000100,
+                    value: 0xAB,
+                },
+                CheatOp::Write {
+                    width: Width::W16,
+                    addr: 0x02000200,
+                    value: 0x1234,
+                },
+                CheatOp::Write {
+                    width: Width::W32,
+                    addr: 0x03000300,
+                    value: 0xDEADBEEF,
+                },
+                CheatOp::RomPatch {
+                    offset: 0x1000,
+                    value: 0x4770,
+                },
+                CheatOp::If {
+                    cond: Cond::Eq,
+                    width: Width::W16,
+                    addr: 0x03000400,
+                    value: 0x1234,
+                    lines: 2,
+                },
+                CheatOp::Hook { addr: 0x08000101 },
+            ]
+        );
+    }
+
+    #[test]
+    fn gsa_v1_errors() {
+        let code = encrypt(&[(0xDEADFACE, 0x00001234)], &GSA_V1_SEEDS);
+        assert!(Cheat::parse("", CheatFormat::GameSharkV1, &code).is_err());
+        let code = encrypt(&[(0x30000000, 0)], &GSA_V1_SEEDS);
+        assert!(Cheat::parse("", CheatFormat::GameSharkV1, &code).is_err());
+    }
+
+    #[test]
+    fn gsa_v3() {
+        let codes = [
+            // 8-bit write to 0x03000010
+            (0x00300010, 0x000000AB),
+            // 16-bit write to 0x02000100
+            (0x02200100, 0x00001234),
+            // If 32-bit [0x03000020] == value
+            (0x0C300020, 0xCAFEBABE),
+            // If 8-bit [0x02000030] != value
+            (0x10200030, 0x00000005),
+            // Master code hooking 0x08000100
+            (0xC4000100, 0x00000000),
+            // ROM patch at offset 0x200, value in the next line
+            (0x00000000, 0x18000100),
+            (0x00004770, 0x00000000),
+        ];
+        let expected = [
+            CheatOp::Write {
+                width: Width::W8,
+                addr: 0x03000010,
+                value: 0xAB,
+            },
+            CheatOp::Write {
+                width: Width::W16,
+                addr: 0x02000100,
+                value: 0x1234,
+            },
+            CheatOp::If {
+                cond: Cond::Eq,
+                width: Width::W32,
+                addr: 0x03000020,
+                value: 0xCAFEBABE,
+                lines: 1,
+            },
+            CheatOp::If {
+                cond: Cond::Ne,
+                width: Width::W8,
+                addr: 0x02000030,
+                value: 5,
+                lines: 1,
+            },
+            CheatOp::Hook { addr: 0x08000100 },
+            CheatOp::RomPatch {
+                offset: 0x200,
+                value: 0x4770,
+            },
+        ];
+
+        let code = encrypt(&codes, &GSA_V3_SEEDS);
+        assert_eq!(ops(CheatFormat::GameSharkV3, &code), expected);
+        // AR MAX codes share the v3 encryption and format
+        assert_eq!(ops(CheatFormat::ActionReplayMax, &code), expected);
+    }
+
+    #[test]
+    fn gsa_v3_fill() {
+        let codes = [
+            // 0x20 bytes of 0xFF from 0x03000010
+            (0x00300010, 0x00001FFF),
+            // 4 halfwords of 0x1234 from 0x02000100
+            (0x02200100, 0x00031234),
+        ];
+        let code = encrypt(&codes, &GSA_V3_SEEDS);
+        assert_eq!(
+            ops(CheatFormat::GameSharkV3, &code),
+            [
+                CheatOp::Fill {
+                    width: Width::W8,
+                    addr: 0x03000010,
+                    value: 0xFF,
+                    count: 0x20,
+                },
+                CheatOp::Fill {
+                    width: Width::W16,
+                    addr: 0x02000100,
+                    value: 0x1234,
+                    count: 4,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn gsa_v3_errors() {
+        for codes in [
+            &[(0xDEADFACE, 0)][..],
+            // ROM patch without its value line
+            &[(0x00000000, 0x18000100)],
+            // Type with the high bit set
+            &[(0x01300010, 0)],
+        ] {
+            let code = encrypt(codes, &GSA_V3_SEEDS);
+            assert!(Cheat::parse("", CheatFormat::ActionReplayMax, &code).is_err());
+        }
+    }
+
+    #[test]
+    fn codebreaker() {
+        let code = "0000ABCD 000A
+                    18000101 0007
+                    32000010 00FF
+                    82000020 1234
+                    D0000020 0009
+                    73000030 0001
+                    E3000040 0010
+                    F3000050 8000
+                    23000060 0100
+                    63000070 FEFF";
+        assert_eq!(
+            ops(CheatFormat::CodeBreaker, code),
+            [
+                CheatOp::Hook { addr: 0x08000101 },
+                CheatOp::Write {
+                    width: Width::W8,
+                    addr: 0x02000010,
+                    value: 0xFF,
+                },
+                CheatOp::Write {
+                    width: Width::W16,
+                    addr: 0x02000020,
+                    value: 0x1234,
+                },
+                CheatOp::IfKeys { keys: 9, lines: 1 },
+                CheatOp::If {
+                    cond: Cond::Eq,
+                    width: Width::W16,
+                    addr: 0x03000030,
+                    value: 1,
+                    lines: 1,
+                },
+                CheatOp::Add16 {
+                    addr: 0x03000040,
+                    value: 0x10,
+                },
+                CheatOp::If {
+                    cond: Cond::And,
+                    width: Width::W16,
+                    addr: 0x03000050,
+                    value: 0x8000,
+                    lines: 1,
+                },
+                CheatOp::Or16 {
+                    addr: 0x03000060,
+                    value: 0x100,
+                },
+                CheatOp::And16 {
+                    addr: 0x03000070,
+                    value: 0xFEFF,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn codebreaker_errors() {
+        // Encrypted codes
+        assert!(Cheat::parse("", CheatFormat::CodeBreaker, "9123ABCD 0001").is_err());
+        // Wrong number of digits
+        assert!(Cheat::parse("", CheatFormat::CodeBreaker, "32000010 000FF").is_err());
+        assert!(Cheat::parse("", CheatFormat::CodeBreaker, "32000010").is_err());
+        // Game ID alone does nothing
+        assert!(Cheat::parse("", CheatFormat::CodeBreaker, "0000ABCD 000A").is_err());
+    }
+
+    #[test]
+    fn raw() {
+        assert_eq!(
+            ops(
+                CheatFormat::Raw,
+                "02000000:12 03000010:BEEF 08000100:46C04770"
+            ),
+            [
+                CheatOp::Write {
+                    width: Width::W8,
+                    addr: 0x02000000,
+                    value: 0x12,
+                },
+                CheatOp::Write {
+                    width: Width::W16,
+                    addr: 0x03000010,
+                    value: 0xBEEF,
+                },
+                CheatOp::RomPatch {
+                    offset: 0x100,
+                    value: 0x4770,
+                },
+                CheatOp::RomPatch {
+                    offset: 0x102,
+                    value: 0x46C0,
+                },
+            ]
+        );
+        assert!(Cheat::parse("", CheatFormat::Raw, "08000101:4770").is_err());
+        assert!(Cheat::parse("", CheatFormat::Raw, "08000100:47").is_err());
+        assert!(Cheat::parse("", CheatFormat::Raw, "02000000:123").is_err());
+    }
+
+    #[test]
+    fn engine() {
+        let mut engine = CheatEngine::new();
+        let hook = engine.add(Cheat::parse("", CheatFormat::CodeBreaker, "18000101 0007").unwrap());
+        engine.add(Cheat::parse("", CheatFormat::Raw, "08000200:1234").unwrap());
+        assert_eq!(engine.hooks(), [0x08000100]);
+        assert_eq!(engine.rom_patches(), HashMap::from([(0x200, 0x1234)]));
+
+        engine.set_enabled(hook, false).unwrap();
+        assert!(engine.hooks().is_empty());
+        assert!(engine.set_enabled(5, false).is_err());
+        assert!(engine.remove(hook).is_some());
+        assert_eq!(engine.cheats().len(), 1);
+    }
+}
@@ -15,6 +15,9 @@ pub trait Bus {
     fn write16(&mut self, addr: u32, data: u16, first: bool);
     fn write32(&mut self, addr: u32, data: u32, first: bool);
 
+    fn peek8(&mut self, addr: u32) -> Option<u8>;
+    fn poke8(&mut self, addr: u32, data: u8);
+
     fn bus_tick(&mut self);
     fn dma_tick(&mut self) -> bool;
 
@@ -63,6 +66,12 @@ pub trait GamePak {
 
     fn backup(&self) -> &backup::Backup;
     fn backup_mut(&mut self) -> &mut backup::Backup;
+
+    fn gamepak_read(&mut self, addr: u32) -> Option<u16>;
+    fn gamepak_write(&mut self, addr: u32, data: u16);
+
+    fn backup_read(&mut self, addr: u32) -> u8;
+    fn backup_write(&mut self, addr: u32, data: u8);
 }
 
 #[delegatable_trait]
@@ -84,7 +93,7 @@ pub struct Context {
 }
 
 impl Context {
-    pub fn new(bios: Vec<u8>, rom: rom::Rom, backup: Option<Vec<u8>>) -> Self {
+    pub fn new(bios: Vec<u8>, rom: rom::Rom, backup: backup::Backup) -> Self {
         let cpu = cpu::Cpu::new();
         let bus = bus::Bus::new(bios);
         let lcd = lcd::Lcd::new();
@@ -157,6 +166,13 @@ impl Bus for Inner {
         self.bus.write32(&mut self.inner, addr, data, first)
     }
 
+    fn peek8(&mut self, addr: u32) -> Option<u8> {
+        self.bus.peek8(&mut self.inner, addr)
+    }
+    fn poke8(&mut self, addr: u32, data: u8) {
+        self.bus.poke8(&mut self.inner, addr, data)
+    }
+
     fn bus_tick(&mut self) {
         self.bus.tick(&mut self.inner);
     }
@@ -238,6 +254,22 @@ impl GamePak for Inner2 {
     fn backup_mut(&mut self) -> &mut backup::Backup {
         self.gamepak.backup_mut()
     }
+
+    fn gamepak_read(&mut self, addr: u32) -> Option<u16> {
+        self.gamepak.read(&self.inner, addr)
+    }
+    fn gamepak_write(&mut self, addr: u32, data: u16) {
+        self.gamepak.write(&self.inner, addr, data)
+    }
+
+    fn backup_read(&mut self, addr: u32) -> u8 {
+        let now = self.inner.now();
+        self.gamepak.backup_mut().read_ram(addr, now)
+    }
+    fn backup_write(&mut self, addr: u32, data: u8) {
+        let now = self.inner.now();
+        self.gamepak.backup_mut().write_ram(addr, data, now)
+    }
 }
 
 #[derive(Serialize, Deserialize)]
@@ -6,6 +6,8 @@ use std::mem::size_of;
 use crate::{
     bios::trace_swi,
     context::{Bus, Interrupt, Timing},
+    history::{CallFrame, CallKind, FaultReport, FaultReportEntry, History, HistoryEntry},
+    trace::{TraceRecord, TraceSink},
     util::trait_alias,
 };
 
@@ -26,6 +28,11 @@ pub struct Cpu<C: Context> {
     trace: bool,
     prev_regs: [u32; 16],
 
+    #[serde(skip)]
+    tracer: Option<Box<dyn TraceSink + Send>>,
+    #[serde(skip)]
+    history: Option<History>,
+
     #[serde(skip)]
     op_tables: OpTables<C>,
 }
@@ -89,6 +96,27 @@ impl Exception {
             Exception::FIQ => MODE_FIQ,
         }
     }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Exception::Reset => "Reset",
+            Exception::UndefinedInstruction => "Undefined",
+            Exception::SoftwareInterrupt => "SWI",
+            Exception::PrefetchAbort => "Prefetch Abort",
+            Exception::DataAbort => "Data Abort",
+            Exception::IRQ => "IRQ",
+            Exception::FIQ => "FIQ",
+        }
+    }
+
+    // Offset from the saved LR to the address the handler returns to
+    fn return_offset(&self) -> u32 {
+        match self {
+            Exception::Reset | Exception::UndefinedInstruction | Exception::SoftwareInterrupt => 0,
+            Exception::PrefetchAbort | Exception::IRQ | Exception::FIQ => 4,
+            Exception::DataAbort => 8,
+        }
+    }
 }
 
 const MODE_USER: u8 = 0b10000;
@@ -99,7 +127,7 @@ const MODE_ABORT: u8 = 0b10111;
 const MODE_UNDEFINED: u8 = 0b11011;
 const MODE_SYSTEM: u8 = 0b11111;
 
-fn mode_name(mode: u8) -> &'static str {
+pub fn mode_name(mode: u8) -> &'static str {
     match mode {
         MODE_USER => "USR",
         MODE_FIQ => "FIQ",
@@ -287,6 +315,8 @@ impl<C: Context> Cpu<C> {
             prefetch: 0,
             trace: false,
             prev_regs: [0; 16],
+            tracer: None,
+            history: None,
             op_tables: OpTables::default(),
         }
     }
@@ -295,6 +325,86 @@ impl<C: Context> Cpu<C> {
         &self.regs
     }
 
+    pub fn set_tracer(
+        &mut self,
+        tracer: Option<Box<dyn TraceSink + Send>>,
+    ) -> Option<Box<dyn TraceSink + Send>> {
+        std::mem::replace(&mut self.tracer, tracer)
+    }
+
+    /// Enables the instruction history and shadow call stack, keeping the last
+    /// `capacity` instructions. `None` disables them.
+    pub fn set_history_capacity(&mut self, capacity: Option<usize>) {
+        self.history = capacity.map(History::new);
+    }
+
+    /// Moves the host-side tracer and history over from another CPU instance
+    /// (after reset or state load), since they are not part of the state.
+    pub fn swap_debug_hooks(&mut self, other: &mut Self) {
+        std::mem::swap(&mut self.tracer, &mut other.tracer);
+        std::mem::swap(&mut self.history, &mut other.history);
+    }
+
+    pub fn history(&self) -> Option<&History> {
+        self.history.as_ref()
+    }
+
+    pub fn fault_report(&self, cycle: u64) -> FaultReport {
+        let (call_stack, history) = match &self.history {
+            Some(h) => (
+                h.call_stack().cloned().collect(),
+                h.instrs()
+                    .map(|e| FaultReportEntry {
+                        disasm: if e.thumb {
+                            self.disasm_thumb(e.opcode as u16, e.pc)
+                        } else {
+                            self.disasm_arm(e.opcode, e.pc)
+                        },
+                        entry: e.clone(),
+                    })
+                    .collect(),
+            ),
+            None => (vec![], vec![]),
+        };
+
+        FaultReport {
+            regs: self.regs.r,
+            cpsr: self.regs.cpsr(),
+            mode: self.regs.mode,
+            thumb: self.regs.state,
+            cycle,
+            call_stack,
+            history,
+        }
+    }
+
+    fn shadow_call(&mut self, kind: CallKind, call_site: u32, target: u32, return_addr: u32) {
+        if let Some(history) = &mut self.history {
+            history.push_call(CallFrame {
+                kind,
+                call_site,
+                target,
+                return_addr,
+                sp: self.regs.r[13],
+            });
+        }
+    }
+
+    fn shadow_ret(&mut self, target: u32) {
+        if let Some(history) = &mut self.history {
+            history.ret(target);
+        }
+    }
+
+    /// Address of the instruction to be executed next
+    pub fn next_pc(&self) -> u32 {
+        if !self.regs.state {
+            self.regs.r[15].wrapping_sub(4)
+        } else {
+            self.regs.r[15].wrapping_sub(2)
+        }
+    }
+
     pub fn set_pc(&mut self, ctx: &mut C, pc: u32) {
         self.regs.r[15] = pc;
         ctx.bus_mut().set_pc(pc);
@@ -307,6 +417,25 @@ impl<C: Context> Cpu<C> {
         }
     }
 
+    /// Sets up the registers the way the BIOS leaves them after booting and
+    /// jumps to `pc`
+    pub fn skip_bios(&mut self, ctx: &mut C, pc: u32) {
+        for (mode, sp) in [
+            (MODE_SUPERVISOR, 0x03007FE0),
+            (MODE_IRQ, 0x03007FA0),
+            (MODE_SYSTEM, 0x03007F00),
+        ] {
+            self.regs.change_mode(mode);
+            self.regs.r[13] = sp;
+            self.regs.r[14] = 0;
+            self.regs.spsr = 0;
+        }
+        self.regs.irq_disable = false;
+        self.regs.fiq_disable = false;
+        self.regs.state = false;
+        self.set_pc(ctx, pc);
+    }
+
     pub fn exec_one(&mut self, ctx: &mut C) {
         if ctx.interrupt().halt() {
             ctx.elapse(1);
@@ -356,6 +485,13 @@ impl<C: Context> Cpu<C> {
             }
         };
 
+        let resume_addr = old_pc.wrapping_sub(e.return_offset());
+        let interrupted = if !self.regs.state {
+            self.regs.r[15].wrapping_sub(8)
+        } else {
+            self.regs.r[15].wrapping_sub(4)
+        };
+
         self.regs.change_mode(e.mode_on_entry());
         self.regs.spsr = old_cpsr;
         self.regs.r[14] = old_pc;
@@ -363,6 +499,13 @@ impl<C: Context> Cpu<C> {
         self.regs.irq_disable = true;
         self.regs.fiq_disable = true;
         self.set_pc(ctx, e.vector_addr());
+
+        self.shadow_call(
+            CallKind::Exception(e.name()),
+            interrupted,
+            e.vector_addr(),
+            resume_addr,
+        );
     }
 
     fn exec_arm(&mut self, ctx: &mut C) {
@@ -376,6 +519,18 @@ impl<C: Context> Cpu<C> {
             trace!("{pc:08X}: {instr:08X}: {s:24} {regs}");
         }
 
+        if self.tracer.is_some() {
+            self.record_trace(ctx, instr, self.regs.r[15].wrapping_sub(8));
+        }
+        if let Some(history) = &mut self.history {
+            history.push_instr(HistoryEntry {
+                pc: self.regs.r[15].wrapping_sub(8),
+                opcode: instr,
+                thumb: false,
+                cycle: ctx.now(),
+            });
+        }
+
         if self.regs.check_cond((instr >> 28) as u8) {
             let ix = (instr >> 16) & 0xFF0 | (instr >> 4) & 0xF;
             self.op_tables.arm_op_table[ix as usize](self, ctx, instr);
@@ -393,10 +548,41 @@ impl<C: Context> Cpu<C> {
             trace!("{pc:08X}:     {instr:04X}: {s:24} {regs}");
         }
 
+        if self.tracer.is_some() {
+            self.record_trace(ctx, instr as u32, self.regs.r[15].wrapping_sub(4));
+        }
+        if let Some(history) = &mut self.history {
+            history.push_instr(HistoryEntry {
+                pc: self.regs.r[15].wrapping_sub(4),
+                opcode: instr as u32,
+                thumb: true,
+                cycle: ctx.now(),
+            });
+        }
+
         let ix = instr >> 6;
         self.op_tables.thumb_op_table[ix as usize](self, ctx, instr);
     }
 
+    fn record_trace(&mut self, ctx: &C, opcode: u32, pc: u32) {
+        let record = TraceRecord {
+            pc,
+            opcode,
+            thumb: self.regs.state,
+            mode: self.regs.mode,
+            regs: self.regs.r,
+            cpsr: self.regs.cpsr(),
+            cycle: ctx.now(),
+        };
+
+        if let Some(tracer) = &mut self.tracer {
+            if let Err(err) = tracer.write_record(&record) {
+                warn!("Failed to write trace, tracing disabled: {err}");
+                self.tracer = None;
+            }
+        }
+    }
+
     fn fetch32(&mut self, ctx: &mut C) {
         let pc = self.regs.r[15];
         assert_eq!(pc & 0x3, 0, "Fetch from unaligned address: 0x{pc:08X}");
@@ -1027,6 +1213,9 @@ fn arm_op_bx<C: Context>(cpu: &mut Cpu<C>, ctx: &mut C, instr: u32) {
     let new_pc = cpu.regs.r[rn];
     cpu.regs.state = new_pc & 1 != 0;
     cpu.set_pc(ctx, new_pc & !1);
+    if rn == 14 {
+        cpu.shadow_ret(new_pc);
+    }
 }
 
 fn arm_disasm_bx(instr: u32, _pc: u32) -> String {
@@ -1041,10 +1230,17 @@ fn arm_op_b<C: Context, const L: bool>(cpu: &mut Cpu<C>, ctx: &mut C, instr: u32
     // TODO: 2S + 1N cycles
     let offset = (((instr & 0xFFFFFF) << 8) as i32 >> 8) << 2;
     let old_pc = cpu.regs.r[15];
+    let dest = old_pc.wrapping_add(offset as u32);
     if L {
         cpu.regs.r[14] = old_pc.wrapping_sub(4);
+        cpu.shadow_call(
+            CallKind::Call,
+            old_pc.wrapping_sub(8),
+            dest,
+            old_pc.wrapping_sub(4),
+        );
     }
-    cpu.set_pc(ctx, old_pc.wrapping_add(offset as u32));
+    cpu.set_pc(ctx, dest);
 }
 
 fn arm_disasm_b(instr: u32, pc: u32) -> String {
@@ -1343,6 +1539,7 @@ fn arm_op_alu<C: Context, const I: bool, const S: bool, const O: u8>(
                 cpu.regs.set_cpsr(cpu.regs.spsr);
             }
             cpu.set_pc(ctx, res);
+            cpu.shadow_ret(res);
         }
     } else if rd == 15 {
         assert!(cpu.regs.mode != MODE_USER);
@@ -2106,6 +2303,7 @@ fn arm_op_ldstm<
             let data = ctx.read32(addr, first).unwrap_or(cpu.prefetch);
             cpu.fetch_first = true;
             cpu.set_pc(ctx, data);
+            cpu.shadow_ret(data);
         } else {
             // Whenever R15 is stored to memory the stored value is the address of the STM
             // instruction plus 12.
@@ -2338,30 +2536,30 @@ fn arm_disasm_mrc_mcr(instr: u32, _pc: u32) -> String {
     return format!("{mne}{cond} p{cp_num}, {cp_opc}, r{rd}, c{crn}, c{crm}{expr2}");
 }
 
-fn arm_op_undef<C: Context>(_cpu: &mut Cpu<C>, _ctx: &mut C, instr: u32) {
+fn arm_op_undef<C: Context>(cpu: &mut Cpu<C>, ctx: &mut C, instr: u32) {
     warn!("Undefined instruction: {:08X}", instr);
-    panic!()
+    panic!("Undefined instruction\n{}", cpu.fault_report(ctx.now()))
 }
 
 fn arm_disasm_undef(_instr: u32, _pc: u32) -> String {
     "undef".to_string()
 }
 
-fn arm_op_invalid<C: Context>(_cpu: &mut Cpu<C>, _ctx: &mut C, instr: u32) {
+fn arm_op_invalid<C: Context>(cpu: &mut Cpu<C>, ctx: &mut C, instr: u32) {
     warn!("Invalid instruction: {:08X}", instr);
-    panic!()
+    panic!("Invalid instruction\n{}", cpu.fault_report(ctx.now()))
 }
 
 fn arm_disasm_invalid(_instr: u32, _pc: u32) -> String {
     "invalid".to_string()
 }
 
-fn thumb_op_invalid<C: Context>(cpu: &mut Cpu<C>, _ctx: &mut C, instr: u16) {
+fn thumb_op_invalid<C: Context>(cpu: &mut Cpu<C>, ctx: &mut C, instr: u16) {
     warn!(
         "Invalid instruction: PC: {:08X}, instr: {instr:04X}",
         cpu.regs.r[15].wrapping_sub(2)
     );
-    panic!()
+    panic!("Invalid instruction\n{}", cpu.fault_report(ctx.now()))
 }
 
 impl<C: Context> Cpu<C> {
@@ -2625,6 +2823,7 @@ fn thumb_op_hireg<C: Context, const OP: usize, const H1: bool, const H2: bool>(
             cpu.regs.r[rd] = res;
         } else {
             cpu.set_pc(ctx, res & !1);
+            cpu.shadow_ret(res);
         }
     }
 }
@@ -2633,6 +2832,10 @@ fn thumb_op_bx<C: Context, const H: bool>(cpu: &mut Cpu<C>, ctx: &mut C, instr:
     let rs = (H as usize * 8) + ((instr >> 3) & 7) as usize;
     let new_pc = cpu.regs.r[rs];
 
+    if rs == 14 {
+        cpu.shadow_ret(new_pc);
+    }
+
     cpu.regs.state = new_pc & 1 != 0;
 
     if !cpu.regs.state {
@@ -2876,6 +3079,7 @@ fn block_trans<C: Context, const L: bool, const IA: bool, const R: bool>(
         } else {
             let new_pc = ctx.read32(addr, first).unwrap_or(cpu.prefetch) & !1;
             cpu.set_pc(ctx, new_pc);
+            cpu.shadow_ret(new_pc);
         }
         first = false;
     }
@@ -2978,6 +3182,7 @@ fn thumb_op_bl<C: Context, const H: bool>(cpu: &mut Cpu<C>, ctx: &mut C, instr:
         let new_pc = cpu.regs.r[14].wrapping_add(offset * 2);
         cpu.set_pc(ctx, new_pc);
         cpu.regs.r[14] = ret_addr | 1;
+        cpu.shadow_call(CallKind::Call, ret_addr.wrapping_sub(4), new_pc, ret_addr);
     }
 }
 
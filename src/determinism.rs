@@ -0,0 +1,277 @@
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+use crate::{
+    context::{Bus, Context, GamePak, Interrupt, Lcd, Sound, SoundDma, Timing},
+    util::Fnv1a64,
+};
+
+pub const HASH_LOG_MAGIC: &[u8; 8] = b"TGBAHASH";
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub enum Component {
+    Cpu,
+    /// Memory, DMA, timers and I/O registers owned by the bus
+    Bus,
+    Lcd,
+    Sound,
+    /// ROM-side state: backup memory and cartridge registers
+    GamePak,
+    Interrupt,
+    /// Cycle counter and sound DMA requests
+    Timing,
+}
+
+impl Component {
+    pub const ALL: [Component; 7] = [
+        Component::Cpu,
+        Component::Bus,
+        Component::Lcd,
+        Component::Sound,
+        Component::GamePak,
+        Component::Interrupt,
+        Component::Timing,
+    ];
+}
+
+/// Hash of the emulated state, per component. Host-only data (frame and audio
+/// buffers, debug hooks, ...) is not serialized and so does not contribute.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct StateHash {
+    pub components: [u64; Component::ALL.len()],
+}
+
+impl StateHash {
+    pub fn of(ctx: &Context) -> Self {
+        fn hash(v: &impl Serialize) -> u64 {
+            let mut h = Fnv1a64::default();
+            bincode::serialize_into(&mut h, v).unwrap();
+            h.finish()
+        }
+
+        let components = Component::ALL.map(|c| match c {
+            Component::Cpu => hash(&ctx.cpu),
+            Component::Bus => hash(ctx.bus()),
+            Component::Lcd => hash(ctx.lcd()),
+            Component::Sound => hash(ctx.sound()),
+            Component::GamePak => hash(ctx.gamepak()),
+            Component::Interrupt => hash(ctx.interrupt()),
+            Component::Timing => hash(&(
+                ctx.now(),
+                ctx.inner.inner.sound_dma_request(0),
+                ctx.inner.inner.sound_dma_request(1),
+            )),
+        });
+        Self { components }
+    }
+
+    pub fn get(&self, component: Component) -> u64 {
+        self.components[component as usize]
+    }
+
+    /// Single value covering all components
+    pub fn combined(&self) -> u64 {
+        let mut h = Fnv1a64::default();
+        bincode::serialize_into(&mut h, &self.components).unwrap();
+        h.finish()
+    }
+
+    /// Components whose hashes differ
+    pub fn diff(&self, other: &StateHash) -> Vec<Component> {
+        Component::ALL
+            .into_iter()
+            .filter(|&c| self.get(c) != other.get(c))
+            .collect()
+    }
+}
+
+/// State hashes after every frame of a movie
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct HashLog {
+    pub hashes: Vec<StateHash>,
+}
+
+impl HashLog {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut ret = HASH_LOG_MAGIC.to_vec();
+        bincode::serialize_into(&mut ret, self).unwrap();
+        ret
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Self> {
+        let body = data
+            .strip_prefix(HASH_LOG_MAGIC)
+            .ok_or_else(|| anyhow!("Not a hash log"))?;
+        Ok(bincode::deserialize(body)?)
+    }
+
+    /// First frame whose hash differs from `actual`. A log that ends early
+    /// diverges at its end.
+    pub fn first_divergence(&self, actual: &HashLog) -> Option<Divergence> {
+        for (frame, (expected, actual)) in self.hashes.iter().zip(&actual.hashes).enumerate() {
+            if expected != actual {
+                return Some(Divergence {
+                    frame,
+                    components: expected.diff(actual),
+                    expected: Some(*expected),
+                    actual: Some(*actual),
+                });
+            }
+        }
+
+        let frame = self.hashes.len().min(actual.hashes.len());
+        (self.hashes.len() != actual.hashes.len()).then(|| Divergence {
+            frame,
+            components: vec![],
+            expected: self.hashes.get(frame).copied(),
+            actual: actual.hashes.get(frame).copied(),
+        })
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Divergence {
+    /// Index of the first movie frame after which the states differ
+    pub frame: usize,
+    /// Empty if one of the runs ended before this frame
+    pub components: Vec<Component>,
+    pub expected: Option<StateHash>,
+    pub actual: Option<StateHash>,
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (&self.expected, &self.actual) {
+            (Some(_), Some(_)) => write!(
+                f,
+                "State diverged after frame {} in {:?}",
+                self.frame, self.components
+            ),
+            (Some(_), None) => write!(f, "Run ended early at frame {}", self.frame),
+            _ => write!(
+                f,
+                "Run continued past the end of the log at frame {}",
+                self.frame
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{movie::MovieStart, Agb, KeyInput, Rom};
+
+    fn hash(seed: u64) -> StateHash {
+        StateHash {
+            components: std::array::from_fn(|i| seed * 100 + i as u64),
+        }
+    }
+
+    fn log(hashes: &[StateHash]) -> HashLog {
+        HashLog {
+            hashes: hashes.to_vec(),
+        }
+    }
+
+    #[test]
+    fn identical_logs() {
+        let a = log(&[hash(0), hash(1), hash(2)]);
+        assert!(a.first_divergence(&a.clone()).is_none());
+        assert!(log(&[]).first_divergence(&log(&[])).is_none());
+    }
+
+    #[test]
+    fn diverged_component() {
+        let expected = log(&[hash(0), hash(1), hash(2), hash(3)]);
+        let mut diverged = hash(2);
+        diverged.components[Component::Lcd as usize] ^= 1;
+        diverged.components[Component::Timing as usize] ^= 1;
+        // Later frames differ as well, only the first is reported
+        let actual = log(&[hash(0), hash(1), diverged, hash(4)]);
+
+        let d = expected.first_divergence(&actual).unwrap();
+        assert_eq!(d.frame, 2);
+        assert_eq!(d.components, [Component::Lcd, Component::Timing]);
+        assert_eq!(d.expected, Some(hash(2)));
+        assert_eq!(d.actual, Some(diverged));
+    }
+
+    #[test]
+    fn different_length() {
+        let long = log(&[hash(0), hash(1), hash(2)]);
+        let short = log(&[hash(0), hash(1)]);
+
+        let d = long.first_divergence(&short).unwrap();
+        assert_eq!(d.frame, 2);
+        assert!(d.components.is_empty());
+        assert_eq!((d.expected, d.actual), (Some(hash(2)), None));
+        assert!(d.to_string().contains("ended early"));
+
+        let d = short.first_divergence(&long).unwrap();
+        assert_eq!(d.frame, 2);
+        assert_eq!((d.expected, d.actual), (None, Some(hash(2))));
+    }
+
+    #[test]
+    fn hash_log_bytes() {
+        let a = log(&[hash(0), hash(1)]);
+        let b = HashLog::from_bytes(&a.to_bytes()).unwrap();
+        assert!(a.first_divergence(&b).is_none());
+        assert!(HashLog::from_bytes(b"TGBAMOV1").is_err());
+    }
+
+    // Counts up in IWRAM forever
+    fn agb() -> Agb {
+        let mut rom = vec![0; 0x400];
+        let code: [u32; 5] = [
+            0xEA00002E, // b 0xC0
+            0xE3A00403, // mov r0, #0x03000000
+            0xE5901000, // ldr r1, [r0]
+            0xE2811001, // add r1, r1, #1
+            0xE5801000, // str r1, [r0]
+        ];
+        rom[..4].copy_from_slice(&code[0].to_le_bytes());
+        for (i, c) in code[1..].iter().enumerate() {
+            rom[0xC0 + i * 4..0xC4 + i * 4].copy_from_slice(&c.to_le_bytes());
+        }
+        // b 0xC4
+        rom[0xD0..0xD4].copy_from_slice(&0xEAFFFFFB_u32.to_le_bytes());
+        rom[0xAC..0xB0].copy_from_slice(b"TDET");
+        Rom::fix_header(&mut rom).unwrap();
+
+        let mut bios = vec![0; 0x4000];
+        // mov pc, #0x08000000
+        bios[..4].copy_from_slice(&0xE3A0F302_u32.to_le_bytes());
+        Agb::new(bios, Rom::from_bytes(&rom).unwrap(), None)
+    }
+
+    #[test]
+    fn deterministic_movie() {
+        // Debug builds construct the context on the stack
+        std::thread::Builder::new()
+            .stack_size(64 * 1024 * 1024)
+            .spawn(|| {
+                let mut agb = agb();
+                agb.record_movie(MovieStart::PowerOn).unwrap();
+                for i in 0..10 {
+                    let keys = KeyInput {
+                        a: i % 3 == 0,
+                        ..Default::default()
+                    };
+                    agb.set_key_input(&keys);
+                    agb.exec_frame(false);
+                }
+                let movie = agb.stop_movie().unwrap();
+
+                let log = agb.record_hash_log(&movie).unwrap();
+                assert_eq!(log.hashes.len(), 10);
+                assert_ne!(log.hashes[0], log.hashes[1]);
+                assert!(agb.verify_determinism(&movie).unwrap().is_none());
+            })
+            .unwrap()
+            .join()
+            .unwrap();
+    }
+}
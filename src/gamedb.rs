@@ -0,0 +1,364 @@
+use anyhow::{anyhow, bail, Context as _, Result};
+use std::{collections::HashMap, path::Path, sync::OnceLock};
+
+use log::warn;
+
+use crate::backup::{flash::FlashChip, BackupConfig, BackupType};
+
+const BUNDLED_GAMEDB: &str = include_str!("gamedb.ini");
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum GpioDevice {
+    Rtc,
+    SolarSensor,
+    TiltSensor,
+    Gyro,
+    Rumble,
+}
+
+/// What is known about a title beyond its ROM header. Fields that are `None`
+/// fall back to detection from the ROM.
+#[derive(Clone, Debug, Default)]
+pub struct GameInfo {
+    pub title: Option<String>,
+    pub backup: Option<BackupType>,
+    /// Flash chip ID reported in ID mode, manufacturer in the low byte
+    pub flash_id: Option<u16>,
+    pub gpio: Option<Vec<GpioDevice>>,
+    /// Address of the busy loop the game waits in for interrupts. Time skips
+    /// ahead to the next LCD event or timer overflow whenever the loop
+    /// comes back there.
+    pub idle_loop: Option<u32>,
+    /// Emulation issues of the title, see `GameDb`
+    pub quirks: Option<Vec<String>>,
+}
+
+/// The ROM repeats over the whole cartridge space
+pub const QUIRK_ROM_MIRRORING: &str = "rom_mirroring";
+
+impl GameInfo {
+    pub fn backup_config(&self) -> BackupConfig {
+        BackupConfig {
+            backup_type: self.backup,
+            flash_chip: self.flash_id.and_then(|id| {
+                let chip = FlashChip::from_id(id);
+                if chip.is_none() {
+                    warn!("Unknown flash chip ID: {id:04X}");
+                }
+                chip
+            }),
+        }
+    }
+
+    pub fn has_quirk(&self, quirk: &str) -> bool {
+        self.quirks.iter().flatten().any(|q| q == quirk)
+    }
+
+    /// Overwrites the fields set in `other`
+    pub fn merge(&mut self, other: &GameInfo) {
+        fn set<T: Clone>(dst: &mut Option<T>, src: &Option<T>) {
+            if src.is_some() {
+                dst.clone_from(src);
+            }
+        }
+        set(&mut self.title, &other.title);
+        set(&mut self.backup, &other.backup);
+        set(&mut self.flash_id, &other.flash_id);
+        set(&mut self.gpio, &other.gpio);
+        set(&mut self.idle_loop, &other.idle_loop);
+        set(&mut self.quirks, &other.quirks);
+    }
+}
+
+/// Per-title overrides keyed by game code and optionally the ROM revision.
+///
+/// The format is INI-like:
+///
+/// ```text
+/// # Pokemon Emerald, any revision
+/// [BPEE]
+/// title = Pokemon Emerald
+/// save = flash1m
+/// flash_id = 0x09C2
+/// gpio = rtc
+///
+/// # Revision 1 only
+/// [AXVE.1]
+/// idle_loop = 0x08000000
+/// ```
+///
+/// `save` is one of `none`, `sram`, `eeprom`, `eeprom512`, `eeprom8k`,
+/// `flash512`, `flash1m`. `flash_id` selects the emulated flash chip by
+/// its ID (see `FlashChip`). `gpio` and `quirks` are comma separated lists,
+/// GPIO devices being `rtc`, `solar`, `tilt`, `gyro` and `rumble`. `idle_loop`
+/// is the address of the first instruction of the loop. GPIO
+/// devices are not emulated yet; a warning is logged for them. The only
+/// quirk acted on is `rom_mirroring`, others are kept as notes.
+#[derive(Clone, Debug, Default)]
+pub struct GameDb {
+    entries: HashMap<([u8; 4], Option<u8>), GameInfo>,
+}
+
+impl GameDb {
+    /// The database shipped with tgba
+    pub fn bundled() -> &'static GameDb {
+        static DB: OnceLock<GameDb> = OnceLock::new();
+        DB.get_or_init(|| GameDb::parse(BUNDLED_GAMEDB).unwrap())
+    }
+
+    pub fn parse(s: &str) -> Result<GameDb> {
+        let mut entries = HashMap::new();
+        let mut cur = None;
+
+        for (lineno, line) in s.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+                continue;
+            }
+            let err = || format!("Line {}: {line}", lineno + 1);
+
+            if let Some(section) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+                let key = parse_key(section.trim()).with_context(err)?;
+                entries.entry(key).or_insert_with(GameInfo::default);
+                cur = Some(key);
+                continue;
+            }
+
+            let key = cur
+                .ok_or_else(|| anyhow!("Entry outside of a section"))
+                .with_context(err)?;
+            let (name, value) = line
+                .split_once('=')
+                .ok_or_else(|| anyhow!("Expected `key = value`"))
+                .with_context(err)?;
+            let info = entries.get_mut(&key).unwrap();
+            set_field(info, name.trim(), value.trim()).with_context(err)?;
+        }
+
+        Ok(GameDb { entries })
+    }
+
+    /// Returns this database with the entries of a user override file
+    /// taking precedence
+    pub fn load_overrides(&self, path: impl AsRef<Path>) -> Result<GameDb> {
+        let path = path.as_ref();
+        let s = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        let overrides = GameDb::parse(&s).with_context(|| format!("{}", path.display()))?;
+        Ok(self.with_overrides(&overrides))
+    }
+
+    /// Returns a database where entries of `overrides` take precedence,
+    /// field by field.
+    pub fn with_overrides(&self, overrides: &GameDb) -> GameDb {
+        let mut ret = self.clone();
+        for (key, info) in &overrides.entries {
+            ret.entries.entry(*key).or_default().merge(info);
+        }
+        ret
+    }
+
+    /// Revision specific entries are applied over the ones for any revision
+    pub fn lookup(&self, game_code: &[u8; 4], revision: u8) -> Option<GameInfo> {
+        let any = self.entries.get(&(*game_code, None));
+        let rev = self.entries.get(&(*game_code, Some(revision)));
+        if any.is_none() && rev.is_none() {
+            return None;
+        }
+
+        let mut ret = any.cloned().unwrap_or_default();
+        if let Some(rev) = rev {
+            ret.merge(rev);
+        }
+        Some(ret)
+    }
+}
+
+fn parse_key(s: &str) -> Result<([u8; 4], Option<u8>)> {
+    let (code, rev) = match s.split_once('.') {
+        Some((code, rev)) => (code, Some(rev.parse::<u8>()?)),
+        None => (s, None),
+    };
+    let code: [u8; 4] = code
+        .as_bytes()
+        .try_into()
+        .map_err(|_| anyhow!("Game code must be 4 characters: {code}"))?;
+    Ok((code, rev))
+}
+
+fn set_field(info: &mut GameInfo, name: &str, value: &str) -> Result<()> {
+    let list = || value.split(',').map(str::trim).filter(|s| !s.is_empty());
+
+    match name {
+        "title" => info.title = Some(value.to_string()),
+        "save" => {
+            info.backup = Some(match value.to_ascii_lowercase().as_str() {
+                "none" => BackupType::None,
+                "sram" => BackupType::Sram,
+                "eeprom" => BackupType::Eeprom,
+                "eeprom512" => BackupType::Eeprom512,
+                "eeprom8k" => BackupType::Eeprom8K,
+                "flash512" => BackupType::Flash512,
+                "flash1m" => BackupType::Flash1M,
+                _ => bail!("Unknown save type: {value}"),
+            })
+        }
+        "flash_id" => info.flash_id = Some(u16::try_from(parse_hex(value)?)?),
+        "gpio" => {
+            info.gpio = Some(
+                list()
+                    .map(|d| {
+                        Ok(match d.to_ascii_lowercase().as_str() {
+                            "rtc" => GpioDevice::Rtc,
+                            "solar" => GpioDevice::SolarSensor,
+                            "tilt" => GpioDevice::TiltSensor,
+                            "gyro" => GpioDevice::Gyro,
+                            "rumble" => GpioDevice::Rumble,
+                            _ => bail!("Unknown GPIO device: {d}"),
+                        })
+                    })
+                    .collect::<Result<_>>()?,
+            )
+        }
+        "idle_loop" => info.idle_loop = Some(parse_hex(value)?),
+        "quirks" => info.quirks = Some(list().map(str::to_string).collect()),
+        _ => bail!("Unknown key: {name}"),
+    }
+    Ok(())
+}
+
+fn parse_hex(s: &str) -> Result<u32> {
+    let digits = s
+        .strip_prefix("0x")
+        .or_else(|| s.strip_prefix("0X"))
+        .unwrap_or(s);
+    Ok(u32::from_str_radix(digits, 16)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_fields() {
+        let db = GameDb::parse(
+            "# comment\n\
+             ; comment\n\
+             [ABCE]\n\
+             title = Some Game\n\
+             save = Flash1M\n\
+             flash_id = 0x09C2\n\
+             gpio = rtc, solar\n\
+             idle_loop = 0x080004E8\n\
+             quirks = rom_mirroring, other\n\
+             \n\
+             [ABCE.1]\n\
+             save = sram\n",
+        )
+        .unwrap();
+
+        let info = db.lookup(b"ABCE", 0).unwrap();
+        assert_eq!(info.title.as_deref(), Some("Some Game"));
+        assert_eq!(info.backup, Some(BackupType::Flash1M));
+        assert_eq!(info.flash_id, Some(0x09C2));
+        assert_eq!(
+            info.gpio,
+            Some(vec![GpioDevice::Rtc, GpioDevice::SolarSensor])
+        );
+        assert_eq!(info.idle_loop, Some(0x080004E8));
+        assert!(info.has_quirk(QUIRK_ROM_MIRRORING));
+        assert!(info.has_quirk("other"));
+        assert_eq!(
+            info.backup_config().flash_chip,
+            Some(FlashChip::Macronix128K)
+        );
+
+        assert!(db.lookup(b"ABCJ", 0).is_none());
+    }
+
+    #[test]
+    fn parse_errors() {
+        for s in [
+            "title = Outside",
+            "[ABC]",
+            "[ABCE.x]",
+            "[ABCE]\ntitle",
+            "[ABCE]\nsave = flash2m",
+            "[ABCE]\nflash_id = 0x10000",
+            "[ABCE]\ngpio = rtc, laser",
+            "[ABCE]\nidle_loop = main",
+            "[ABCE]\nspeed = 2",
+        ] {
+            assert!(GameDb::parse(s).is_err(), "{s:?}");
+        }
+    }
+
+    #[test]
+    fn revision_over_any() {
+        let db = GameDb::parse(
+            "[ABCE]\n\
+             title = Any\n\
+             idle_loop = 0x08000100\n\
+             [ABCE.1]\n\
+             idle_loop = 0x08000200\n\
+             [ABCP.1]\n\
+             title = Revision only\n",
+        )
+        .unwrap();
+
+        let rev0 = db.lookup(b"ABCE", 0).unwrap();
+        assert_eq!(rev0.idle_loop, Some(0x08000100));
+
+        let rev1 = db.lookup(b"ABCE", 1).unwrap();
+        assert_eq!(rev1.title.as_deref(), Some("Any"));
+        assert_eq!(rev1.idle_loop, Some(0x08000200));
+
+        assert!(db.lookup(b"ABCP", 0).is_none());
+        let info = db.lookup(b"ABCP", 1).unwrap();
+        assert_eq!(info.title.as_deref(), Some("Revision only"));
+    }
+
+    #[test]
+    fn overrides_field_by_field() {
+        let base = GameDb::parse(
+            "[ABCE]\n\
+             title = Base\n\
+             save = eeprom\n\
+             gpio = rtc\n",
+        )
+        .unwrap();
+        let overrides = GameDb::parse(
+            "[ABCE]\n\
+             save = eeprom8k\n\
+             [XYZE]\n\
+             save = sram\n",
+        )
+        .unwrap();
+        let db = base.with_overrides(&overrides);
+
+        let info = db.lookup(b"ABCE", 0).unwrap();
+        assert_eq!(info.title.as_deref(), Some("Base"));
+        assert_eq!(info.backup, Some(BackupType::Eeprom8K));
+        assert_eq!(info.gpio, Some(vec![GpioDevice::Rtc]));
+
+        let info = db.lookup(b"XYZE", 0).unwrap();
+        assert_eq!(info.backup, Some(BackupType::Sram));
+
+        // The base database is left untouched
+        let info = base.lookup(b"ABCE", 0).unwrap();
+        assert_eq!(info.backup, Some(BackupType::Eeprom));
+    }
+
+    #[test]
+    fn bundled() {
+        let info = GameDb::bundled().lookup(b"BPEE", 0).unwrap();
+        assert_eq!(info.backup, Some(BackupType::Flash1M));
+        assert_eq!(
+            info.backup_config().flash_chip,
+            Some(FlashChip::Macronix128K)
+        );
+
+        let info = GameDb::bundled().lookup(b"AXVE", 0).unwrap();
+        assert_eq!(info.backup_config().flash_chip, Some(FlashChip::Sanyo));
+    }
+}
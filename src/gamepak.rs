@@ -1,18 +1,34 @@
-use crate::{backup::Backup, rom::Rom, util::read16};
+use crate::{
+    backup::Backup,
+    context::Timing,
+    rom::Rom,
+    util::{read16, trait_alias},
+};
 use log::warn;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+trait_alias!(pub trait Context = Timing);
 
 #[derive(Serialize, Deserialize)]
 pub struct GamePak {
     #[serde(skip)]
     rom: Rom,
     backup: Backup,
+    #[serde(skip)]
+    rom_patches: HashMap<u32, u16>,
+    #[serde(skip)]
+    rom_mirroring: bool,
 }
 
 impl GamePak {
-    pub fn new(rom: Rom, backup: Option<Vec<u8>>) -> Self {
-        let backup = Backup::detect_backup(&rom.data, backup);
-        Self { rom, backup }
+    pub fn new(rom: Rom, backup: Backup) -> Self {
+        Self {
+            rom,
+            backup,
+            rom_patches: HashMap::new(),
+            rom_mirroring: false,
+        }
     }
 
     pub fn rom(&self) -> &Rom {
@@ -31,14 +47,42 @@ impl GamePak {
         &mut self.backup
     }
 
+    /// Replaces the halfwords returned for the given ROM offsets, leaving the
+    /// ROM image itself untouched.
+    pub fn set_rom_patches(&mut self, patches: HashMap<u32, u16>) {
+        self.rom_patches = patches;
+    }
+
+    /// Repeat the ROM over the whole cartridge space instead of reading
+    /// past its end as open bus, as the Classic NES Series expects
+    pub fn set_rom_mirroring(&mut self, mirroring: bool) {
+        self.rom_mirroring = mirroring;
+    }
+
     pub fn is_valid_eeprom_addr(&self, addr: u32) -> bool {
         let large_rom = self.rom.data.len() > 0x01000000;
         (!large_rom && addr & 0x01000000 != 0) || (large_rom && addr & 0x01FFFF00 == 0x01FFFF00)
     }
 
-    pub fn read(&mut self, addr: u32) -> Option<u16> {
+    pub fn read(&mut self, ctx: &impl Context, addr: u32) -> Option<u16> {
         if self.is_valid_eeprom_addr(addr) {
-            return Some(self.backup.read_eeprom() as u16);
+            return Some(self.backup.read_eeprom(ctx.now()) as u16);
+        }
+
+        if !self.rom_patches.is_empty() {
+            if let Some(data) = self.rom_patches.get(&(addr & 0x01FFFFFE)) {
+                return Some(*data);
+            }
+        }
+
+        // Without a cartridge the bus returns the lower address bits
+        if self.rom.data.is_empty() {
+            return Some((addr >> 1) as u16);
+        }
+
+        if self.rom_mirroring {
+            let offset = (addr as usize & 0x01FFFFFE) % self.rom.data.len();
+            return Some(read16(&self.rom.data, offset));
         }
 
         if (addr as usize & 0x01FFFFFE) >= self.rom.data.len() {
@@ -49,9 +93,9 @@ impl GamePak {
         Some(read16(&self.rom.data, addr as usize))
     }
 
-    pub fn write(&mut self, addr: u32, data: u16) {
+    pub fn write(&mut self, ctx: &impl Context, addr: u32, data: u16) {
         if self.is_valid_eeprom_addr(addr) {
-            self.backup.write_eeprom(data & 1 != 0);
+            self.backup.write_eeprom(data & 1 != 0, ctx.now());
         } else {
             warn!("Write to invalid Game Pak ROM address: 0x{addr:08X} = 0x{data:04X}");
         }
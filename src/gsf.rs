@@ -0,0 +1,513 @@
+//! Playback of GSF and miniGSF music rips.
+//!
+//! A GSF file is a PSF container holding a zlib-compressed program section
+//! (entry point, load address, size and data) and optional tags. A miniGSF
+//! holds only the song selection and takes the rest of the image from the
+//! files named by its `_lib` tags.
+
+use std::{collections::BTreeMap, io::Read, path::Path, time::Duration};
+
+use anyhow::{anyhow, bail, Context as _, Result};
+
+use crate::{interface::AudioSample, multiboot::MULTIBOOT_MAX_SIZE, util::crc32, Agb};
+
+const PSF_MAGIC: &[u8] = b"PSF";
+const GSF_VERSION: u8 = 0x22;
+const HEADER_SIZE: usize = 0x10;
+const TAG_MAGIC: &[u8] = b"[TAG]";
+
+// Libraries can include further libraries, limit how deep to catch cycles
+const MAX_LIB_DEPTH: usize = 10;
+
+pub const ROM_BASE: u32 = 0x0800_0000;
+const EWRAM_BASE: u32 = 0x0200_0000;
+const MAX_ROM_SIZE: usize = 32 * 1024 * 1024;
+
+/// Used when a file has no `length` tag, as most players do
+pub const DEFAULT_GSF_LENGTH: Duration = Duration::from_secs(150);
+/// Used when a file has no `fade` tag
+pub const DEFAULT_GSF_FADE: Duration = Duration::from_secs(10);
+
+pub struct Gsf {
+    /// Address execution starts at, from the first file loaded
+    pub entry_point: u32,
+    /// Base address of `image`, the start of ROM or EWRAM
+    pub load_address: u32,
+    pub image: Vec<u8>,
+    /// Tags of the file itself, not of its libraries
+    pub tags: BTreeMap<String, String>,
+}
+
+struct Psf {
+    program: Vec<u8>,
+    tags: BTreeMap<String, String>,
+}
+
+fn parse_psf(data: &[u8]) -> Result<Psf> {
+    if data.len() < HEADER_SIZE || !data.starts_with(PSF_MAGIC) {
+        bail!("Not a PSF file");
+    }
+    if data[3] != GSF_VERSION {
+        bail!("Not a GSF file: PSF version 0x{:02X}", data[3]);
+    }
+    let word = |i: usize| u32::from_le_bytes(data[i..i + 4].try_into().unwrap()) as usize;
+    let reserved_size = word(0x04);
+    let program_size = word(0x08);
+    let program_crc = word(0x0C) as u32;
+
+    let program_start = HEADER_SIZE + reserved_size;
+    let program_end = program_start + program_size;
+    let compressed = data
+        .get(program_start..program_end)
+        .ok_or_else(|| anyhow!("PSF file is truncated"))?;
+    if crc32(compressed) != program_crc {
+        bail!("PSF program CRC32 mismatch");
+    }
+    // Entry point, offset and size words, then up to a whole ROM
+    let max_program_size = MAX_ROM_SIZE + 12;
+    let mut program = vec![];
+    flate2::read::ZlibDecoder::new(compressed)
+        .take(max_program_size as u64 + 1)
+        .read_to_end(&mut program)?;
+    if program.len() > max_program_size {
+        bail!("GSF program is larger than {max_program_size} bytes");
+    }
+
+    let tags = match data[program_end..].strip_prefix(TAG_MAGIC) {
+        Some(text) => parse_tags(&String::from_utf8_lossy(text)),
+        None => BTreeMap::new(),
+    };
+    Ok(Psf { program, tags })
+}
+
+// `key=value` lines; a key given several times continues the value on a
+// new line
+fn parse_tags(text: &str) -> BTreeMap<String, String> {
+    let mut ret = BTreeMap::<String, String>::new();
+    for line in text.lines() {
+        let Some((key, value)) = line.split_once('=') else {
+            continue;
+        };
+        let key = key.trim().to_ascii_lowercase();
+        let value = value.trim();
+        ret.entry(key)
+            .and_modify(|v| {
+                v.push('\n');
+                v.push_str(value);
+            })
+            .or_insert_with(|| value.to_string());
+    }
+    ret
+}
+
+/// Parses `[[h:]m:]s[.fff]`
+fn parse_time(s: &str) -> Option<Duration> {
+    let mut secs = 0.0;
+    for part in s.trim().replace(',', ".").split(':') {
+        let v: f64 = part.parse().ok()?;
+        if !(0.0..).contains(&v) {
+            return None;
+        }
+        secs = secs * 60.0 + v;
+    }
+    Some(Duration::from_secs_f64(secs))
+}
+
+impl Gsf {
+    /// Loads a file, with the libraries it references from the same
+    /// directory
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let dir = path.parent().unwrap_or(Path::new("."));
+        let data = std::fs::read(path).with_context(|| format!("{}", path.display()))?;
+        Self::from_bytes(&data, |name| {
+            let lib = dir.join(name);
+            std::fs::read(&lib).with_context(|| format!("{}", lib.display()))
+        })
+    }
+
+    /// Parses a file, calling `load_lib` for the contents of each library
+    pub fn from_bytes(
+        data: &[u8],
+        mut load_lib: impl FnMut(&str) -> Result<Vec<u8>>,
+    ) -> Result<Self> {
+        let mut ret = Gsf {
+            entry_point: 0,
+            load_address: 0,
+            image: vec![],
+            tags: BTreeMap::new(),
+        };
+        let psf = parse_psf(data)?;
+        ret.load_psf(&psf, &mut load_lib, 0)?;
+        ret.tags = psf.tags;
+        Ok(ret)
+    }
+
+    // The `_lib` library comes first and provides the entry point, then
+    // `_lib2`, `_lib3`, ..., then the file's own program
+    fn load_psf(
+        &mut self,
+        psf: &Psf,
+        load_lib: &mut impl FnMut(&str) -> Result<Vec<u8>>,
+        depth: usize,
+    ) -> Result<()> {
+        if depth > MAX_LIB_DEPTH {
+            bail!("GSF libraries nested too deep");
+        }
+
+        for n in 1.. {
+            let key = if n == 1 {
+                "_lib".to_string()
+            } else {
+                format!("_lib{n}")
+            };
+            let Some(name) = psf.tags.get(&key) else {
+                // `_lib2` and later may be present without `_lib`
+                if n == 1 {
+                    continue;
+                }
+                break;
+            };
+            let lib = parse_psf(&load_lib(name)?).with_context(|| name.clone())?;
+            self.load_psf(&lib, load_lib, depth + 1)?;
+        }
+
+        self.load_program(&psf.program)
+    }
+
+    fn load_program(&mut self, program: &[u8]) -> Result<()> {
+        if program.len() < 12 {
+            bail!("GSF program section is truncated");
+        }
+        let word = |i: usize| u32::from_le_bytes(program[i..i + 4].try_into().unwrap());
+        let entry_point = word(0);
+        let offset = word(4);
+        let data = &program[12..];
+
+        let (base, max_size) = match offset >> 24 {
+            0x02 => (EWRAM_BASE, MULTIBOOT_MAX_SIZE),
+            0x08..=0x0D => (ROM_BASE, MAX_ROM_SIZE),
+            _ => bail!("Unsupported GSF load address: {offset:08X}"),
+        };
+        if self.load_address == 0 {
+            self.entry_point = entry_point;
+            self.load_address = base;
+        } else if base != self.load_address {
+            bail!("GSF files load to both ROM and EWRAM");
+        }
+
+        let start = (offset & (max_size as u32 - 1)) as usize;
+        let end = start + data.len();
+        if end > max_size {
+            bail!(
+                "GSF program does not fit at {offset:08X}: {} bytes",
+                data.len()
+            );
+        }
+        if self.image.len() < end {
+            self.image.resize(end, 0);
+        }
+        self.image[start..end].copy_from_slice(data);
+        Ok(())
+    }
+
+    pub fn tag(&self, key: &str) -> Option<&str> {
+        self.tags.get(key).map(|s| s.as_str())
+    }
+
+    pub fn title(&self) -> Option<&str> {
+        self.tag("title")
+    }
+
+    /// Play time before fading out, from the `length` tag
+    pub fn length(&self) -> Option<Duration> {
+        parse_time(self.tag("length")?)
+    }
+
+    /// From the `fade` tag
+    pub fn fade(&self) -> Option<Duration> {
+        parse_time(self.tag("fade")?)
+    }
+}
+
+/// Runs a GSF without rendering and returns its audio, faded out at the
+/// end of its length
+pub struct GsfPlayer {
+    agb: Agb,
+    length: Duration,
+    fade: Duration,
+    // Output samples so far
+    played: u64,
+    buf: Vec<AudioSample>,
+}
+
+impl GsfPlayer {
+    pub fn new(bios: Vec<u8>, gsf: &Gsf) -> Result<Self> {
+        Ok(Self {
+            agb: Agb::gsf(bios, gsf)?,
+            length: gsf.length().unwrap_or(DEFAULT_GSF_LENGTH),
+            fade: gsf.fade().unwrap_or(DEFAULT_GSF_FADE),
+            played: 0,
+            buf: vec![],
+        })
+    }
+
+    /// The emulator, e.g. to change the sample rate or enable MP2K HLE
+    pub fn agb_mut(&mut self) -> &mut Agb {
+        &mut self.agb
+    }
+
+    /// Length of the whole song, including the fade
+    pub fn duration(&self) -> Duration {
+        self.length + self.fade
+    }
+
+    pub fn position(&self) -> Duration {
+        Duration::from_secs_f64(self.played as f64 / self.agb.audio_sample_rate() as f64)
+    }
+
+    pub fn finished(&self) -> bool {
+        self.position() >= self.duration()
+    }
+
+    /// Runs one frame and returns its samples, or `None` at the end
+    pub fn next_frame(&mut self) -> Option<&[AudioSample]> {
+        if self.finished() {
+            return None;
+        }
+        self.agb.exec_frame(false);
+
+        let rate = self.agb.audio_sample_rate() as f64;
+        let fade_start = self.length.as_secs_f64() * rate;
+        let fade_len = self.fade.as_secs_f64() * rate;
+        let end = (fade_start + fade_len) as u64;
+
+        self.buf.clear();
+        for s in &self.agb.audio_buf().buf {
+            if self.played >= end {
+                break;
+            }
+            let t = self.played as f64 - fade_start;
+            let gain = if t <= 0.0 { 1.0 } else { 1.0 - t / fade_len };
+            let fade = |v: i16| (v as f64 * gain).round() as i16;
+            self.buf.push(AudioSample::new(fade(s.right), fade(s.left)));
+            self.played += 1;
+        }
+        Some(&self.buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{cell::RefCell, collections::HashMap, io::Write};
+
+    fn program(entry_point: u32, offset: u32, data: &[u8]) -> Vec<u8> {
+        let mut ret = vec![];
+        ret.extend_from_slice(&entry_point.to_le_bytes());
+        ret.extend_from_slice(&offset.to_le_bytes());
+        ret.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        ret.extend_from_slice(data);
+        ret
+    }
+
+    fn psf(program: &[u8], tags: &str) -> Vec<u8> {
+        let mut enc = flate2::write::ZlibEncoder::new(vec![], flate2::Compression::fast());
+        enc.write_all(program).unwrap();
+        let compressed = enc.finish().unwrap();
+
+        let mut ret = PSF_MAGIC.to_vec();
+        ret.push(GSF_VERSION);
+        ret.extend_from_slice(&0_u32.to_le_bytes());
+        ret.extend_from_slice(&(compressed.len() as u32).to_le_bytes());
+        ret.extend_from_slice(&crc32(&compressed).to_le_bytes());
+        ret.extend_from_slice(&compressed);
+        if !tags.is_empty() {
+            ret.extend_from_slice(TAG_MAGIC);
+            ret.extend_from_slice(tags.as_bytes());
+        }
+        ret
+    }
+
+    fn no_lib(name: &str) -> Result<Vec<u8>> {
+        bail!("Unexpected library: {name}")
+    }
+
+    #[test]
+    fn single_file() {
+        let data = psf(&program(ROM_BASE, ROM_BASE + 4, b"song"), "");
+        let gsf = Gsf::from_bytes(&data, no_lib).unwrap();
+        assert_eq!(gsf.entry_point, ROM_BASE);
+        assert_eq!(gsf.load_address, ROM_BASE);
+        assert_eq!(gsf.image, b"\0\0\0\0song");
+        assert!(gsf.tags.is_empty());
+        assert_eq!(gsf.length(), None);
+    }
+
+    #[test]
+    fn tags() {
+        let tags = "title=Overworld\n\
+                    Artist = Someone \n\
+                    comment=first\n\
+                    comment=second\n\
+                    not a tag\n\
+                    length=1:02.5\n\
+                    fade=10\n";
+        let data = psf(&program(ROM_BASE, ROM_BASE, b""), tags);
+        let gsf = Gsf::from_bytes(&data, no_lib).unwrap();
+        assert_eq!(gsf.title(), Some("Overworld"));
+        assert_eq!(gsf.tag("artist"), Some("Someone"));
+        assert_eq!(gsf.tag("comment"), Some("first\nsecond"));
+        assert_eq!(gsf.tags.len(), 5);
+        assert_eq!(gsf.length(), Some(Duration::from_millis(62_500)));
+        assert_eq!(gsf.fade(), Some(Duration::from_secs(10)));
+    }
+
+    #[test]
+    fn library_order() {
+        let libs = HashMap::from([
+            (
+                "base.gsflib",
+                psf(
+                    &program(ROM_BASE + 0xC0, ROM_BASE, b"AAAAAAAA"),
+                    "_lib=nested.gsflib",
+                ),
+            ),
+            (
+                "nested.gsflib",
+                psf(&program(ROM_BASE + 0xE0, ROM_BASE, b"NNNNNNNNNN"), ""),
+            ),
+            ("second.gsflib", psf(&program(0, ROM_BASE + 2, b"BB"), "")),
+            ("third.gsflib", psf(&program(0, ROM_BASE + 3, b"CC"), "")),
+        ]);
+        let loaded = RefCell::new(vec![]);
+        let load_lib = |name: &str| {
+            loaded.borrow_mut().push(name.to_string());
+            Ok(libs[name].clone())
+        };
+
+        let data = psf(
+            &program(0, ROM_BASE + 5, b"S"),
+            "_lib3=third.gsflib\n_lib=base.gsflib\n_lib2=second.gsflib\ntitle=Song",
+        );
+        let gsf = Gsf::from_bytes(&data, load_lib).unwrap();
+        assert_eq!(
+            *loaded.borrow(),
+            [
+                "base.gsflib",
+                "nested.gsflib",
+                "second.gsflib",
+                "third.gsflib"
+            ]
+        );
+        // Each file overwrites the ones loaded before it
+        assert_eq!(gsf.image, b"AABCCSAANN");
+        // The first file loaded is the deepest `_lib`
+        assert_eq!(gsf.entry_point, ROM_BASE + 0xE0);
+        // Tags of the libraries are not inherited
+        assert_eq!(gsf.tags.len(), 4);
+        assert_eq!(gsf.title(), Some("Song"));
+    }
+
+    #[test]
+    fn lib2_without_lib() {
+        let lib = psf(&program(ROM_BASE, ROM_BASE, b"LL"), "");
+        let data = psf(&program(0, ROM_BASE + 1, b"S"), "_lib2=lib2.gsflib");
+        let gsf = Gsf::from_bytes(&data, |_| Ok(lib.clone())).unwrap();
+        assert_eq!(gsf.image, b"LS");
+    }
+
+    #[test]
+    fn nesting_limit() {
+        // lib0 includes lib1, ..., the last one includes nothing
+        let chain = |n: usize| {
+            move |name: &str| {
+                let i: usize = name[3..].parse().unwrap();
+                let tags = if i + 1 < n {
+                    format!("_lib=lib{}", i + 1)
+                } else {
+                    String::new()
+                };
+                Ok(psf(&program(ROM_BASE, ROM_BASE, b"x"), &tags))
+            }
+        };
+        let data = psf(&program(0, ROM_BASE, b"S"), "_lib=lib0");
+        assert!(Gsf::from_bytes(&data, chain(MAX_LIB_DEPTH)).is_ok());
+        let err = Gsf::from_bytes(&data, chain(MAX_LIB_DEPTH + 1))
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("nested too deep"));
+
+        // A library including itself
+        let lib = psf(&program(ROM_BASE, ROM_BASE, b"x"), "_lib=self.gsflib");
+        assert!(Gsf::from_bytes(&lib, |_| Ok(lib.clone())).is_err());
+    }
+
+    #[test]
+    fn missing_library() {
+        let data = psf(&program(0, ROM_BASE, b"S"), "_lib=missing.gsflib");
+        assert!(Gsf::from_bytes(&data, no_lib).is_err());
+    }
+
+    #[test]
+    fn invalid_files() {
+        let data = psf(&program(ROM_BASE, ROM_BASE, b"song"), "");
+
+        let mut bad = data.clone();
+        bad[3] = 0x01;
+        assert!(Gsf::from_bytes(&bad, no_lib).is_err());
+
+        let mut bad = data.clone();
+        bad[HEADER_SIZE] ^= 0xFF;
+        let err = Gsf::from_bytes(&bad, no_lib).err().unwrap();
+        assert!(err.to_string().contains("CRC32"));
+
+        assert!(Gsf::from_bytes(&data[..data.len() - 1], no_lib).is_err());
+        assert!(Gsf::from_bytes(b"PSF", no_lib).is_err());
+
+        let data = psf(&program(0, 0x0300_0000, b"song"), "");
+        assert!(Gsf::from_bytes(&data, no_lib).is_err());
+    }
+
+    #[test]
+    fn oversized_program() {
+        let data = vec![0; MAX_ROM_SIZE + 1];
+        let data = psf(&program(ROM_BASE, ROM_BASE, &data), "");
+        let Err(err) = Gsf::from_bytes(&data, no_lib) else {
+            panic!("oversized program accepted");
+        };
+        assert!(err.to_string().contains("larger than"), "{err}");
+
+        // A whole ROM still fits
+        let data = vec![0; MAX_ROM_SIZE];
+        let data = psf(&program(ROM_BASE, ROM_BASE, &data), "");
+        assert!(Gsf::from_bytes(&data, no_lib).is_ok());
+    }
+
+    #[test]
+    fn ewram_and_rom_mixed() {
+        let lib = psf(&program(EWRAM_BASE, EWRAM_BASE, b"LL"), "");
+        let data = psf(&program(0, ROM_BASE, b"S"), "_lib=lib.gsflib");
+        assert!(Gsf::from_bytes(&data, |_| Ok(lib.clone())).is_err());
+
+        let data = psf(&program(0, EWRAM_BASE + 1, b"S"), "_lib=lib.gsflib");
+        let gsf = Gsf::from_bytes(&data, |_| Ok(lib.clone())).unwrap();
+        assert_eq!(gsf.load_address, EWRAM_BASE);
+        assert_eq!(gsf.image, b"LS");
+    }
+
+    #[test]
+    fn times() {
+        let secs = |s: f64| Some(Duration::from_secs_f64(s));
+        assert_eq!(parse_time("45"), secs(45.0));
+        assert_eq!(parse_time("2:30"), secs(150.0));
+        assert_eq!(parse_time("1:02:03.5"), secs(3723.5));
+        assert_eq!(parse_time(" 1,25 "), secs(1.25));
+        assert_eq!(parse_time("0:00"), secs(0.0));
+        assert_eq!(parse_time("-1"), None);
+        assert_eq!(parse_time("1:-5"), None);
+        assert_eq!(parse_time("abc"), None);
+        assert_eq!(parse_time(""), None);
+        assert_eq!(parse_time("1::2"), None);
+    }
+}
@@ -0,0 +1,165 @@
+use std::{collections::VecDeque, fmt};
+
+use crate::cpu::mode_name;
+
+const MAX_CALL_DEPTH: usize = 1024;
+
+#[derive(Clone, Debug)]
+pub struct HistoryEntry {
+    pub pc: u32,
+    pub opcode: u32,
+    pub thumb: bool,
+    pub cycle: u64,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum CallKind {
+    /// BL
+    Call,
+    /// Exception entry (IRQ, SWI, ...)
+    Exception(&'static str),
+}
+
+#[derive(Clone, Debug)]
+pub struct CallFrame {
+    pub kind: CallKind,
+    /// Address of the calling instruction (or the interrupted one for exceptions)
+    pub call_site: u32,
+    pub target: u32,
+    /// Address execution resumes at when this frame returns
+    pub return_addr: u32,
+    pub sp: u32,
+}
+
+/// Last executed instructions and a shadow call stack, built from
+/// BL / BX LR / POP {PC} and exception entry / return.
+pub struct History {
+    capacity: usize,
+    instrs: VecDeque<HistoryEntry>,
+    call_stack: VecDeque<CallFrame>,
+}
+
+impl History {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            instrs: VecDeque::with_capacity(capacity),
+            call_stack: VecDeque::new(),
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Executed instructions, oldest first
+    pub fn instrs(&self) -> impl Iterator<Item = &HistoryEntry> {
+        self.instrs.iter()
+    }
+
+    /// Active frames, innermost first
+    pub fn call_stack(&self) -> impl Iterator<Item = &CallFrame> {
+        self.call_stack.iter().rev()
+    }
+
+    pub fn push_instr(&mut self, entry: HistoryEntry) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.instrs.len() == self.capacity {
+            self.instrs.pop_front();
+        }
+        self.instrs.push_back(entry);
+    }
+
+    pub fn push_call(&mut self, frame: CallFrame) {
+        if self.call_stack.len() == MAX_CALL_DEPTH {
+            self.call_stack.pop_front();
+        }
+        self.call_stack.push_back(frame);
+    }
+
+    /// Pops frames up to the one returning to `target`. Jumps that do not
+    /// match any frame (longjmp, jump tables, ...) leave the stack untouched.
+    pub fn ret(&mut self, target: u32) {
+        let target = target & !1;
+        if let Some(ix) = self
+            .call_stack
+            .iter()
+            .rposition(|f| f.return_addr & !1 == target)
+        {
+            self.call_stack.truncate(ix);
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct FaultReportEntry {
+    pub entry: HistoryEntry,
+    pub disasm: String,
+}
+
+/// Snapshot of the CPU state with the instruction history and shadow call
+/// stack, for diagnosing how the guest got into a bad state.
+#[derive(Clone, Debug)]
+pub struct FaultReport {
+    pub regs: [u32; 16],
+    pub cpsr: u32,
+    pub mode: u8,
+    pub thumb: bool,
+    pub cycle: u64,
+    /// Innermost first
+    pub call_stack: Vec<CallFrame>,
+    /// Oldest first
+    pub history: Vec<FaultReportEntry>,
+}
+
+impl fmt::Display for FaultReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "CPU state at cycle {}: {} {}, CPSR: {:08X}",
+            self.cycle,
+            mode_name(self.mode),
+            if self.thumb { "THUMB" } else { "ARM" },
+            self.cpsr
+        )?;
+        for (i, r) in self.regs.iter().enumerate() {
+            write!(f, "{:>4}: {r:08X}", format!("r{i}"))?;
+            if i % 4 == 3 {
+                writeln!(f)?;
+            }
+        }
+
+        writeln!(f, "Call stack (innermost first):")?;
+        if self.call_stack.is_empty() {
+            writeln!(f, "  <empty or history disabled>")?;
+        }
+        for (i, frame) in self.call_stack.iter().enumerate() {
+            let kind = match frame.kind {
+                CallKind::Call => "BL",
+                CallKind::Exception(name) => name,
+            };
+            writeln!(
+                f,
+                "  #{i:<3} 0x{:08X} from 0x{:08X} ({kind}), returns to 0x{:08X}, sp: 0x{:08X}",
+                frame.target, frame.call_site, frame.return_addr, frame.sp
+            )?;
+        }
+
+        writeln!(f, "Last {} instructions:", self.history.len())?;
+        for e in &self.history {
+            let opcode = if e.entry.thumb {
+                format!("    {:04X}", e.entry.opcode)
+            } else {
+                format!("{:08X}", e.entry.opcode)
+            };
+            writeln!(
+                f,
+                "  {:10} {:08X}: {opcode}: {}",
+                e.entry.cycle, e.entry.pc, e.disasm
+            )?;
+        }
+        Ok(())
+    }
+}
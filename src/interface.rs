@@ -1,19 +1,40 @@
-use std::fmt;
+use std::{
+    fmt,
+    ops::{Deref, DerefMut},
+    sync::{Arc, OnceLock},
+};
 
 #[derive(Default)]
 pub struct FrameBuf {
     width: u32,
     height: u32,
     buf: Vec<Pixel>,
+    // Colours as output by the LCD controller
+    raw: Vec<u16>,
+    format: PixelFormat,
+    data: Vec<u8>,
+    correction: ColorCorrection,
+    // Corrected colour of each BGR555 value
+    lut: Arc<[Pixel]>,
 }
 
 impl FrameBuf {
     pub fn new(width: u32, height: u32) -> Self {
-        FrameBuf {
+        let len = (width * height) as usize;
+        let format = PixelFormat::default();
+        let correction = ColorCorrection::default();
+        let mut ret = FrameBuf {
             width,
             height,
-            buf: vec![Pixel::new(0, 0, 0); (width * height) as usize],
-        }
+            buf: vec![Pixel::new(0, 0, 0); len],
+            raw: vec![0; len],
+            format,
+            data: vec![],
+            correction,
+            lut: correction.lut(),
+        };
+        ret.set_pixel_format(format);
+        ret
     }
 
     pub fn width(&self) -> u32 {
@@ -28,8 +49,234 @@ impl FrameBuf {
         &self.buf[(y * self.width + x) as usize]
     }
 
-    pub fn pixel_mut(&mut self, x: u32, y: u32) -> &mut Pixel {
-        &mut self.buf[(y * self.width + x) as usize]
+    /// The BGR555 colour and output data are updated when the returned
+    /// guard is dropped
+    #[deprecated(note = "use `set_bgr555`, which applies the colour correction")]
+    pub fn pixel_mut(&mut self, x: u32, y: u32) -> PixelMut<'_> {
+        let index = (y * self.width + x) as usize;
+        PixelMut { buf: self, index }
+    }
+
+    /// Sets a pixel from a BGR555 colour, applying the colour correction.
+    /// Bit 15 marks the pixel transparent.
+    pub fn set_bgr555(&mut self, x: u32, y: u32, col: u16) {
+        let i = (y * self.width + x) as usize;
+        self.raw[i] = col;
+        self.buf[i] = self.lut[col as usize & 0x7FFF].clone();
+        self.encode(i);
+    }
+
+    // Updates pixel `i` of `data`
+    fn encode(&mut self, i: usize) {
+        let bpp = self.format.bytes_per_pixel();
+        let out = &mut self.data[i * bpp..(i + 1) * bpp];
+        self.format.encode(&self.buf[i], self.raw[i], out);
+    }
+
+    /// BGR555 colours before colour correction, row major
+    pub fn bgr555(&self) -> &[u16] {
+        &self.raw
+    }
+
+    /// Pixels in `pixel_format`, row major with no padding between rows
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Bytes per row of `data`
+    pub fn pitch(&self) -> usize {
+        self.width as usize * self.format.bytes_per_pixel()
+    }
+
+    pub fn pixel_format(&self) -> PixelFormat {
+        self.format
+    }
+
+    pub fn set_pixel_format(&mut self, format: PixelFormat) {
+        self.format = format;
+        self.data = vec![0; self.buf.len() * format.bytes_per_pixel()];
+        for i in 0..self.buf.len() {
+            self.encode(i);
+        }
+    }
+
+    pub fn color_correction(&self) -> ColorCorrection {
+        self.correction
+    }
+
+    /// Also converts the current frame
+    pub fn set_color_correction(&mut self, correction: ColorCorrection) {
+        self.correction = correction;
+        self.lut = correction.lut();
+        for i in 0..self.buf.len() {
+            self.buf[i] = self.lut[self.raw[i] as usize & 0x7FFF].clone();
+            self.encode(i);
+        }
+    }
+
+    /// Takes over the output settings, which are not part of save states
+    pub fn copy_output_settings(&mut self, other: &FrameBuf) {
+        if self.correction != other.correction {
+            self.set_color_correction(other.correction);
+        }
+        if self.format != other.format {
+            self.set_pixel_format(other.format);
+        }
+    }
+
+    /// Replaces the frame with BGR555 colours as returned by `bgr555`,
+    /// applying the current colour correction. Returns false if the size
+    /// does not match.
+    pub fn copy_from_bgr555(&mut self, raw: &[u16]) -> bool {
+        if raw.len() != self.raw.len() {
+            return false;
+        }
+        self.raw.copy_from_slice(raw);
+        for i in 0..self.buf.len() {
+            self.buf[i] = self.lut[self.raw[i] as usize & 0x7FFF].clone();
+            self.encode(i);
+        }
+        true
+    }
+}
+
+/// Mutable access to a pixel of a `FrameBuf`, see `FrameBuf::pixel_mut`
+pub struct PixelMut<'a> {
+    buf: &'a mut FrameBuf,
+    index: usize,
+}
+
+impl Deref for PixelMut<'_> {
+    type Target = Pixel;
+
+    fn deref(&self) -> &Pixel {
+        &self.buf.buf[self.index]
+    }
+}
+
+impl DerefMut for PixelMut<'_> {
+    fn deref_mut(&mut self) -> &mut Pixel {
+        &mut self.buf.buf[self.index]
+    }
+}
+
+impl Drop for PixelMut<'_> {
+    fn drop(&mut self) {
+        let i = self.index;
+        self.buf.raw[i] = self.buf.buf[i].to_bgr555();
+        self.buf.encode(i);
+    }
+}
+
+/// Layout of `FrameBuf::data`. Alpha is 0xFF except for the transparent
+/// pixels of layer buffers.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum PixelFormat {
+    /// Bytes R, G, B, A
+    #[default]
+    Rgba8888,
+    /// Native endian u32 0xAARRGGBB
+    Xrgb8888,
+    /// Native endian u16, red in the top bits
+    Rgb565,
+    /// Native endian u16 as output by the LCD controller, red in the bottom
+    /// bits. Not colour corrected.
+    Bgr555,
+}
+
+impl PixelFormat {
+    pub fn bytes_per_pixel(self) -> usize {
+        match self {
+            PixelFormat::Rgba8888 | PixelFormat::Xrgb8888 => 4,
+            PixelFormat::Rgb565 | PixelFormat::Bgr555 => 2,
+        }
+    }
+
+    fn encode(self, p: &Pixel, raw: u16, out: &mut [u8]) {
+        let (r, g, b) = (p.r as u32, p.g as u32, p.b as u32);
+        let alpha = if raw & 0x8000 != 0 { 0 } else { 0xFF };
+        match self {
+            PixelFormat::Rgba8888 => out.copy_from_slice(&[p.r, p.g, p.b, alpha as u8]),
+            PixelFormat::Xrgb8888 => {
+                out.copy_from_slice(&(alpha << 24 | r << 16 | g << 8 | b).to_ne_bytes())
+            }
+            PixelFormat::Rgb565 => {
+                let v = ((r >> 3) << 11 | (g >> 2) << 5 | b >> 3) as u16;
+                out.copy_from_slice(&v.to_ne_bytes())
+            }
+            PixelFormat::Bgr555 => out.copy_from_slice(&raw.to_ne_bytes()),
+        }
+    }
+}
+
+/// Emulation of how each screen shows colours. The GBA LCD is dark and
+/// washed out, so games use brighter, more saturated colours than look
+/// right on a modern display.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum ColorCorrection {
+    /// Linear expansion to 8 bits
+    #[default]
+    None,
+    /// Original GBA (AGB-001)
+    Gba,
+}
+
+// Approximate response of a screen: colours are linearized with the
+// screen's gamma, mixed to model the bleeding between channels, then
+// encoded with the sRGB gamma.
+//
+// The GBA profile is the colour emulation of higan/ares by Talarubi and
+// byuu. Other screens should only be added with measured values.
+struct ScreenProfile {
+    gamma: f64,
+    // Rows give the output red, green and blue from the input red, green
+    // and blue, in 1/255
+    mix: [[f64; 3]; 3],
+    brightness: f64,
+}
+
+const OUTPUT_GAMMA: f64 = 2.2;
+
+const PROFILE_COUNT: usize = 2;
+
+// Tables are shared by all frame buffers and built on first use
+static LUTS: [OnceLock<Arc<[Pixel]>>; PROFILE_COUNT] = [const { OnceLock::new() }; PROFILE_COUNT];
+
+impl ColorCorrection {
+    fn profile(self) -> Option<ScreenProfile> {
+        Some(match self {
+            ColorCorrection::None => return None,
+            ColorCorrection::Gba => ScreenProfile {
+                gamma: 4.0,
+                mix: [[255.0, 50.0, 0.0], [10.0, 230.0, 30.0], [50.0, 10.0, 220.0]],
+                brightness: 255.0 / 280.0,
+            },
+        })
+    }
+
+    fn lut(self) -> Arc<[Pixel]> {
+        LUTS[self as usize]
+            .get_or_init(|| self.make_lut().into())
+            .clone()
+    }
+
+    fn make_lut(self) -> Vec<Pixel> {
+        let profile = self.profile();
+        (0..0x8000)
+            .map(|col| {
+                let rgb = [col & 0x1F, (col >> 5) & 0x1F, (col >> 10) & 0x1F];
+                let Some(profile) = &profile else {
+                    return Pixel::from_u16(col as u16);
+                };
+                let lin = rgb.map(|c| (c as f64 / 31.0).powf(profile.gamma));
+                let [r, g, b] = profile.mix.map(|row| {
+                    let v = row.iter().zip(&lin).map(|(m, c)| m * c).sum::<f64>() / 255.0;
+                    let v = v.powf(1.0 / OUTPUT_GAMMA) * profile.brightness;
+                    (v * 255.0).round().clamp(0.0, 255.0) as u8
+                });
+                Pixel::new(r, g, b)
+            })
+            .collect()
     }
 }
 
@@ -58,6 +305,10 @@ impl Pixel {
             b: extend_color((p >> 10) & 0x1F),
         }
     }
+
+    fn to_bgr555(&self) -> u16 {
+        (self.r >> 3) as u16 | ((self.g >> 3) as u16) << 5 | ((self.b >> 3) as u16) << 10
+    }
 }
 
 fn extend_color(col5: u16) -> u8 {
@@ -67,18 +318,29 @@ fn extend_color(col5: u16) -> u8 {
 #[derive(Default)]
 pub struct AudioBuf {
     pub buf: Vec<AudioSample>,
+    /// Output of each `AudioChannel` before mixing, one entry per sample in
+    /// `buf`. Empty unless enabled with `Agb::set_channel_buffers`.
+    pub channels: Vec<[i16; 6]>,
 }
 
 impl AudioBuf {
     pub fn new() -> Self {
-        Self { buf: vec![] }
+        Self {
+            buf: vec![],
+            channels: vec![],
+        }
     }
 
     pub fn len(&self) -> usize {
         self.buf.len()
     }
+
+    pub fn is_empty(&self) -> bool {
+        self.buf.is_empty()
+    }
 }
 
+#[derive(Clone, Copy, Default, Debug, PartialEq, Eq)]
 pub struct AudioSample {
     pub right: i16,
     pub left: i16,
@@ -103,3 +365,40 @@ pub struct KeyInput {
     pub r: bool,
     pub l: bool,
 }
+
+impl KeyInput {
+    /// Pressed keys in KEYINPUT bit order (bit 0: A .. bit 9: L), 1 = pressed
+    pub fn to_bits(&self) -> u16 {
+        [
+            self.a,
+            self.b,
+            self.select,
+            self.start,
+            self.right,
+            self.left,
+            self.up,
+            self.down,
+            self.r,
+            self.l,
+        ]
+        .iter()
+        .enumerate()
+        .fold(0, |acc, (i, &b)| acc | (b as u16) << i)
+    }
+
+    pub fn from_bits(bits: u16) -> Self {
+        let bit = |i: u32| bits & (1 << i) != 0;
+        Self {
+            a: bit(0),
+            b: bit(1),
+            select: bit(2),
+            start: bit(3),
+            right: bit(4),
+            left: bit(5),
+            up: bit(6),
+            down: bit(7),
+            r: bit(8),
+            l: bit(9),
+        }
+    }
+}
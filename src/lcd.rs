@@ -9,7 +9,7 @@ use crate::{
         CLOCK_PER_DOT, DOTS_PER_LINE, HBLANK_POS, LINES_PER_FRAME, SCREEN_HEIGHT, SCREEN_WIDTH,
     },
     context::{Interrupt, Timing},
-    interface::{FrameBuf, Pixel},
+    interface::FrameBuf,
     interrupt::InterruptKind,
     util::{pack, read16, trait_alias},
 };
@@ -61,6 +61,11 @@ pub struct Lcd {
 
     #[serde(skip)]
     render_graphics: bool,
+    #[serde(skip)]
+    layer_disabled: [bool; LAYER_COUNT],
+    // BG0-3 and OBJ, each on its own
+    #[serde(skip)]
+    layer_bufs: Option<Vec<FrameBuf>>,
 
     #[serde(skip)]
     line_buf: LineBuf,
@@ -68,6 +73,23 @@ pub struct Lcd {
     pub frame_buf: FrameBuf,
 }
 
+/// Parts of the picture that can be turned off for debugging
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Layer {
+    Bg0,
+    Bg1,
+    Bg2,
+    Bg3,
+    Obj,
+    /// Window 0, 1 and the OBJ window
+    Window,
+    /// Alpha blending and brightness changes
+    Effects,
+}
+
+const LAYER_COUNT: usize = 7;
+const LAYER_BGS: [Layer; 4] = [Layer::Bg0, Layer::Bg1, Layer::Bg2, Layer::Bg3];
+
 struct LineBuf {
     bg: [Vec<u16>; 4],
     obj: Vec<u16>,
@@ -260,6 +282,54 @@ impl Lcd {
         &self.frame_buf
     }
 
+    pub fn layer_enabled(&self, layer: Layer) -> bool {
+        !self.layer_disabled[layer as usize]
+    }
+
+    /// Hides a layer regardless of DISPCNT. A hidden BG or OBJ is still
+    /// drawn to its layer buffer.
+    pub fn set_layer_enabled(&mut self, layer: Layer, enabled: bool) {
+        self.layer_disabled[layer as usize] = !enabled;
+    }
+
+    /// Also draw BG0-3 and OBJ each to its own `FrameBuf`
+    pub fn set_layer_buffers(&mut self, enable: bool) {
+        self.layer_bufs = enable.then(|| {
+            (0..Layer::Window as usize)
+                .map(|_| {
+                    let mut buf = FrameBuf::new(SCREEN_WIDTH, SCREEN_HEIGHT);
+                    buf.copy_output_settings(&self.frame_buf);
+                    buf
+                })
+                .collect()
+        });
+    }
+
+    /// The layer as the LCD controller drew it, before windows and
+    /// effects, with transparent pixels where the layer has none.
+    /// `None` for `Window` and `Effects`, or without `set_layer_buffers`.
+    pub fn layer_buf(&self, layer: Layer) -> Option<&FrameBuf> {
+        self.layer_bufs.as_ref()?.get(layer as usize)
+    }
+
+    /// Takes over the debug settings and layer buffers of the `Lcd` this
+    /// one replaces, as they are not part of save states
+    pub fn take_debug_settings(&mut self, other: &mut Lcd) {
+        self.layer_disabled = other.layer_disabled;
+        self.layer_bufs = other.layer_bufs.take();
+    }
+
+    /// Cycles until the next H-Blank or line start, where interrupts and
+    /// DMA transfers are triggered
+    pub fn cycles_to_next_event(&self) -> u64 {
+        let dots = if self.x < HBLANK_POS {
+            HBLANK_POS - self.x
+        } else {
+            DOTS_PER_LINE - self.x
+        };
+        dots as u64 * CLOCK_PER_DOT - self.fraction
+    }
+
     pub fn tick(&mut self, ctx: &mut impl Context) {
         let now = ctx.now();
         let elapsed = now - self.prev_clock;
@@ -641,9 +711,23 @@ impl Lcd {
             return;
         }
 
+        // Follow changes to the colour correction and pixel format
+        if let Some(bufs) = &mut self.layer_bufs {
+            if self.y == 0 {
+                for buf in bufs {
+                    buf.copy_output_settings(&self.frame_buf);
+                }
+            }
+        }
+
         if self.force_blank {
             for x in 0..SCREEN_WIDTH {
-                *self.frame_buf.pixel_mut(x, self.y) = Pixel::new(255, 255, 255);
+                self.frame_buf.set_bgr555(x, self.y, 0x7FFF);
+            }
+            for buf in self.layer_bufs.iter_mut().flatten() {
+                for x in 0..SCREEN_WIDTH {
+                    buf.set_bgr555(x, self.y, 0x8000);
+                }
             }
             return;
         }
@@ -703,8 +787,17 @@ impl Lcd {
         self.color_special_effect();
 
         for x in 0..SCREEN_WIDTH {
-            *self.frame_buf.pixel_mut(x, self.y) =
-                Pixel::from_u16(self.line_buf.finished[x as usize]);
+            self.frame_buf
+                .set_bgr555(x, self.y, self.line_buf.finished[x as usize]);
+        }
+
+        if let Some(bufs) = &mut self.layer_bufs {
+            let lines = self.line_buf.bg.iter().chain([&self.line_buf.obj]);
+            for (buf, line) in bufs.iter_mut().zip(lines) {
+                for x in 0..SCREEN_WIDTH {
+                    buf.set_bgr555(x, self.y, line[x as usize]);
+                }
+            }
         }
     }
 
@@ -1278,15 +1371,18 @@ impl Lcd {
             trace!("  - Display Obj: {}", self.display_obj);
         }
 
-        let y_in_win0 = self.display_window[0]
+        let windows = self.layer_enabled(Layer::Window);
+        let display_window = self.display_window.map(|b| b && windows);
+        let display_obj_window = self.display_obj_window && windows;
+
+        let y_in_win0 = display_window[0]
             && self.window[0].u as u32 <= self.y
             && self.y < self.window[0].d as u32;
-        let y_in_win1 = self.display_window[1]
+        let y_in_win1 = display_window[1]
             && self.window[1].u as u32 <= self.y
             && self.y < self.window[1].d as u32;
 
-        let winout_enable =
-            self.display_window[0] || self.display_window[1] || self.display_obj_window;
+        let winout_enable = display_window[0] || display_window[1] || display_obj_window;
 
         let any = WindowCtrl {
             display_bg: [true, true, true, true],
@@ -1305,7 +1401,7 @@ impl Lcd {
                 &self.winin[0]
             } else if in_win1 {
                 &self.winin[1]
-            } else if self.display_obj_window && self.line_buf.obj_attr[x as usize].window() {
+            } else if display_obj_window && self.line_buf.obj_attr[x as usize].window() {
                 &self.objwin
             } else if winout_enable {
                 &self.winout
@@ -1324,7 +1420,7 @@ impl Lcd {
 
             self.put_surface_pixel(x, backdrop, SurfaceAttr::new(4, 5, effect));
 
-            if self.display_obj && win_ctrl.display_obj {
+            if self.display_obj && win_ctrl.display_obj && self.layer_enabled(Layer::Obj) {
                 let col = self.line_buf.obj[x];
                 if col & 0x8000 == 0 {
                     let effect = if !win_ctrl.color_special_effect {
@@ -1341,8 +1437,8 @@ impl Lcd {
                 }
             }
 
-            for i in 0..4 {
-                if !(self.display_bg[i] && win_ctrl.display_bg[i]) {
+            for (i, &layer) in LAYER_BGS.iter().enumerate() {
+                if !(self.display_bg[i] && win_ctrl.display_bg[i] && self.layer_enabled(layer)) {
                     continue;
                 }
 
@@ -1379,6 +1475,7 @@ impl Lcd {
         let eva = self.blend_ctrl.eva.min(16);
         let evb = self.blend_ctrl.evb.min(16);
         let evy = self.blend_ctrl.evy.min(16);
+        let effects = self.layer_enabled(Layer::Effects);
 
         for x in 0..SCREEN_WIDTH {
             let x = x as usize;
@@ -1391,6 +1488,7 @@ impl Lcd {
             let eff = a0.effect();
 
             let col = match (eff & 4, eff & 3) {
+                _ if !effects => c0,
                 (4, _) if target1 & (1 << a1.kind()) != 0 => alpha_blend(c0, eva, c1, evb),
                 (_, 1) if target0 & (1 << a0.kind()) != 0 && target1 & (1 << a1.kind()) != 0 => {
                     alpha_blend(c0, eva, c1, evb)
@@ -1,96 +1,398 @@
+mod archive;
+mod audio_sink;
+mod autosave;
 mod backup;
 mod bios;
 mod bus;
+mod cheat;
 mod consts;
 mod context;
 mod cpu;
+mod determinism;
 mod dma;
+mod gamedb;
 mod gamepak;
+mod gsf;
+mod history;
 mod interface;
 mod interrupt;
 mod ioreg_info;
 mod lcd;
+mod movie;
+mod mp2k;
+mod multiboot;
+mod output_filter;
+mod patch;
+mod resampler;
+mod rewind;
 mod rom;
 mod serial;
 mod sound;
+mod state;
 mod timer;
+mod trace;
 mod util;
+mod vgm;
 
+use backup::Backup;
+use cheat::CheatEngine;
 use context::Context;
+use movie::MovieSession;
 
-use interface::AudioBuf;
-pub use interface::{FrameBuf, KeyInput};
-pub use rom::Rom;
+pub use audio_sink::{AudioRing, AudioRingWriter, AudioSink, DEFAULT_MAX_RATE_DELTA};
+pub use autosave::{Autosave, AutosaveConfig};
+pub use backup::savefile::{
+    detect_save_format, export_save, import_save, SaveFileError, SaveFileFormat, SaveKind,
+};
+pub use backup::{flash::FlashChip, BackupConfig, BackupType};
+pub use cheat::{Cheat, CheatFormat};
+pub use determinism::{Component, Divergence, HashLog, StateHash, HASH_LOG_MAGIC};
+pub use gamedb::{GameDb, GameInfo, GpioDevice, QUIRK_ROM_MIRRORING};
+pub use gsf::{Gsf, GsfPlayer, DEFAULT_GSF_FADE, DEFAULT_GSF_LENGTH};
+pub use history::{CallFrame, CallKind, FaultReport, FaultReportEntry, HistoryEntry};
+pub use interface::{
+    AudioBuf, AudioSample, ColorCorrection, FrameBuf, KeyInput, Pixel, PixelFormat, PixelMut,
+};
+pub use lcd::Layer;
+pub use movie::{Movie, MovieFrame, MovieMode, MovieStart, SensorInput, MOVIE_MAGIC};
+pub use multiboot::{MultibootMode, MULTIBOOT_MAX_SIZE};
+pub use output_filter::OutputFilter;
+pub use patch::{apply_patch, detect_patch_format, PatchError, PatchFormat};
+pub use rewind::{RewindBuffer, RewindConfig};
+pub use rom::{Region, Rom, RomInfo};
+pub use sound::{AudioChannel, MAX_AUDIO_SAMPLE_RATE};
+pub use state::{
+    read_state_header, SaveStateOptions, StateCompression, StateError, StateHeader, StateMetadata,
+    Thumbnail, STATE_FORMAT_VERSION, STATE_MAGIC,
+};
+pub use trace::{
+    trace_writer, BinaryTraceWriter, MgbaTraceWriter, NbaTraceWriter, TextTraceWriter, TraceFormat,
+    TraceRecord, TraceSink, BINARY_TRACE_MAGIC,
+};
+
+// Samples collected before they are passed to the audio sink
+const AUDIO_SINK_CHUNK: usize = 64;
 
 pub struct Agb {
     ctx: Context,
+    cheats: CheatEngine,
+    rom_crc32: u32,
+    // Hashing a large ROM takes a while, so this is computed once
+    rom_info: RomInfo,
+    game_info: GameInfo,
+    rewind: Option<RewindBuffer>,
+    movie: Option<MovieSession>,
+    sensors: SensorInput,
+    // Booted without a cartridge
+    multiboot: Option<(Vec<u8>, MultibootMode)>,
+    // Started here without running the BIOS
+    entry_point: Option<u32>,
+    mp2k: Option<mp2k::Mp2k>,
+    audio_sink: Option<Box<dyn AudioSink + Send>>,
 }
 
 impl Agb {
     pub fn new(bios: Vec<u8>, rom: Rom, backup: Option<Vec<u8>>) -> Self {
+        Self::with_game_db(bios, rom, backup, GameDb::bundled())
+    }
+
+    /// Like `new`, taking per-title settings from `game_db` instead of the
+    /// bundled database.
+    pub fn with_game_db(
+        bios: Vec<u8>,
+        rom: Rom,
+        backup: Option<Vec<u8>>,
+        game_db: &GameDb,
+    ) -> Self {
+        let rom_info = rom.info();
+        let game_info = game_db
+            .lookup(&rom.game_code, rom.rom_version)
+            .unwrap_or_default();
+        if let Some(gpio) = &game_info.gpio {
+            log::warn!("Cartridge GPIO devices are not emulated: {gpio:?}");
+        }
+        let backup = Backup::for_rom(&rom.data, &game_info.backup_config(), backup);
         let mut ctx = Context::new(bios, rom, backup);
         ctx.cpu.set_pc(&mut ctx.inner, 0);
-        Agb { ctx }
+        {
+            use context::GamePak;
+            ctx.gamepak_mut()
+                .set_rom_mirroring(game_info.has_quirk(QUIRK_ROM_MIRRORING));
+        }
+        Agb {
+            ctx,
+            cheats: CheatEngine::new(),
+            rom_crc32: rom_info.crc32,
+            rom_info,
+            game_info,
+            rewind: None,
+            movie: None,
+            sensors: SensorInput::default(),
+            multiboot: None,
+            entry_point: None,
+            mp2k: None,
+            audio_sink: None,
+        }
+    }
+
+    /// Boots a multiboot program with an empty cartridge slot. `image` is
+    /// loaded at the start of EWRAM and entered at 0x020000C0.
+    pub fn multiboot(bios: Vec<u8>, image: &[u8], mode: MultibootMode) -> anyhow::Result<Self> {
+        multiboot::check_image(image)?;
+
+        let game_info = GameInfo {
+            backup: Some(BackupType::None),
+            ..Default::default()
+        };
+        let backup = Backup::for_rom(&[], &game_info.backup_config(), None);
+        let mut ctx = Context::new(bios, Rom::default(), backup);
+        multiboot::boot(&mut ctx, image, mode);
+
+        Ok(Agb {
+            ctx,
+            cheats: CheatEngine::new(),
+            rom_crc32: util::crc32(image),
+            rom_info: Rom::default().info(),
+            game_info,
+            rewind: None,
+            movie: None,
+            sensors: SensorInput::default(),
+            multiboot: Some((image.to_vec(), mode)),
+            entry_point: None,
+            mp2k: None,
+            audio_sink: None,
+        })
+    }
+
+    /// Boots the program of a GSF music rip at its entry point, skipping
+    /// the BIOS
+    pub fn gsf(bios: Vec<u8>, gsf: &Gsf) -> anyhow::Result<Self> {
+        let (rom, multiboot) = if gsf.load_address == gsf::ROM_BASE {
+            let rom = Rom {
+                data: gsf.image.clone(),
+                ..Default::default()
+            };
+            (rom, None)
+        } else {
+            let image = gsf.image.clone();
+            (Rom::default(), Some((image, MultibootMode::Direct)))
+        };
+
+        let game_info = GameInfo {
+            backup: Some(BackupType::None),
+            ..Default::default()
+        };
+        let backup = Backup::for_rom(&[], &game_info.backup_config(), None);
+        let rom_info = rom.info();
+        let mut ctx = Context::new(bios, rom, backup);
+        if let Some((image, _)) = &multiboot {
+            use context::Bus;
+            ctx.bus_mut().load_ext_ram(image);
+        }
+        multiboot::boot_direct(&mut ctx, gsf.entry_point);
+
+        Ok(Agb {
+            ctx,
+            cheats: CheatEngine::new(),
+            rom_crc32: util::crc32(&gsf.image),
+            rom_info,
+            game_info,
+            rewind: None,
+            movie: None,
+            sensors: SensorInput::default(),
+            multiboot,
+            entry_point: Some(gsf.entry_point),
+            mp2k: None,
+            audio_sink: None,
+        })
+    }
+
+    pub fn rom_info(&self) -> RomInfo {
+        self.rom_info.clone()
     }
 
+    /// `rom_info` and the backup type as human-readable pairs
     pub fn info(&self) -> Vec<(String, String)> {
         use context::GamePak;
-        let rom = self.ctx.gamepak().rom();
+        let info = &self.rom_info;
 
-        let rom_size = rom.data.len();
-        let rom_size = if rom_size < 1 * 1024 * 1024 {
-            format!("{} KiB", rom_size as f64 / 1024 as f64)
+        let rom_size = if info.size < 1024 * 1024 {
+            format!("{} KiB", info.size as f64 / 1024.0)
         } else {
-            format!("{} MiB", rom_size as f64 / (1024 * 1024) as f64)
+            format!("{} MiB", info.size as f64 / (1024.0 * 1024.0))
         };
+        let valid = |b: bool| if b { "OK" } else { "Invalid" }.to_string();
 
         vec![
-            (
-                "Title".to_string(),
-                String::from_utf8_lossy(&rom.title).to_string(),
-            ),
+            ("Title".to_string(), info.title.clone()),
             (
                 "Game Code".to_string(),
-                String::from_utf8_lossy(&rom.game_code).to_string(),
+                String::from_utf8_lossy(&info.game_code).to_string(),
             ),
             (
                 "Maker Code".to_string(),
-                String::from_utf8_lossy(&rom.maker_code).to_string(),
+                String::from_utf8_lossy(&info.maker_code).to_string(),
+            ),
+            ("Region".to_string(), format!("{:?}", info.region)),
+            (
+                "Main Unit Code".to_string(),
+                info.main_unit_code.to_string(),
+            ),
+            ("Device Type".to_string(), info.device_type.to_string()),
+            ("ROM Version".to_string(), info.version.to_string()),
+            ("Logo".to_string(), valid(info.logo_valid)),
+            ("Fixed Value".to_string(), valid(info.fixed_value_valid)),
+            (
+                "Header Checksum".to_string(),
+                format!(
+                    "{:02X} ({})",
+                    info.header_checksum,
+                    valid(info.header_checksum_valid)
+                ),
+            ),
+            (
+                "Backup Type".to_string(),
+                self.ctx.backup().backup_type().to_string(),
             ),
-            ("Main Unit Code".to_string(), rom.main_unit_code.to_string()),
-            ("Device Type".to_string(), rom.device_type.to_string()),
-            ("ROM Version".to_string(), rom.rom_version.to_string()),
-            ("Backup Type".to_string(), rom.backup_type().to_string()),
             ("ROM Size".to_string(), rom_size),
+            ("CRC32".to_string(), format!("{:08X}", info.crc32)),
+            (
+                "SHA1".to_string(),
+                info.sha1.iter().map(|b| format!("{b:02x}")).collect(),
+            ),
         ]
     }
 
     pub fn reset(&mut self) {
-        use context::{Bus, GamePak};
+        if let Some(movie) = &mut self.movie {
+            match movie.mode {
+                MovieMode::Recording => movie.pending_reset = true,
+                MovieMode::Playback if movie.read_only => {
+                    log::warn!("Ignoring reset during read-only movie playback");
+                    return;
+                }
+                MovieMode::Playback => {
+                    movie.branch();
+                    movie.pending_reset = true;
+                }
+                MovieMode::Finished => {}
+            }
+        }
+        self.reset_ctx();
+    }
+
+    fn reset_ctx(&mut self) {
+        use context::GamePak;
+        self.power_on(self.ctx.backup().data());
+    }
+
+    fn power_on(&mut self, backup: Option<Vec<u8>>) {
+        use context::{Bus, GamePak, Lcd, Sound};
 
         let bios = self.ctx.bus().bios.clone();
         let rom = self.ctx.gamepak().rom().clone();
-        let backup = self.ctx.backup().data();
+        let backup = Backup::for_rom(&rom.data, &self.game_info.backup_config(), backup);
 
-        self.ctx = Context::new(bios, rom, backup);
+        let mut ctx = Context::new(bios, rom, backup);
+        ctx.gamepak_mut()
+            .set_rom_mirroring(self.game_info.has_quirk(QUIRK_ROM_MIRRORING));
+        ctx.sound_mut().copy_output_settings(self.ctx.sound());
+        ctx.lcd_mut()
+            .frame_buf
+            .copy_output_settings(self.ctx.lcd().frame_buf());
+        ctx.lcd_mut().take_debug_settings(self.ctx.lcd_mut());
+        self.move_vgm_recorder(&mut ctx);
+        if let Some((image, mode)) = &self.multiboot {
+            multiboot::boot(&mut ctx, image, *mode);
+        }
+        if let Some(pc) = self.entry_point {
+            multiboot::boot_direct(&mut ctx, pc);
+        }
+        ctx.cpu.swap_debug_hooks(&mut self.ctx.cpu);
+        self.ctx = ctx;
+        self.update_rom_patches();
+        if let Some(mp2k) = &mut self.mp2k {
+            mp2k.reset();
+        }
+        if let Some(rewind) = &mut self.rewind {
+            rewind.clear();
+        }
     }
 
     pub fn exec_frame(&mut self, render_graphics: bool) {
-        use context::{Bus, Lcd, Sound};
+        use context::{Bus, Interrupt, Lcd, Sound, Timing};
 
         self.ctx.sound_mut().clear_buf();
         self.ctx.lcd_mut().set_render_graphics(render_graphics);
         self.ctx.sound_mut().clear_buf();
 
+        if let Some(frame) = self.movie.as_mut().and_then(|m| m.next_frame()) {
+            if frame.reset {
+                self.reset_ctx();
+            }
+            self.ctx.set_key_input(&KeyInput::from_bits(frame.keys));
+            self.sensors = frame.sensors;
+        }
+
+        // Without a master code, cheats are applied once per frame
+        let hooks = self.cheats.hooks();
+        if hooks.is_empty() {
+            self.cheats.apply(&mut self.ctx.inner);
+        }
+
+        let idle_loop = self.game_info.idle_loop;
+        let mut pushed = 0;
         let start_frame = self.ctx.lcd().frame();
         while start_frame == self.ctx.lcd().frame() {
             if !self.ctx.dma_tick() {
+                if idle_loop == Some(self.ctx.cpu.next_pc()) && self.idle() {
+                    // Only an LCD event or a timer overflow can end the loop,
+                    // so skip ahead to the next one before polling again
+                    let timer = self.ctx.bus().cycles_to_timer_overflow();
+                    let step = self.ctx.lcd().cycles_to_next_event();
+                    self.ctx.elapse(timer.map_or(step, |t| t.min(step)));
+                }
+                if !hooks.is_empty()
+                    && !self.ctx.interrupt().halt()
+                    && hooks.contains(&self.ctx.cpu.next_pc())
+                {
+                    self.cheats.apply(&mut self.ctx.inner);
+                }
                 self.ctx.cpu.exec_one(&mut self.ctx.inner);
             }
             self.ctx.lcd_tick();
             self.ctx.sound_tick();
             self.ctx.bus_tick();
+
+            if let Some(sink) = &mut self.audio_sink {
+                let buf = &self.ctx.sound().audio_buf().buf;
+                if buf.len() >= pushed + AUDIO_SINK_CHUNK {
+                    sink.push_samples(&buf[pushed..]);
+                    pushed = buf.len();
+                }
+            }
+        }
+
+        if let Some(sink) = &mut self.audio_sink {
+            sink.push_samples(&self.ctx.sound().audio_buf().buf[pushed..]);
+        }
+        if let Some(mp2k) = &mut self.mp2k {
+            mp2k.sync(&mut self.ctx);
         }
+        self.capture_rewind();
+    }
+
+    // No interrupt is pending and the CPU is not halted already
+    fn idle(&self) -> bool {
+        use context::Interrupt;
+
+        let interrupt = self.ctx.interrupt();
+        !interrupt.halt() && interrupt.enable() & interrupt.request() == 0
+    }
+
+    /// Settings for this title from the game database
+    pub fn game_info(&self) -> &GameInfo {
+        &self.game_info
     }
 
     pub fn ctx(&self) -> &Context {
@@ -101,19 +403,288 @@ impl Agb {
         &mut self.ctx
     }
 
+    /// Starts recording every executed instruction to `sink`, replacing the
+    /// previous sink. Passing `None` stops tracing and returns the old sink.
+    pub fn set_trace_sink(
+        &mut self,
+        sink: Option<Box<dyn TraceSink + Send>>,
+    ) -> Option<Box<dyn TraceSink + Send>> {
+        let mut prev = self.ctx.cpu.set_tracer(sink);
+        if let Some(prev) = &mut prev {
+            if let Err(err) = prev.flush() {
+                log::warn!("Failed to flush trace: {err}");
+            }
+        }
+        prev
+    }
+
+    /// Sends audio to `sink` while frames run, in addition to `audio_buf`.
+    /// Returns the previous sink.
+    pub fn set_audio_sink(
+        &mut self,
+        sink: Option<Box<dyn AudioSink + Send>>,
+    ) -> Option<Box<dyn AudioSink + Send>> {
+        std::mem::replace(&mut self.audio_sink, sink)
+    }
+
+    /// Keeps the last `capacity` executed instructions and a shadow call
+    /// stack for `fault_report`. `None` disables them.
+    pub fn set_history_capacity(&mut self, capacity: Option<usize>) {
+        self.ctx.cpu.set_history_capacity(capacity);
+    }
+
+    pub fn instr_history(&self) -> Vec<HistoryEntry> {
+        self.ctx
+            .cpu
+            .history()
+            .map_or_else(Vec::new, |h| h.instrs().cloned().collect())
+    }
+
+    /// Innermost frame first
+    pub fn call_stack(&self) -> Vec<CallFrame> {
+        self.ctx
+            .cpu
+            .history()
+            .map_or_else(Vec::new, |h| h.call_stack().cloned().collect())
+    }
+
+    /// Describes the current CPU state, call stack and recent instructions.
+    /// Useful after catching a panic from `exec_frame`.
+    pub fn fault_report(&self) -> FaultReport {
+        use context::Timing;
+        self.ctx.cpu.fault_report(self.ctx.now())
+    }
+
+    pub fn cheats(&self) -> &[Cheat] {
+        self.cheats.cheats()
+    }
+
+    /// Parses and enables a cheat, returning its index.
+    pub fn add_cheat(
+        &mut self,
+        name: &str,
+        format: CheatFormat,
+        code: &str,
+    ) -> anyhow::Result<usize> {
+        let ix = self.cheats.add(Cheat::parse(name, format, code)?);
+        self.update_rom_patches();
+        Ok(ix)
+    }
+
+    pub fn remove_cheat(&mut self, ix: usize) -> Option<Cheat> {
+        let ret = self.cheats.remove(ix);
+        self.update_rom_patches();
+        ret
+    }
+
+    pub fn clear_cheats(&mut self) {
+        self.cheats.clear();
+        self.update_rom_patches();
+    }
+
+    pub fn set_cheat_enabled(&mut self, ix: usize, enabled: bool) -> anyhow::Result<()> {
+        self.cheats.set_enabled(ix, enabled)?;
+        self.update_rom_patches();
+        Ok(())
+    }
+
+    fn update_rom_patches(&mut self) {
+        use context::GamePak;
+        let patches = self.cheats.rom_patches();
+        self.ctx.gamepak_mut().set_rom_patches(patches);
+    }
+
     pub fn frame_buf(&self) -> &FrameBuf {
         use context::Lcd;
         self.ctx.lcd().frame_buf()
     }
 
+    pub fn layer_enabled(&self, layer: Layer) -> bool {
+        use context::Lcd;
+        self.ctx.lcd().layer_enabled(layer)
+    }
+
+    /// Hides a BG, OBJ, the windows or the colour special effects
+    /// regardless of DISPCNT, for debugging
+    pub fn set_layer_enabled(&mut self, layer: Layer, enabled: bool) {
+        use context::Lcd;
+        self.ctx.lcd_mut().set_layer_enabled(layer, enabled);
+    }
+
+    /// Also draw BG0-3 and OBJ each to its own `FrameBuf`, see `layer_buf`
+    pub fn set_layer_buffers(&mut self, enable: bool) {
+        use context::Lcd;
+        self.ctx.lcd_mut().set_layer_buffers(enable);
+    }
+
+    /// BG or OBJ alone, with alpha 0 and bit 15 of `FrameBuf::bgr555` set
+    /// where it is transparent
+    pub fn layer_buf(&self, layer: Layer) -> Option<&FrameBuf> {
+        use context::Lcd;
+        self.ctx.lcd().layer_buf(layer)
+    }
+
+    pub fn color_correction(&self) -> ColorCorrection {
+        self.frame_buf().color_correction()
+    }
+
+    /// Adjusts `frame_buf` to look like the given screen. Off by default.
+    pub fn set_color_correction(&mut self, correction: ColorCorrection) {
+        use context::Lcd;
+        self.ctx
+            .lcd_mut()
+            .frame_buf
+            .set_color_correction(correction);
+    }
+
+    pub fn pixel_format(&self) -> PixelFormat {
+        self.frame_buf().pixel_format()
+    }
+
+    /// Layout of `FrameBuf::data`, RGBA8888 by default
+    pub fn set_pixel_format(&mut self, format: PixelFormat) {
+        use context::Lcd;
+        self.ctx.lcd_mut().frame_buf.set_pixel_format(format);
+    }
+
     pub fn audio_buf(&self) -> &AudioBuf {
         use context::Sound;
         self.ctx.sound().audio_buf()
     }
 
+    pub fn audio_sample_rate(&self) -> u32 {
+        use context::Sound;
+        self.ctx.sound().sample_rate()
+    }
+
+    pub fn channel_muted(&self, ch: AudioChannel) -> bool {
+        use context::Sound;
+        self.ctx.sound().channel_muted(ch)
+    }
+
+    pub fn set_channel_muted(&mut self, ch: AudioChannel, muted: bool) {
+        use context::Sound;
+        self.ctx.sound_mut().set_channel_muted(ch, muted);
+    }
+
+    pub fn channel_solo(&self, ch: AudioChannel) -> bool {
+        use context::Sound;
+        self.ctx.sound().channel_solo(ch)
+    }
+
+    /// While any channel is soloed, only soloed channels are heard
+    pub fn set_channel_solo(&mut self, ch: AudioChannel, solo: bool) {
+        use context::Sound;
+        self.ctx.sound_mut().set_channel_solo(ch, solo);
+    }
+
+    /// Also fill `AudioBuf::channels` with the output of each channel
+    pub fn set_channel_buffers(&mut self, enable: bool) {
+        use context::Sound;
+        self.ctx.sound_mut().set_channel_buffers(enable);
+    }
+
+    /// Sets the rate of the samples in `audio_buf`, e.g. 32000, 44100,
+    /// 48000 (default) or 96000 Hz
+    pub fn set_audio_sample_rate(&mut self, rate: u32) -> anyhow::Result<()> {
+        use context::Sound;
+        if !(1..=MAX_AUDIO_SAMPLE_RATE).contains(&rate) {
+            anyhow::bail!("Unsupported sample rate: {rate} Hz");
+        }
+        self.ctx.sound_mut().set_sample_rate(rate);
+        Ok(())
+    }
+
+    pub fn audio_output_filter(&self) -> Option<OutputFilter> {
+        use context::Sound;
+        self.ctx.sound().output_filter()
+    }
+
+    /// Filters `audio_buf` like the analog output, e.g.
+    /// `OutputFilter::SPEAKER`. Off by default.
+    pub fn set_audio_output_filter(&mut self, filter: Option<OutputFilter>) {
+        use context::Sound;
+        self.ctx.sound_mut().set_output_filter(filter);
+    }
+
+    pub fn vgm_recording(&self) -> bool {
+        use context::Sound;
+        self.ctx.sound().vgm_recording()
+    }
+
+    /// Starts logging the PSG registers to a VGM file, and with `pcm` the
+    /// samples played by DirectSound. A recording in progress is discarded.
+    pub fn start_vgm_recording(&mut self, pcm: bool) {
+        use context::{Sound, Timing};
+        let now = self.ctx.now();
+        self.ctx
+            .sound_mut()
+            .start_vgm(vgm::VgmRecorder::new(now, pcm), now);
+    }
+
+    /// Returns the VGM file of the recording
+    pub fn stop_vgm_recording(&mut self) -> Option<Vec<u8>> {
+        use context::{Sound, Timing};
+        let now = self.ctx.now();
+        Some(self.ctx.sound_mut().take_vgm()?.finish(now))
+    }
+
+    // Continues a recording in the context replacing the current one
+    fn move_vgm_recorder(&mut self, ctx: &mut Context) {
+        use context::{Sound, Timing};
+        if let Some(mut vgm) = self.ctx.sound_mut().take_vgm() {
+            let now = ctx.now();
+            vgm.rebase(self.ctx.now(), now);
+            ctx.sound_mut().start_vgm(vgm, now);
+        }
+    }
+
+    pub fn mp2k_hle(&self) -> bool {
+        self.mp2k.is_some()
+    }
+
+    /// Mixes the voices of games using the MP2K (m4a) sound driver on the
+    /// host at the output sample rate, instead of playing the driver's
+    /// low-rate mix. Fails if the driver is not found in the ROM.
+    pub fn set_mp2k_hle(&mut self, enable: bool) -> anyhow::Result<()> {
+        use context::{GamePak, Sound};
+        if enable == self.mp2k.is_some() {
+            return Ok(());
+        }
+        if enable {
+            let sound_main = mp2k::find_sound_main(&self.ctx.gamepak().rom().data)
+                .ok_or_else(|| anyhow::anyhow!("MP2K sound driver not found"))?;
+            log::info!("MP2K: SoundMain at {:08X}", 0x0800_0000 + sound_main);
+            self.mp2k = Some(mp2k::Mp2k::new());
+        } else {
+            self.mp2k = None;
+        }
+        self.ctx.sound_mut().set_mp2k_mixer(enable);
+        Ok(())
+    }
+
+    /// While a movie is active, keys are applied at the start of the next
+    /// frame (recording) or ignored (playback).
     pub fn set_key_input(&mut self, key_input: &KeyInput) {
         use context::Bus;
-        self.ctx.set_key_input(key_input);
+        match &mut self.movie {
+            Some(movie) if movie.mode == MovieMode::Recording => movie.keys = key_input.clone(),
+            Some(movie) if movie.mode == MovieMode::Playback => {}
+            _ => self.ctx.set_key_input(key_input),
+        }
+    }
+
+    pub fn set_sensor_input(&mut self, sensors: &SensorInput) {
+        match &mut self.movie {
+            Some(movie) if movie.mode == MovieMode::Recording => movie.sensors = *sensors,
+            Some(movie) if movie.mode == MovieMode::Playback => {}
+            _ => self.sensors = *sensors,
+        }
+    }
+
+    /// Sensor readings for the current frame, from the movie during playback
+    pub fn sensor_input(&self) -> &SensorInput {
+        &self.sensors
     }
 
     pub fn backup(&self) -> Option<Vec<u8>> {
@@ -121,15 +692,138 @@ impl Agb {
         self.ctx.gamepak().backup().data()
     }
 
+    /// Whether the backup changed since the last `take_dirty_backup` or
+    /// `clear_backup_dirty`
+    pub fn backup_dirty(&self) -> bool {
+        use context::GamePak;
+        self.ctx.backup().dirty()
+    }
+
+    pub fn clear_backup_dirty(&mut self) {
+        use context::GamePak;
+        self.ctx.backup_mut().set_dirty(false);
+    }
+
+    /// Returns the backup only if it changed since the last call
+    pub fn take_dirty_backup(&mut self) -> Option<Vec<u8>> {
+        if !self.backup_dirty() {
+            return None;
+        }
+        self.clear_backup_dirty();
+        self.backup()
+    }
+
+    pub fn save_kind(&self) -> Option<SaveKind> {
+        use context::GamePak;
+        self.ctx.backup().save_kind()
+    }
+
+    /// Replaces the backup memory with a save file of tgba or another
+    /// emulator. The format is detected if `format` is `None`, which fails
+    /// for EEPROM saves of the exact size.
+    pub fn import_backup(
+        &mut self,
+        data: &[u8],
+        format: Option<SaveFileFormat>,
+    ) -> anyhow::Result<()> {
+        use context::GamePak;
+
+        let kind = self.save_kind().ok_or(SaveFileError::NoBackup)?;
+        let data = import_save(data, kind, format)?;
+        let backup = Backup::for_rom(
+            &self.ctx.gamepak().rom().data,
+            &self.game_info.backup_config(),
+            Some(data),
+        );
+        *self.ctx.backup_mut() = backup;
+        self.ctx.backup_mut().set_dirty(true);
+        Ok(())
+    }
+
+    pub fn export_backup(&self, format: SaveFileFormat) -> anyhow::Result<Vec<u8>> {
+        let kind = self.save_kind().ok_or(SaveFileError::NoBackup)?;
+        let data = self.backup().ok_or(SaveFileError::NoBackup)?;
+        Ok(export_save(&data, kind, format)?)
+    }
+
     pub fn save_state(&self) -> Vec<u8> {
-        bincode::serialize(&self.ctx).unwrap()
+        self.save_state_with(&SaveStateOptions::default())
     }
 
+    pub fn save_state_with(&self, opts: &SaveStateOptions) -> Vec<u8> {
+        use context::{GamePak, Lcd};
+        use std::time::{SystemTime, UNIX_EPOCH};
+
+        let frame_buf = self.ctx.lcd().frame_buf();
+        let header = StateHeader {
+            emulator_version: env!("CARGO_PKG_VERSION").to_string(),
+            game_code: self.ctx.gamepak().rom().game_code,
+            rom_crc32: self.rom_crc32,
+            compression: if opts.compress {
+                StateCompression::Deflate
+            } else {
+                StateCompression::None
+            },
+            metadata: StateMetadata {
+                timestamp: SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |d| d.as_secs()),
+                frame: self.ctx.lcd().frame(),
+                label: opts.label.clone(),
+                movie_frame: self.movie.as_ref().map(|m| m.cursor as u64),
+            },
+            thumbnail: opts.thumbnail.then(|| Thumbnail::from_frame_buf(frame_buf)),
+        };
+
+        let ctx = bincode::serialize(&self.ctx).unwrap();
+        state::encode_state(&header, &ctx, Some(frame_buf))
+    }
+
+    /// Restores a state produced by `save_state`. Fails with a `StateError`
+    /// if the state belongs to another ROM or a newer format version.
+    ///
+    /// While a movie is active, the state must have been saved during it.
+    /// Playback continues from the state's position in read-only mode;
+    /// otherwise the movie is truncated there and recording resumes.
     pub fn load_state(&mut self, data: &[u8]) -> anyhow::Result<()> {
-        use context::{Bus, GamePak, Lcd};
+        let movie_frame = match &self.movie {
+            Some(movie) => {
+                let movie_frame = read_state_header(data)?
+                    .and_then(|(_, header)| header.metadata.movie_frame)
+                    .ok_or_else(|| anyhow::anyhow!("State was not saved during a movie"))?
+                    as usize;
+                movie.check_seek(movie_frame)?;
+                Some(movie_frame)
+            }
+            None => None,
+        };
+
+        // The movie is only truncated once the state is known to be valid
+        self.load_state_inner(data)?;
+        if let (Some(movie), Some(movie_frame)) = (&mut self.movie, movie_frame) {
+            movie.seek(movie_frame)?;
+        }
+        Ok(())
+    }
+
+    fn load_state_inner(&mut self, data: &[u8]) -> anyhow::Result<()> {
+        use context::GamePak;
+
+        let game_code = self.ctx.gamepak().rom().game_code;
+        let decoded = state::decode_state(data, &game_code, self.rom_crc32)?;
+        self.restore_ctx(&decoded.ctx, decoded.frame.as_deref())?;
+        if let Some(rewind) = &mut self.rewind {
+            rewind.clear();
+        }
+        Ok(())
+    }
+
+    fn restore_ctx(&mut self, data: &[u8], frame: Option<&[u16]>) -> anyhow::Result<()> {
+        use context::{Bus, GamePak, Lcd, Sound};
         use std::mem::swap;
 
-        let mut ctx: Context = bincode::deserialize(data)?;
+        let mut ctx: Context =
+            bincode::deserialize(data).map_err(|e| StateError::Corrupted(e.to_string()))?;
 
         // Restore unsaved components
         swap(
@@ -137,12 +831,210 @@ impl Agb {
             ctx.gamepak_mut().rom_mut(),
         );
         swap(&mut self.ctx.bus_mut().bios, &mut ctx.bus_mut().bios);
+        ctx.gamepak_mut()
+            .set_rom_mirroring(self.game_info.has_quirk(QUIRK_ROM_MIRRORING));
+        if let Some(chip) = self.ctx.backup().flash_chip() {
+            ctx.backup_mut().set_flash_chip(chip);
+        }
+        // Only schedule a write if the restored backup differs from what
+        // was persisted, so rewinding does not rewrite an unchanged save
+        let dirty = self.ctx.backup().dirty() || ctx.backup().data() != self.ctx.backup().data();
+        ctx.backup_mut().set_dirty(dirty);
         swap(
             &mut self.ctx.lcd_mut().frame_buf,
             &mut ctx.lcd_mut().frame_buf,
         );
+        ctx.lcd_mut().take_debug_settings(self.ctx.lcd_mut());
+        ctx.sound_mut().copy_output_settings(self.ctx.sound());
+        self.move_vgm_recorder(&mut ctx);
+        ctx.cpu.swap_debug_hooks(&mut self.ctx.cpu);
+
+        if let Some(frame) = frame {
+            ctx.lcd_mut().frame_buf.copy_from_bgr555(frame);
+        }
 
         self.ctx = ctx;
+        self.update_rom_patches();
+        if let Some(mp2k) = &mut self.mp2k {
+            mp2k.reset();
+        }
+        Ok(())
+    }
+
+    /// Captures a snapshot every `config.interval` frames for `rewind`.
+    /// `None` disables rewinding and frees the snapshots.
+    pub fn set_rewind(&mut self, config: Option<RewindConfig>) {
+        self.rewind = config.map(RewindBuffer::new);
+    }
+
+    pub fn rewind_buffer(&self) -> Option<&RewindBuffer> {
+        self.rewind.as_ref()
+    }
+
+    fn capture_rewind(&mut self) {
+        use context::Lcd;
+
+        let Some(rewind) = &mut self.rewind else {
+            return;
+        };
+        if !rewind.tick() {
+            return;
+        }
+
+        let mut snapshot = vec![0; 4];
+        bincode::serialize_into(&mut snapshot, &self.ctx).unwrap();
+        let ctx_len = (snapshot.len() - 4) as u32;
+        snapshot[..4].copy_from_slice(&ctx_len.to_le_bytes());
+        state::encode_frame(self.ctx.lcd().frame_buf(), &mut snapshot);
+
+        rewind.push(self.ctx.lcd().frame(), snapshot);
+    }
+
+    /// Goes back at least `frames` frames (or as far as the buffer allows),
+    /// restoring both the emulation state and the frame buffer. Returns the
+    /// number of frames actually rewound.
+    pub fn rewind(&mut self, frames: u64) -> anyhow::Result<u64> {
+        use context::Lcd;
+
+        let cur_frame = self.ctx.lcd().frame();
+        let (frame, snapshot) = self
+            .rewind
+            .as_mut()
+            .ok_or_else(|| anyhow::anyhow!("Rewind is not enabled"))?
+            .rewind_to(cur_frame.saturating_sub(frames))
+            .ok_or_else(|| anyhow::anyhow!("No rewind snapshot available"))?;
+
+        let ctx_len = u32::from_le_bytes(snapshot[..4].try_into().unwrap()) as usize;
+        let frame_buf = state::decode_frame(&snapshot[4 + ctx_len..]);
+        self.restore_ctx(&snapshot[4..4 + ctx_len], Some(&frame_buf))?;
+
+        let rewound = cur_frame.saturating_sub(frame);
+        if let Some(movie) = &mut self.movie {
+            movie.seek(movie.cursor.saturating_sub(rewound as usize))?;
+        }
+        Ok(rewound)
+    }
+
+    /// Starts recording input from power-on or from a save state.
+    /// Replaces the active movie, if any.
+    pub fn record_movie(&mut self, start: MovieStart) -> anyhow::Result<()> {
+        use context::GamePak;
+
+        self.movie = None;
+        self.start_movie(&start)?;
+        let movie = Movie {
+            emulator_version: env!("CARGO_PKG_VERSION").to_string(),
+            game_code: self.ctx.gamepak().rom().game_code,
+            rom_crc32: self.rom_crc32,
+            start,
+            frames: vec![],
+            rerecords: 0,
+        };
+        self.movie = Some(MovieSession::new(movie, MovieMode::Recording, false));
+        Ok(())
+    }
+
+    /// Restarts from the movie's starting point and replays its input.
+    pub fn play_movie(&mut self, movie: Movie, read_only: bool) -> anyhow::Result<()> {
+        use context::GamePak;
+
+        let game_code = self.ctx.gamepak().rom().game_code;
+        if movie.game_code != game_code || movie.rom_crc32 != self.rom_crc32 {
+            anyhow::bail!(
+                "Movie is for a different ROM: movie: {} (CRC32: {:08X}), loaded: {} (CRC32: {:08X})",
+                String::from_utf8_lossy(&movie.game_code),
+                movie.rom_crc32,
+                String::from_utf8_lossy(&game_code),
+                self.rom_crc32
+            );
+        }
+
+        self.movie = None;
+        self.start_movie(&movie.start)?;
+        self.movie = Some(MovieSession::new(movie, MovieMode::Playback, read_only));
+        Ok(())
+    }
+
+    fn start_movie(&mut self, start: &MovieStart) -> anyhow::Result<()> {
+        match start {
+            // Movies do not store the backup, so they start from a blank one
+            // to play back the same with any save file
+            MovieStart::PowerOn => self.power_on(None),
+            MovieStart::SaveState(state) => self.load_state_inner(state)?,
+        }
+        self.sensors = SensorInput::default();
         Ok(())
     }
+
+    /// Stops recording or playback and returns the movie
+    pub fn stop_movie(&mut self) -> Option<Movie> {
+        self.movie.take().map(|m| m.movie)
+    }
+
+    pub fn movie(&self) -> Option<&Movie> {
+        self.movie.as_ref().map(|m| &m.movie)
+    }
+
+    pub fn movie_mode(&self) -> Option<MovieMode> {
+        self.movie.as_ref().map(|m| m.mode)
+    }
+
+    /// Number of movie frames elapsed
+    pub fn movie_frame(&self) -> Option<usize> {
+        self.movie.as_ref().map(|m| m.cursor)
+    }
+
+    pub fn set_movie_read_only(&mut self, read_only: bool) {
+        if let Some(movie) = &mut self.movie {
+            movie.read_only = read_only;
+        }
+    }
+
+    pub fn state_hash(&self) -> StateHash {
+        StateHash::of(&self.ctx)
+    }
+
+    /// Plays `movie` from its start, hashing the state after every frame.
+    /// Stops the active movie, if any, and returns to the current state
+    /// afterwards, so the backup is left as it was.
+    pub fn record_hash_log(&mut self, movie: &Movie) -> anyhow::Result<HashLog> {
+        use context::GamePak;
+
+        self.movie = None;
+        let saved = self.save_state();
+        let dirty = self.backup_dirty();
+        let ret = self.hash_movie(movie);
+        self.load_state_inner(&saved)?;
+        // The backup is back to what it was before the movie wrote to it
+        self.ctx.backup_mut().set_dirty(dirty);
+        ret
+    }
+
+    fn hash_movie(&mut self, movie: &Movie) -> anyhow::Result<HashLog> {
+        self.play_movie(movie.clone(), true)?;
+        let mut log = HashLog::default();
+        while self.movie_mode() == Some(MovieMode::Playback) {
+            self.exec_frame(false);
+            log.hashes.push(self.state_hash());
+        }
+        self.stop_movie();
+        Ok(log)
+    }
+
+    /// Replays `movie` and compares against a previously recorded hash log.
+    pub fn verify_hash_log(
+        &mut self,
+        movie: &Movie,
+        expected: &HashLog,
+    ) -> anyhow::Result<Option<Divergence>> {
+        let actual = self.record_hash_log(movie)?;
+        Ok(expected.first_divergence(&actual))
+    }
+
+    /// Replays `movie` twice and reports the first frame where the runs
+    /// differ, if any.
+    pub fn verify_determinism(&mut self, movie: &Movie) -> anyhow::Result<Option<Divergence>> {
+        let expected = self.record_hash_log(movie)?;
+        self.verify_hash_log(movie, &expected)
+    }
 }
@@ -0,0 +1,165 @@
+use anyhow::{anyhow, bail, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::interface::KeyInput;
+
+pub const MOVIE_MAGIC: &[u8; 8] = b"TGBAMOV1";
+
+/// Readings of cartridge sensors. The core does not emulate cartridge GPIO
+/// devices yet; these are recorded and played back for the frontend.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct SensorInput {
+    /// Boktai solar sensor level
+    pub solar: u8,
+    pub tilt_x: u16,
+    pub tilt_y: u16,
+    pub gyro: u16,
+}
+
+/// Input applied at the start of a frame
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct MovieFrame {
+    /// Pressed keys, see `KeyInput::to_bits`
+    pub keys: u16,
+    /// The console is reset before this frame runs
+    pub reset: bool,
+    pub sensors: SensorInput,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum MovieStart {
+    /// Power-on with a blank backup
+    PowerOn,
+    /// A save state produced by `Agb::save_state`
+    SaveState(#[serde(with = "serde_bytes")] Vec<u8>),
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Movie {
+    pub emulator_version: String,
+    pub game_code: [u8; 4],
+    pub rom_crc32: u32,
+    pub start: MovieStart,
+    pub frames: Vec<MovieFrame>,
+    /// Number of times the recording was branched from an earlier point
+    pub rerecords: u32,
+}
+
+impl Movie {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut ret = MOVIE_MAGIC.to_vec();
+        bincode::serialize_into(&mut ret, self).unwrap();
+        ret
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Self> {
+        let body = data
+            .strip_prefix(MOVIE_MAGIC)
+            .ok_or_else(|| anyhow!("Not a movie file"))?;
+        Ok(bincode::deserialize(body)?)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum MovieMode {
+    Recording,
+    Playback,
+    /// Playback reached the end of the movie, input is taken from the user
+    Finished,
+}
+
+pub(crate) struct MovieSession {
+    pub movie: Movie,
+    pub mode: MovieMode,
+    /// When false, loading a state during playback branches into recording
+    pub read_only: bool,
+    /// Index of the next frame
+    pub cursor: usize,
+    pub keys: KeyInput,
+    pub sensors: SensorInput,
+    pub pending_reset: bool,
+}
+
+impl MovieSession {
+    pub fn new(movie: Movie, mode: MovieMode, read_only: bool) -> Self {
+        let mode = if mode == MovieMode::Playback && movie.frames.is_empty() {
+            MovieMode::Finished
+        } else {
+            mode
+        };
+        Self {
+            movie,
+            mode,
+            read_only,
+            cursor: 0,
+            keys: KeyInput::default(),
+            sensors: SensorInput::default(),
+            pending_reset: false,
+        }
+    }
+
+    /// Advances one frame. Returns the input to apply, or `None` once
+    /// playback has finished.
+    pub fn next_frame(&mut self) -> Option<MovieFrame> {
+        match self.mode {
+            MovieMode::Recording => {
+                let frame = MovieFrame {
+                    keys: self.keys.to_bits(),
+                    reset: std::mem::take(&mut self.pending_reset),
+                    sensors: self.sensors,
+                };
+                self.movie.frames.truncate(self.cursor);
+                self.movie.frames.push(frame);
+                self.cursor += 1;
+                Some(frame)
+            }
+            MovieMode::Playback => {
+                let frame = self.movie.frames.get(self.cursor).copied();
+                self.cursor += frame.is_some() as usize;
+                if self.cursor >= self.movie.frames.len() {
+                    self.mode = MovieMode::Finished;
+                }
+                frame
+            }
+            MovieMode::Finished => None,
+        }
+    }
+
+    /// Fails if `seek(frame)` would, so a state can be validated before
+    /// anything is restored
+    pub fn check_seek(&self, frame: usize) -> Result<()> {
+        if frame > self.movie.frames.len() {
+            bail!(
+                "State is at movie frame {frame}, beyond the end of the movie ({} frames)",
+                self.movie.frames.len()
+            );
+        }
+        Ok(())
+    }
+
+    /// Moves to `frame` after a state load or rewind. In read-write mode the
+    /// frames after it are discarded and recording resumes from there.
+    pub fn seek(&mut self, frame: usize) -> Result<()> {
+        self.check_seek(frame)?;
+        self.cursor = frame;
+        self.branch();
+        Ok(())
+    }
+
+    /// Continues from the current frame as `seek` does
+    pub fn branch(&mut self) {
+        let frame = self.cursor;
+        self.pending_reset = false;
+        if self.read_only {
+            self.mode = if frame < self.movie.frames.len() {
+                MovieMode::Playback
+            } else {
+                MovieMode::Finished
+            };
+        } else {
+            self.movie.frames.truncate(frame);
+            self.movie.rerecords += 1;
+            self.mode = MovieMode::Recording;
+        }
+    }
+}
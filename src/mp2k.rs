@@ -0,0 +1,441 @@
+//! High-level emulation of the mixer of Nintendo's MP2K (m4a) sound driver.
+//!
+//! The driver mixes its DirectSound voices in software, usually at 13 or
+//! 18 kHz, into a buffer that is streamed to FIFO A/B. The game's code still
+//! runs unchanged; once per frame the driver's voice state is read from its
+//! `SoundInfo` structure and the voices are mixed again on the host at the
+//! output rate, replacing the DirectSound output. Compressed samples are not
+//! supported; games using them keep the driver's own output.
+//!
+//! Note starts are inferred by comparing the voice state between frames,
+//! which is best-effort: a note restarted on the same sample at the same
+//! position within a frame can be missed.
+
+use std::{collections::HashMap, sync::Arc};
+
+use log::{info, warn};
+
+use crate::{
+    consts::SYSTEM_CLOCK,
+    context::{Bus, Context, GamePak, Sound},
+};
+
+// The driver stores the address of its `SoundInfo` here
+const SOUND_INFO_PTR: u32 = 0x0300_7FF0;
+// `SoundInfo::ident` while the driver is idle; it is incremented while
+// `SoundMain` runs
+const ID_NUMBER: u32 = 0x6873_6D53;
+
+const MAX_CHANNELS: usize = 12;
+
+// Offsets in `SoundInfo`
+const INFO_REVERB: u32 = 0x05;
+const INFO_MAX_CHANS: u32 = 0x06;
+const INFO_PCM_DMA_PERIOD: u32 = 0x0B;
+const INFO_PCM_FREQ: u32 = 0x14;
+const INFO_CHANS: u32 = 0x50;
+
+// Offsets in `SoundChannel`
+const CHAN_SIZE: u32 = 0x40;
+const CHAN_STATUS: u32 = 0x00;
+const CHAN_TYPE: u32 = 0x01;
+const CHAN_ENV_RIGHT: u32 = 0x0A;
+const CHAN_ENV_LEFT: u32 = 0x0B;
+const CHAN_COUNT: u32 = 0x18;
+const CHAN_FREQ: u32 = 0x20;
+const CHAN_WAV: u32 = 0x24;
+const CHAN_CUR_PTR: u32 = 0x28;
+
+// START | STOP | IEC | ENV, none set means the channel is off
+const STATUS_ON: u8 = 0xC7;
+// Played at the mixing rate regardless of the key
+const TYPE_FIX: u8 = 0x08;
+
+// `WaveData`: type, status, freq, loop start, size, then the samples
+const WAV_HEADER_SIZE: u32 = 0x10;
+const WAV_STATUS_LOOP: u16 = 0xC000;
+
+const FRAMES_PER_SECOND: f64 = SYSTEM_CLOCK as f64 / 280_896.0;
+
+// Thumb code at the start of `SoundMain`, `None` for the literal pool
+// offsets of `ldr r0, =SOUND_INFO_PTR` and `ldr r2, =ID_NUMBER`
+const SOUND_MAIN_SIGNATURE: [Option<u8>; 20] = [
+    None,
+    Some(0x48),
+    Some(0x00),
+    Some(0x68),
+    None,
+    Some(0x4A),
+    Some(0x03),
+    Some(0x68),
+    Some(0x9A),
+    Some(0x42),
+    Some(0x00),
+    Some(0xD0),
+    Some(0x70),
+    Some(0x47),
+    Some(0x01),
+    Some(0x33),
+    Some(0x03),
+    Some(0x60),
+    Some(0xF0),
+    Some(0xB5),
+];
+
+/// Returns the ROM offset of the driver's `SoundMain`
+pub fn find_sound_main(rom: &[u8]) -> Option<u32> {
+    let literal = |pc: usize, imm: u8| -> Option<u32> {
+        let addr = ((pc + 4) & !3) + imm as usize * 4;
+        Some(u32::from_le_bytes(
+            rom.get(addr..addr + 4)?.try_into().unwrap(),
+        ))
+    };
+
+    (0..rom.len().saturating_sub(SOUND_MAIN_SIGNATURE.len()))
+        .step_by(2)
+        .find(|&i| {
+            SOUND_MAIN_SIGNATURE
+                .iter()
+                .zip(&rom[i..])
+                .all(|(s, b)| s.is_none_or(|s| s == *b))
+                && literal(i, rom[i]) == Some(SOUND_INFO_PTR)
+                && literal(i + 4, rom[i + 4]) == Some(ID_NUMBER)
+        })
+        .map(|i| i as u32)
+}
+
+#[derive(Clone)]
+struct ChannelState {
+    wav: u32,
+    count: u32,
+    sample: Sample,
+}
+
+/// Follows the driver's voices and passes them to the mixer of `Sound`
+pub struct Mp2k {
+    channels: [Option<ChannelState>; MAX_CHANNELS],
+    // Samples in ROM; those in RAM can change and are read on every note
+    rom_samples: HashMap<u32, Option<Sample>>,
+}
+
+impl Mp2k {
+    pub fn new() -> Self {
+        Self {
+            channels: Default::default(),
+            rom_samples: HashMap::new(),
+        }
+    }
+
+    /// Forgets the voices and samples, e.g. after a reset or state load
+    pub fn reset(&mut self) {
+        self.channels = Default::default();
+        self.rom_samples.clear();
+    }
+
+    /// Reads the driver state; called once per frame
+    pub fn sync(&mut self, ctx: &mut Context) {
+        let info = read32(ctx, SOUND_INFO_PTR);
+        if read32(ctx, info) != ID_NUMBER {
+            // Not initialized yet, or interrupted in the middle of `SoundMain`
+            set_mixer_active(ctx, false);
+            return;
+        }
+
+        let reverb = read8(ctx, info + INFO_REVERB);
+        let period = read8(ctx, info + INFO_PCM_DMA_PERIOD);
+        let pcm_freq = read32(ctx, info + INFO_PCM_FREQ);
+        let max_chans = (read8(ctx, info + INFO_MAX_CHANS) as usize).min(MAX_CHANNELS);
+
+        let mut updates = vec![];
+        for i in 0..MAX_CHANNELS {
+            let chan = info + INFO_CHANS + i as u32 * CHAN_SIZE;
+            if i >= max_chans || read8(ctx, chan + CHAN_STATUS) & STATUS_ON == 0 {
+                self.channels[i] = None;
+                updates.push(None);
+                continue;
+            }
+
+            let wav = read32(ctx, chan + CHAN_WAV);
+            let count = read32(ctx, chan + CHAN_COUNT);
+            let started = match &self.channels[i] {
+                // Best-effort: the driver clears its start flag before the
+                // frame ends, so a restart of the same sample is guessed from
+                // the remaining count going up without a loop explaining it
+                Some(prev) if prev.wav == wav => {
+                    count > prev.count
+                        && !matches!(prev.sample.loop_start, Some(start) if count as usize <= prev.sample.data.len() - start)
+                }
+                _ => true,
+            };
+
+            let sample = match &self.channels[i] {
+                Some(prev) if !started => prev.sample.clone(),
+                _ => match self.sample(ctx, wav) {
+                    Some(sample) => sample,
+                    None => {
+                        // Leave the mixing to the driver
+                        self.channels = Default::default();
+                        set_mixer_active(ctx, false);
+                        return;
+                    }
+                },
+            };
+
+            let hz = if read8(ctx, chan + CHAN_TYPE) & TYPE_FIX != 0 {
+                pcm_freq
+            } else {
+                read32(ctx, chan + CHAN_FREQ)
+            } as f64;
+            let volume =
+                [CHAN_ENV_RIGHT, CHAN_ENV_LEFT].map(|o| read8(ctx, chan + o) as f32 / 256.0);
+
+            self.channels[i] = Some(ChannelState {
+                wav,
+                count,
+                sample: sample.clone(),
+            });
+
+            // The driver has already mixed the frame's buffer, which is played
+            // during the next frame
+            let start = if started {
+                let pos = read32(ctx, chan + CHAN_CUR_PTR).wrapping_sub(wav + WAV_HEADER_SIZE);
+                Some((pos as f64 - hz / FRAMES_PER_SECOND).max(0.0))
+            } else {
+                None
+            };
+
+            updates.push(Some(VoiceUpdate {
+                sample: sample.clone(),
+                start,
+                hz,
+                volume,
+            }));
+        }
+
+        if let Some(mixer) = ctx.sound_mut().mp2k_mixer_mut() {
+            mixer.set_reverb(reverb, period);
+            for (i, update) in updates.into_iter().enumerate() {
+                mixer.update(i, update);
+            }
+            mixer.set_active(true);
+        }
+    }
+
+    fn sample(&mut self, ctx: &mut Context, wav: u32) -> Option<Sample> {
+        if !is_rom(wav) {
+            return read_sample(ctx, wav);
+        }
+        self.rom_samples
+            .entry(wav)
+            .or_insert_with(|| read_sample(ctx, wav))
+            .clone()
+    }
+}
+
+fn read_sample(ctx: &mut Context, wav: u32) -> Option<Sample> {
+    let ty = read16(ctx, wav);
+    if ty != 0 {
+        warn!("MP2K: unsupported sample type {ty:#06X} at {wav:08X}, HLE disabled");
+        return None;
+    }
+    let status = read16(ctx, wav + 2);
+    let loop_start = read32(ctx, wav + 8) as usize;
+    let size = read32(ctx, wav + 12) as usize;
+    let data = read_bytes(ctx, wav + WAV_HEADER_SIZE, size)?;
+    Some(Sample {
+        loop_start: (status & WAV_STATUS_LOOP != 0 && loop_start < size).then_some(loop_start),
+        data: data.into_iter().map(|b| b as i8).collect(),
+    })
+}
+
+fn is_rom(addr: u32) -> bool {
+    (0x0800_0000..0x0E00_0000).contains(&addr)
+}
+
+fn set_mixer_active(ctx: &mut Context, active: bool) {
+    if let Some(mixer) = ctx.sound_mut().mp2k_mixer_mut() {
+        mixer.set_active(active);
+    }
+}
+
+fn read8(ctx: &mut Context, addr: u32) -> u8 {
+    ctx.peek8(addr).unwrap_or(0)
+}
+
+fn read16(ctx: &mut Context, addr: u32) -> u16 {
+    u16::from_le_bytes([0, 1].map(|i| read8(ctx, addr + i)))
+}
+
+fn read32(ctx: &mut Context, addr: u32) -> u32 {
+    u32::from_le_bytes([0, 1, 2, 3].map(|i| read8(ctx, addr.wrapping_add(i))))
+}
+
+fn read_bytes(ctx: &mut Context, addr: u32, len: usize) -> Option<Vec<u8>> {
+    if is_rom(addr) {
+        let offset = (addr & 0x01FF_FFFF) as usize;
+        let ret = ctx.gamepak().rom().data.get(offset..offset + len);
+        if ret.is_none() {
+            warn!("MP2K: sample at {addr:08X} is out of the ROM");
+        }
+        return ret.map(|r| r.to_vec());
+    }
+    // Samples in RAM are small
+    if len > 0x40000 {
+        return None;
+    }
+    Some((0..len as u32).map(|i| read8(ctx, addr + i)).collect())
+}
+
+#[derive(Clone)]
+struct Sample {
+    data: Arc<[i8]>,
+    loop_start: Option<usize>,
+}
+
+struct VoiceUpdate {
+    sample: Sample,
+    // Position to (re)start from, in samples
+    start: Option<f64>,
+    hz: f64,
+    volume: [f32; 2],
+}
+
+struct Voice {
+    sample: Sample,
+    pos: f64,
+    hz: f64,
+    volume: [f32; 2],
+    done: bool,
+}
+
+impl Voice {
+    fn at(&self, i: usize) -> f32 {
+        let data = &self.sample.data;
+        let i = match self.sample.loop_start {
+            Some(start) if i >= data.len() => start + (i - start) % (data.len() - start),
+            _ => i,
+        };
+        data.get(i).map_or(0.0, |&s| s as f32)
+    }
+
+    // Cubic Hermite interpolation between the neighbouring samples
+    fn next(&mut self, out_rate: f64) -> f32 {
+        let i = self.pos as usize;
+        let t = (self.pos - i as f64) as f32;
+        let [y0, y1, y2, y3] = [i.wrapping_sub(1), i, i + 1, i + 2].map(|j| {
+            if j == usize::MAX {
+                0.0
+            } else {
+                self.at(j)
+            }
+        });
+        let c1 = (y2 - y0) * 0.5;
+        let c2 = y0 - y1 * 2.5 + y2 * 2.0 - y3 * 0.5;
+        let c3 = (y3 - y0) * 0.5 + (y1 - y2) * 1.5;
+        let ret = ((c3 * t + c2) * t + c1) * t + y1;
+
+        self.pos += self.hz / out_rate;
+        let len = self.sample.data.len();
+        if self.pos >= len as f64 {
+            match self.sample.loop_start {
+                Some(start) => {
+                    self.pos = start as f64 + (self.pos - start as f64) % (len - start) as f64
+                }
+                None => self.done = true,
+            }
+        }
+        ret
+    }
+}
+
+/// Host-side mixer of the driver's voices, output in DirectSound sample units
+pub struct Mp2kMixer {
+    active: bool,
+    out_rate: f64,
+    voices: [Option<Voice>; MAX_CHANNELS],
+    reverb: u8,
+    delay: Vec<[f32; 2]>,
+    delay_pos: usize,
+}
+
+impl Mp2kMixer {
+    pub fn new(out_rate: u32) -> Self {
+        Self {
+            active: false,
+            out_rate: out_rate as f64,
+            voices: Default::default(),
+            reverb: 0,
+            delay: vec![],
+            delay_pos: 0,
+        }
+    }
+
+    /// While active, the mixer replaces the DirectSound output
+    pub fn active(&self) -> bool {
+        self.active
+    }
+
+    fn set_active(&mut self, active: bool) {
+        if self.active && !active {
+            self.voices = Default::default();
+            info!("MP2K: driver inactive, using the DirectSound output");
+        }
+        self.active = active;
+    }
+
+    fn set_reverb(&mut self, reverb: u8, period: u8) {
+        self.reverb = reverb & 0x7F;
+        // The driver reads back its ring buffer, which is `period` frames long
+        let len = (self.out_rate * period.max(1) as f64 / FRAMES_PER_SECOND).round() as usize;
+        if self.reverb == 0 {
+            self.delay.clear();
+        } else if self.delay.len() != len {
+            self.delay = vec![[0.0; 2]; len];
+            self.delay_pos = 0;
+        }
+    }
+
+    fn update(&mut self, i: usize, update: Option<VoiceUpdate>) {
+        let Some(update) = update else {
+            self.voices[i] = None;
+            return;
+        };
+        match (&mut self.voices[i], update.start) {
+            (Some(voice), None) => {
+                voice.hz = update.hz;
+                voice.volume = update.volume;
+            }
+            (voice, start) => {
+                *voice = Some(Voice {
+                    sample: update.sample,
+                    pos: start.unwrap_or(0.0),
+                    hz: update.hz,
+                    volume: update.volume,
+                    done: false,
+                })
+            }
+        }
+    }
+
+    /// Renders one output sample as right, left
+    pub fn render(&mut self) -> [f32; 2] {
+        let mut out = [0.0; 2];
+        for voice in self.voices.iter_mut().flatten() {
+            if voice.done {
+                continue;
+            }
+            let s = voice.next(self.out_rate);
+            out[0] += s * voice.volume[0];
+            out[1] += s * voice.volume[1];
+        }
+
+        if !self.delay.is_empty() {
+            let [r, l] = self.delay[self.delay_pos];
+            let echo = (r + l) * self.reverb as f32 / 256.0;
+            out = out.map(|v| v + echo);
+            self.delay[self.delay_pos] = out;
+            self.delay_pos = (self.delay_pos + 1) % self.delay.len();
+        }
+        out
+    }
+}
@@ -0,0 +1,62 @@
+use anyhow::{bail, Result};
+
+use crate::context::{Bus, Context};
+
+/// Size of EWRAM, where multiboot images are transferred to
+pub const MULTIBOOT_MAX_SIZE: usize = 0x40000;
+
+const EWRAM_BASE: u32 = 0x02000000;
+const ENTRY_POINT: u32 = 0x020000C0;
+
+// SoftReset returns to EWRAM when this byte is non-zero
+const RESET_FLAG_ADDR: u32 = 0x03007FFA;
+// Cleared by SoftReset, so a free place for the instruction calling it
+const STUB_ADDR: u32 = 0x03007E00;
+const ARM_SWI_SOFT_RESET: u32 = 0xEF000000;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum MultibootMode {
+    /// Start at the entry point with the registers set up as the BIOS would
+    Direct,
+    /// Start through the BIOS SoftReset, which initializes the system and
+    /// enters the image through the branch at its start. The transfer over
+    /// the link cable itself is not emulated; the image is placed in EWRAM
+    /// as if it had completed.
+    Bios,
+}
+
+pub fn check_image(image: &[u8]) -> Result<()> {
+    if image.len() < (ENTRY_POINT - EWRAM_BASE) as usize {
+        bail!("Multiboot image is too small: {} bytes", image.len());
+    }
+    if image.len() > MULTIBOOT_MAX_SIZE {
+        bail!(
+            "Multiboot image is larger than EWRAM: {} bytes, max {MULTIBOOT_MAX_SIZE} bytes",
+            image.len()
+        );
+    }
+    Ok(())
+}
+
+/// Loads `image` into a freshly created context and starts it
+pub fn boot(ctx: &mut Context, image: &[u8], mode: MultibootMode) {
+    ctx.bus_mut().load_ext_ram(image);
+
+    match mode {
+        MultibootMode::Direct => boot_direct(ctx, ENTRY_POINT),
+        MultibootMode::Bios => {
+            ctx.poke8(RESET_FLAG_ADDR, 1);
+            for (i, b) in ARM_SWI_SOFT_RESET.to_le_bytes().into_iter().enumerate() {
+                ctx.poke8(STUB_ADDR + i as u32, b);
+            }
+            ctx.bus_mut().set_post_boot(1);
+            ctx.cpu.set_pc(&mut ctx.inner, STUB_ADDR);
+        }
+    }
+}
+
+/// Starts at `pc` with the registers set up as the BIOS would
+pub fn boot_direct(ctx: &mut Context, pc: u32) {
+    ctx.bus_mut().set_post_boot(1);
+    ctx.cpu.skip_bios(&mut ctx.inner, pc);
+}
@@ -0,0 +1,78 @@
+//! Approximation of the analog stage between the PWM output and the ear.
+//!
+//! The PWM signal passes an RC low-pass and a coupling capacitor that removes
+//! the bias, and the built-in speaker cannot reproduce bass. Both are modeled
+//! with first-order filters applied at the output sample rate.
+
+use std::f64::consts::PI;
+
+use crate::interface::AudioSample;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct OutputFilter {
+    /// Cutoff of the low-pass in Hz
+    pub low_pass: Option<f64>,
+    /// Cutoff of the DC-blocking high-pass in Hz
+    pub dc_block: Option<f64>,
+}
+
+impl OutputFilter {
+    /// Built-in speaker: muffled, with little bass
+    pub const SPEAKER: Self = Self {
+        low_pass: Some(6000.0),
+        dc_block: Some(250.0),
+    };
+
+    /// Headphone jack
+    pub const HEADPHONES: Self = Self {
+        low_pass: Some(16000.0),
+        dc_block: Some(20.0),
+    };
+}
+
+pub struct FilterState {
+    config: OutputFilter,
+    // Smoothing factor of the low-pass and pole of the high-pass
+    lp_alpha: f64,
+    hp_pole: f64,
+    lp: [f64; 2],
+    hp_in: [f64; 2],
+    hp_out: [f64; 2],
+}
+
+impl FilterState {
+    pub fn new(config: OutputFilter, rate: u32) -> Self {
+        let pole = |hz: f64| (-2.0 * PI * hz / rate as f64).exp();
+        Self {
+            config,
+            lp_alpha: config.low_pass.map_or(1.0, |hz| 1.0 - pole(hz)),
+            hp_pole: config.dc_block.map_or(1.0, pole),
+            lp: [0.0; 2],
+            hp_in: [0.0; 2],
+            hp_out: [0.0; 2],
+        }
+    }
+
+    pub fn config(&self) -> OutputFilter {
+        self.config
+    }
+
+    pub fn process(&mut self, samples: &mut [AudioSample]) {
+        for s in samples {
+            let mut out = [s.right, s.left];
+            for (ch, v) in out.iter_mut().enumerate() {
+                let x = *v as f64;
+                self.lp[ch] += self.lp_alpha * (x - self.lp[ch]);
+                let y = if self.config.dc_block.is_some() {
+                    self.hp_pole * (self.hp_out[ch] + self.lp[ch] - self.hp_in[ch])
+                } else {
+                    self.lp[ch]
+                };
+                self.hp_in[ch] = self.lp[ch];
+                self.hp_out[ch] = y;
+                *v = y.round().clamp(-32768.0, 32767.0) as i16;
+            }
+            *s = AudioSample::new(out[0], out[1]);
+        }
+    }
+}
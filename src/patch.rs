@@ -0,0 +1,676 @@
+//! Soft-patching of ROM images with IPS, UPS and BPS patches.
+
+use std::fmt;
+
+use crate::util::crc32;
+
+const IPS_MAGIC: &[u8] = b"PATCH";
+const IPS_EOF: &[u8] = b"EOF";
+const UPS_MAGIC: &[u8] = b"UPS1";
+const BPS_MAGIC: &[u8] = b"BPS1";
+
+// Source, target and patch CRC32 at the end of UPS and BPS patches
+const FOOTER_SIZE: usize = 12;
+
+// Largest output of UPS and BPS patches, the size of the cartridge space
+const MAX_TARGET_SIZE: usize = 32 * 1024 * 1024;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum PatchFormat {
+    Ips,
+    Ups,
+    Bps,
+}
+
+#[derive(Debug)]
+pub enum PatchError {
+    UnknownFormat,
+    /// The patch was made for another ROM
+    SourceMismatch {
+        expected: u32,
+        actual: u32,
+    },
+    /// The patched ROM differs from what the patch produces on its author's side
+    TargetMismatch {
+        expected: u32,
+        actual: u32,
+    },
+    Corrupted(String),
+}
+
+impl fmt::Display for PatchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PatchError::UnknownFormat => write!(f, "Unknown patch format"),
+            PatchError::SourceMismatch { expected, actual } => write!(
+                f,
+                "Patch is for another ROM: CRC32 {actual:08X}, expected {expected:08X}"
+            ),
+            PatchError::TargetMismatch { expected, actual } => write!(
+                f,
+                "Patched ROM has CRC32 {actual:08X}, expected {expected:08X}"
+            ),
+            PatchError::Corrupted(msg) => write!(f, "Patch is corrupted: {msg}"),
+        }
+    }
+}
+
+impl std::error::Error for PatchError {}
+
+pub fn detect_patch_format(patch: &[u8]) -> Option<PatchFormat> {
+    if patch.starts_with(IPS_MAGIC) {
+        Some(PatchFormat::Ips)
+    } else if patch.starts_with(UPS_MAGIC) {
+        Some(PatchFormat::Ups)
+    } else if patch.starts_with(BPS_MAGIC) {
+        Some(PatchFormat::Bps)
+    } else {
+        None
+    }
+}
+
+/// Applies `patch` to `rom`. The checksums of UPS and BPS patches are
+/// verified; IPS has none.
+pub fn apply_patch(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, PatchError> {
+    match detect_patch_format(patch).ok_or(PatchError::UnknownFormat)? {
+        PatchFormat::Ips => apply_ips(rom, patch),
+        PatchFormat::Ups => apply_ups(rom, patch),
+        PatchFormat::Bps => apply_bps(rom, patch),
+    }
+}
+
+fn corrupted(msg: &str) -> PatchError {
+    PatchError::Corrupted(msg.to_string())
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8], pos: usize) -> Self {
+        Self { data, pos }
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], PatchError> {
+        let end = self
+            .pos
+            .checked_add(len)
+            .filter(|&end| end <= self.data.len())
+            .ok_or_else(|| corrupted("unexpected end of patch"))?;
+        let ret = &self.data[self.pos..end];
+        self.pos = end;
+        Ok(ret)
+    }
+
+    fn u8(&mut self) -> Result<u8, PatchError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn be(&mut self, len: usize) -> Result<usize, PatchError> {
+        Ok(self.bytes(len)?.iter().fold(0, |a, &b| a << 8 | b as usize))
+    }
+
+    // Variable length integer of UPS and BPS
+    fn vlq(&mut self) -> Result<usize, PatchError> {
+        let mut ret = 0_usize;
+        let mut shift = 1_usize;
+        loop {
+            let b = self.u8()?;
+            ret = (b as usize & 0x7F)
+                .checked_mul(shift)
+                .and_then(|v| v.checked_add(ret))
+                .ok_or_else(|| corrupted("number too large"))?;
+            if b & 0x80 != 0 {
+                return Ok(ret);
+            }
+            shift = shift
+                .checked_shl(7)
+                .ok_or_else(|| corrupted("number too large"))?;
+            ret = ret
+                .checked_add(shift)
+                .ok_or_else(|| corrupted("number too large"))?;
+        }
+    }
+}
+
+fn apply_ips(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, PatchError> {
+    let mut ret = rom.to_vec();
+    let mut r = Reader::new(patch, IPS_MAGIC.len());
+
+    loop {
+        if patch.get(r.pos..r.pos + 3) == Some(IPS_EOF) {
+            r.pos += 3;
+            break;
+        }
+        let offset = r.be(3)?;
+        let (len, fill) = match r.be(2)? {
+            // Run of one byte
+            0 => (r.be(2)?, Some(r.u8()?)),
+            len => (len, None),
+        };
+        if ret.len() < offset + len {
+            ret.resize(offset + len, 0);
+        }
+        match fill {
+            Some(b) => ret[offset..offset + len].fill(b),
+            None => ret[offset..offset + len].copy_from_slice(r.bytes(len)?),
+        }
+    }
+
+    // Extension: the size the output is truncated to
+    if patch.len() == r.pos + 3 {
+        ret.truncate(r.be(3)?);
+    }
+    Ok(ret)
+}
+
+/// Checks the footer and returns the expected CRC32 of the output
+fn check_footer(rom: &[u8], patch: &[u8]) -> Result<u32, PatchError> {
+    if patch.len() < 4 + FOOTER_SIZE {
+        return Err(corrupted("too short"));
+    }
+    let footer = &patch[patch.len() - FOOTER_SIZE..];
+    let crc = |i: usize| u32::from_le_bytes(footer[i * 4..i * 4 + 4].try_into().unwrap());
+
+    if crc32(&patch[..patch.len() - 4]) != crc(2) {
+        return Err(corrupted("checksum mismatch"));
+    }
+    let actual = crc32(rom);
+    if actual != crc(0) {
+        return Err(PatchError::SourceMismatch {
+            expected: crc(0),
+            actual,
+        });
+    }
+    Ok(crc(1))
+}
+
+fn check_target_size(size: usize) -> Result<(), PatchError> {
+    if size > MAX_TARGET_SIZE {
+        return Err(corrupted("target size too large"));
+    }
+    Ok(())
+}
+
+fn check_target(target: &[u8], expected: u32) -> Result<(), PatchError> {
+    let actual = crc32(target);
+    if actual != expected {
+        return Err(PatchError::TargetMismatch { expected, actual });
+    }
+    Ok(())
+}
+
+fn apply_ups(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, PatchError> {
+    let target_crc = check_footer(rom, patch)?;
+    let end = patch.len() - FOOTER_SIZE;
+    let mut r = Reader::new(&patch[..end], UPS_MAGIC.len());
+
+    let source_size = r.vlq()?;
+    let target_size = r.vlq()?;
+    if source_size != rom.len() {
+        return Err(corrupted("source size mismatch"));
+    }
+    check_target_size(target_size)?;
+
+    let mut ret = rom.to_vec();
+    ret.resize(target_size, 0);
+
+    // Hunks of bytes XORed with the source, each preceded by the distance
+    // from the end of the previous one
+    let mut pos = 0_usize;
+    while r.pos < end {
+        pos = pos
+            .checked_add(r.vlq()?)
+            .ok_or_else(|| corrupted("write past the end of target"))?;
+        loop {
+            let b = r.u8()?;
+            if b == 0 {
+                pos += 1;
+                break;
+            }
+            *ret.get_mut(pos)
+                .ok_or_else(|| corrupted("write past the end of target"))? ^= b;
+            pos += 1;
+        }
+    }
+
+    check_target(&ret, target_crc)?;
+    Ok(ret)
+}
+
+fn apply_bps(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, PatchError> {
+    let target_crc = check_footer(rom, patch)?;
+    let end = patch.len() - FOOTER_SIZE;
+    let mut r = Reader::new(&patch[..end], BPS_MAGIC.len());
+
+    let source_size = r.vlq()?;
+    let target_size = r.vlq()?;
+    let metadata_size = r.vlq()?;
+    r.bytes(metadata_size)?;
+    if source_size != rom.len() {
+        return Err(corrupted("source size mismatch"));
+    }
+    check_target_size(target_size)?;
+
+    let mut ret = Vec::with_capacity(target_size);
+    let mut source_rel = 0_usize;
+    let mut target_rel = 0_usize;
+
+    let relative = |r: &mut Reader, base: usize| -> Result<usize, PatchError> {
+        let d = r.vlq()?;
+        let offset = d >> 1;
+        if d & 1 == 0 {
+            base.checked_add(offset)
+        } else {
+            base.checked_sub(offset)
+        }
+        .ok_or_else(|| corrupted("offset out of range"))
+    };
+
+    while r.pos < end {
+        let data = r.vlq()?;
+        let len = (data >> 2) + 1;
+        if len > target_size - ret.len() {
+            return Err(corrupted("output larger than target size"));
+        }
+        match data & 3 {
+            // SourceRead
+            0 => {
+                let pos = ret.len();
+                let src = pos
+                    .checked_add(len)
+                    .and_then(|end| rom.get(pos..end))
+                    .ok_or_else(|| corrupted("read past the end of source"))?;
+                ret.extend_from_slice(src);
+            }
+            // TargetRead
+            1 => ret.extend_from_slice(r.bytes(len)?),
+            // SourceCopy
+            2 => {
+                source_rel = relative(&mut r, source_rel)?;
+                let src = source_rel
+                    .checked_add(len)
+                    .and_then(|end| rom.get(source_rel..end))
+                    .ok_or_else(|| corrupted("copy past the end of source"))?;
+                ret.extend_from_slice(src);
+                source_rel += len;
+            }
+            // TargetCopy, may overlap the bytes being written
+            _ => {
+                target_rel = relative(&mut r, target_rel)?;
+                for _ in 0..len {
+                    let b = *ret
+                        .get(target_rel)
+                        .ok_or_else(|| corrupted("copy past the end of target"))?;
+                    ret.push(b);
+                    target_rel += 1;
+                }
+            }
+        }
+    }
+
+    if ret.len() != target_size {
+        return Err(corrupted("target size mismatch"));
+    }
+    check_target(&ret, target_crc)?;
+    Ok(ret)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vlq(out: &mut Vec<u8>, mut n: usize) {
+        loop {
+            let x = (n & 0x7F) as u8;
+            n >>= 7;
+            if n == 0 {
+                out.push(0x80 | x);
+                break;
+            }
+            out.push(x);
+            n -= 1;
+        }
+    }
+
+    fn footer(mut patch: Vec<u8>, source: &[u8], target: &[u8]) -> Vec<u8> {
+        patch.extend_from_slice(&crc32(source).to_le_bytes());
+        patch.extend_from_slice(&crc32(target).to_le_bytes());
+        patch.extend_from_slice(&crc32(&patch).to_le_bytes());
+        patch
+    }
+
+    fn source() -> Vec<u8> {
+        (0..32).collect()
+    }
+
+    fn ups(source: &[u8], target: &[u8]) -> Vec<u8> {
+        let mut patch = UPS_MAGIC.to_vec();
+        vlq(&mut patch, source.len());
+        vlq(&mut patch, target.len());
+        let mut last = 0;
+        let mut i = 0;
+        while i < target.len() {
+            let src = |i: usize| source.get(i).copied().unwrap_or(0);
+            if src(i) == target[i] {
+                i += 1;
+                continue;
+            }
+            vlq(&mut patch, i - last);
+            while i < target.len() && src(i) != target[i] {
+                patch.push(src(i) ^ target[i]);
+                i += 1;
+            }
+            patch.push(0);
+            i += 1;
+            last = i;
+        }
+        footer(patch, source, target)
+    }
+
+    #[test]
+    fn detect() {
+        assert_eq!(detect_patch_format(b"PATCHEOF"), Some(PatchFormat::Ips));
+        assert_eq!(detect_patch_format(b"UPS1"), Some(PatchFormat::Ups));
+        assert_eq!(detect_patch_format(b"BPS1"), Some(PatchFormat::Bps));
+        assert_eq!(detect_patch_format(b"PK\x03\x04"), None);
+        assert!(matches!(
+            apply_patch(&[], b"junk"),
+            Err(PatchError::UnknownFormat)
+        ));
+    }
+
+    #[test]
+    fn ips() {
+        let mut patch = IPS_MAGIC.to_vec();
+        // Two bytes at 0x04
+        patch.extend_from_slice(&[0x00, 0x00, 0x04, 0x00, 0x02, 0xAA, 0xBB]);
+        // Run of four 0xCC at 0x1E, growing the ROM
+        patch.extend_from_slice(&[0x00, 0x00, 0x1E, 0x00, 0x00, 0x00, 0x04, 0xCC]);
+        patch.extend_from_slice(IPS_EOF);
+
+        let mut expected = source();
+        expected[4..6].copy_from_slice(&[0xAA, 0xBB]);
+        expected.truncate(0x1E);
+        expected.extend_from_slice(&[0xCC; 4]);
+        assert_eq!(apply_patch(&source(), &patch).unwrap(), expected);
+
+        // Truncation extension
+        patch.extend_from_slice(&[0x00, 0x00, 0x08]);
+        assert_eq!(apply_patch(&source(), &patch).unwrap(), expected[..8]);
+    }
+
+    #[test]
+    fn ips_truncated() {
+        let mut patch = IPS_MAGIC.to_vec();
+        patch.extend_from_slice(&[0x00, 0x00, 0x04, 0x00, 0x04, 0xAA]);
+        assert!(matches!(
+            apply_patch(&source(), &patch),
+            Err(PatchError::Corrupted(_))
+        ));
+        // Missing EOF marker
+        let patch = IPS_MAGIC.to_vec();
+        assert!(matches!(
+            apply_patch(&source(), &patch),
+            Err(PatchError::Corrupted(_))
+        ));
+    }
+
+    #[test]
+    fn ups_round_trip() {
+        let mut target = source();
+        target[3] = 0xFF;
+        target[10..14].fill(0x55);
+        target.extend_from_slice(b"tail");
+        let patch = ups(&source(), &target);
+        assert_eq!(apply_patch(&source(), &patch).unwrap(), target);
+    }
+
+    #[test]
+    fn ups_checksum_mismatch() {
+        let mut target = source();
+        target[0] = 0xFF;
+        let mut patch = ups(&source(), &target);
+
+        // Wrong source ROM
+        let mut other = source();
+        other[31] = 0;
+        assert!(matches!(
+            apply_patch(&other, &patch),
+            Err(PatchError::SourceMismatch { .. })
+        ));
+
+        // Damaged patch
+        patch[6] ^= 1;
+        assert!(matches!(
+            apply_patch(&source(), &patch),
+            Err(PatchError::Corrupted(_))
+        ));
+    }
+
+    #[test]
+    fn ups_target_mismatch() {
+        let mut target = source();
+        target[0] = 0xFF;
+        let mut patch = UPS_MAGIC.to_vec();
+        vlq(&mut patch, 32);
+        vlq(&mut patch, 32);
+        vlq(&mut patch, 0);
+        patch.extend_from_slice(&[0x01, 0x00]);
+        // Footer claims a different target than the hunks produce
+        let patch = footer(patch, &source(), &target);
+        assert!(matches!(
+            apply_patch(&source(), &patch),
+            Err(PatchError::TargetMismatch { .. })
+        ));
+    }
+
+    #[test]
+    fn ups_truncated() {
+        let patch = footer(UPS_MAGIC.to_vec(), &source(), &source());
+        assert!(matches!(
+            apply_patch(&source(), &patch[..8]),
+            Err(PatchError::Corrupted(_))
+        ));
+        // Hunk without a terminating zero
+        let mut patch = UPS_MAGIC.to_vec();
+        vlq(&mut patch, 32);
+        vlq(&mut patch, 32);
+        vlq(&mut patch, 0);
+        patch.push(0x01);
+        let patch = footer(patch, &source(), &source());
+        assert!(matches!(
+            apply_patch(&source(), &patch),
+            Err(PatchError::Corrupted(_))
+        ));
+    }
+
+    #[test]
+    fn ups_write_past_end() {
+        let mut patch = UPS_MAGIC.to_vec();
+        vlq(&mut patch, 32);
+        vlq(&mut patch, 32);
+        vlq(&mut patch, 40);
+        patch.extend_from_slice(&[0x01, 0x00]);
+        let patch = footer(patch, &source(), &source());
+        assert!(matches!(
+            apply_patch(&source(), &patch),
+            Err(PatchError::Corrupted(_))
+        ));
+    }
+
+    #[test]
+    fn ups_offset_overflow() {
+        let mut patch = UPS_MAGIC.to_vec();
+        vlq(&mut patch, 32);
+        vlq(&mut patch, 32);
+        vlq(&mut patch, 0);
+        patch.extend_from_slice(&[0x01, 0x00]);
+        vlq(&mut patch, usize::MAX - 1);
+        patch.extend_from_slice(&[0x01, 0x00]);
+        let patch = footer(patch, &source(), &source());
+        assert!(matches!(
+            apply_patch(&source(), &patch),
+            Err(PatchError::Corrupted(msg)) if msg == "write past the end of target"
+        ));
+    }
+
+    #[test]
+    fn target_too_large() {
+        let mut patch = UPS_MAGIC.to_vec();
+        vlq(&mut patch, 32);
+        vlq(&mut patch, usize::MAX);
+        let patch = footer(patch, &source(), &source());
+        assert!(matches!(
+            apply_patch(&source(), &patch),
+            Err(PatchError::Corrupted(msg)) if msg == "target size too large"
+        ));
+
+        let mut patch = BPS_MAGIC.to_vec();
+        vlq(&mut patch, 32);
+        vlq(&mut patch, MAX_TARGET_SIZE + 1);
+        vlq(&mut patch, 0);
+        let patch = footer(patch, &source(), &source());
+        assert!(matches!(
+            apply_patch(&source(), &patch),
+            Err(PatchError::Corrupted(msg)) if msg == "target size too large"
+        ));
+    }
+
+    #[test]
+    fn vlq_overflow() {
+        let mut patch = UPS_MAGIC.to_vec();
+        patch.extend_from_slice(&[0x7F; 16]);
+        patch.push(0x80);
+        let patch = footer(patch, &source(), &source());
+        assert!(matches!(
+            apply_patch(&source(), &patch),
+            Err(PatchError::Corrupted(msg)) if msg == "number too large"
+        ));
+    }
+
+    fn bps(target: &[u8], actions: impl FnOnce(&mut Vec<u8>)) -> Vec<u8> {
+        let mut patch = BPS_MAGIC.to_vec();
+        vlq(&mut patch, 32);
+        vlq(&mut patch, target.len());
+        vlq(&mut patch, 4);
+        patch.extend_from_slice(b"meta");
+        actions(&mut patch);
+        footer(patch, &source(), target)
+    }
+
+    fn action(patch: &mut Vec<u8>, kind: usize, len: usize) {
+        vlq(patch, (len - 1) << 2 | kind);
+    }
+
+    #[test]
+    fn bps_all_actions() {
+        let mut target = source()[..8].to_vec();
+        target.extend_from_slice(&source()[20..24]);
+        target.extend_from_slice(b"newnewnewn");
+
+        let patch = bps(&target, |p| {
+            // SourceRead
+            action(p, 0, 8);
+            // SourceCopy from +20
+            action(p, 2, 4);
+            vlq(p, 20 << 1);
+            // TargetRead
+            action(p, 1, 3);
+            p.extend_from_slice(b"new");
+            // TargetCopy of "new" overlapping itself, from +12
+            action(p, 3, 7);
+            vlq(p, 12 << 1);
+        });
+        assert_eq!(apply_patch(&source(), &patch).unwrap(), target);
+    }
+
+    #[test]
+    fn bps_negative_offset() {
+        let mut target = source()[20..24].to_vec();
+        target.extend_from_slice(&source()[16..20]);
+        let patch = bps(&target, |p| {
+            action(p, 2, 4);
+            vlq(p, 20 << 1);
+            // Back 8 from the end of the previous copy
+            action(p, 2, 4);
+            vlq(p, 8 << 1 | 1);
+        });
+        assert_eq!(apply_patch(&source(), &patch).unwrap(), target);
+    }
+
+    #[test]
+    fn bps_out_of_range() {
+        let target = vec![0; 4];
+        let patch = bps(&target, |p| {
+            action(p, 2, 4);
+            vlq(p, 1 << 1 | 1);
+        });
+        assert!(matches!(
+            apply_patch(&source(), &patch),
+            Err(PatchError::Corrupted(msg)) if msg == "offset out of range"
+        ));
+
+        let patch = bps(&target, |p| {
+            action(p, 2, 4);
+            vlq(p, 30 << 1);
+        });
+        assert!(matches!(
+            apply_patch(&source(), &patch),
+            Err(PatchError::Corrupted(msg)) if msg == "copy past the end of source"
+        ));
+    }
+
+    #[test]
+    fn bps_length_overflow() {
+        let target = vec![0; 4];
+        // Metadata longer than the address space
+        let mut patch = BPS_MAGIC.to_vec();
+        vlq(&mut patch, 32);
+        vlq(&mut patch, 4);
+        vlq(&mut patch, usize::MAX);
+        let patch = footer(patch, &source(), &target);
+        assert!(matches!(
+            apply_patch(&source(), &patch),
+            Err(PatchError::Corrupted(msg)) if msg == "unexpected end of patch"
+        ));
+
+        // Copy far past the end of the output
+        let patch = bps(&target, |p| {
+            vlq(p, usize::MAX & !3 | 3);
+            vlq(p, 0);
+        });
+        assert!(matches!(
+            apply_patch(&source(), &patch),
+            Err(PatchError::Corrupted(msg)) if msg == "output larger than target size"
+        ));
+    }
+
+    #[test]
+    fn bps_size_mismatch() {
+        let target = source()[..4].to_vec();
+        let patch = bps(&target, |p| action(p, 0, 8));
+        assert!(matches!(
+            apply_patch(&source(), &patch),
+            Err(PatchError::Corrupted(msg)) if msg == "output larger than target size"
+        ));
+
+        let patch = bps(&target, |p| action(p, 0, 2));
+        assert!(matches!(
+            apply_patch(&source(), &patch),
+            Err(PatchError::Corrupted(msg)) if msg == "target size mismatch"
+        ));
+    }
+
+    #[test]
+    fn bps_source_mismatch() {
+        let target = source();
+        let patch = bps(&target, |p| action(p, 0, 32));
+        assert!(matches!(
+            apply_patch(&source()[..16], &patch),
+            Err(PatchError::SourceMismatch { .. })
+        ));
+    }
+}
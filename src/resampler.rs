@@ -0,0 +1,124 @@
+//! Band-limited conversion of the mixer output to the host sample rate.
+//!
+//! The mixer runs at 1 MiHz and its output is a staircase. Each step is
+//! added to the output as a band-limited step (windowed sinc), so content
+//! above the output Nyquist frequency is removed instead of aliasing.
+
+use std::f64::consts::PI;
+
+use crate::interface::AudioSample;
+
+// Taps per step, output latency is half of this
+const WIDTH: usize = 16;
+const PHASE_BITS: u32 = 6;
+const PHASES: usize = 1 << PHASE_BITS;
+
+const FRAC_BITS: u32 = 32;
+const ONE: u64 = 1 << FRAC_BITS;
+
+// Audible range kept at high output rates
+const MAX_CUTOFF_HZ: f64 = 20000.0;
+
+pub struct Resampler {
+    in_rate: u32,
+    out_rate: u32,
+    // Output samples per input sample and time since the last output sample,
+    // both 32.32 fixed point
+    step: u64,
+    time: u64,
+    last: [i32; 2],
+    // Sum of the deltas emitted so far
+    level: [f64; 2],
+    // Deltas pending for the next WIDTH output samples
+    pending: [[f64; 2]; WIDTH],
+    kernel: Vec<[f64; WIDTH]>,
+}
+
+impl Resampler {
+    pub fn new(in_rate: u32, out_rate: u32) -> Self {
+        Self {
+            in_rate,
+            out_rate,
+            step: ((out_rate as u64) << FRAC_BITS) / in_rate as u64,
+            time: 0,
+            last: [0; 2],
+            level: [0.0; 2],
+            pending: [[0.0; 2]; WIDTH],
+            kernel: make_kernel(out_rate),
+        }
+    }
+
+    pub fn out_rate(&self) -> u32 {
+        self.out_rate
+    }
+
+    pub fn set_out_rate(&mut self, out_rate: u32) {
+        *self = Self::new(self.in_rate, out_rate);
+    }
+
+    /// Feeds one input sample, appending any completed output samples to `out`
+    pub fn push(&mut self, sample: [i32; 2], out: &mut Vec<AudioSample>) {
+        if sample != self.last {
+            let phase = ((self.time & (ONE - 1)) >> (FRAC_BITS - PHASE_BITS)) as usize;
+            let taps = &self.kernel[phase];
+            for ch in 0..2 {
+                let delta = (sample[ch] - self.last[ch]) as f64;
+                for (p, t) in self.pending.iter_mut().zip(taps) {
+                    p[ch] += delta * t;
+                }
+            }
+            self.last = sample;
+        }
+
+        self.time += self.step;
+        while self.time >= ONE {
+            self.time -= ONE;
+
+            for ch in 0..2 {
+                self.level[ch] += self.pending[0][ch];
+            }
+            self.pending.rotate_left(1);
+            self.pending[WIDTH - 1] = [0.0; 2];
+
+            let [right, left] = self
+                .level
+                .map(|v| v.round().clamp(-32768.0, 32767.0) as i16);
+            out.push(AudioSample::new(right, left));
+        }
+    }
+}
+
+// For each sub-sample phase, the amount of a unit step that arrives at each
+// of the next WIDTH output samples
+fn make_kernel(out_rate: u32) -> Vec<[f64; WIDTH]> {
+    let cutoff = (MAX_CUTOFF_HZ / out_rate as f64).min(0.45);
+    let half = WIDTH as f64 / 2.0;
+
+    let impulse = |x: f64| {
+        if x.abs() >= half {
+            return 0.0;
+        }
+        let sinc = if x == 0.0 {
+            2.0 * cutoff
+        } else {
+            (2.0 * PI * cutoff * x).sin() / (PI * x)
+        };
+        // Blackman window
+        let w = (x + half) / (2.0 * half);
+        let window = 0.42 - 0.5 * (2.0 * PI * w).cos() + 0.08 * (4.0 * PI * w).cos();
+        sinc * window
+    };
+
+    (0..PHASES)
+        .map(|phase| {
+            let frac = phase as f64 / PHASES as f64;
+            let mut taps = [0.0; WIDTH];
+            for (i, t) in taps.iter_mut().enumerate() {
+                *t = impulse(i as f64 + 1.0 - half - frac);
+            }
+            // Steps must settle at exactly their height
+            let sum: f64 = taps.iter().sum();
+            taps.map(|t| t / sum)
+        })
+        .collect()
+}
@@ -0,0 +1,164 @@
+use std::collections::VecDeque;
+
+#[derive(Clone, Debug)]
+pub struct RewindConfig {
+    /// Capture a snapshot every `interval` frames
+    pub interval: u32,
+    /// Upper bound of memory used by the snapshots, in bytes
+    pub memory_budget: usize,
+}
+
+impl Default for RewindConfig {
+    fn default() -> Self {
+        Self {
+            interval: 2,
+            memory_budget: 64 * 1024 * 1024,
+        }
+    }
+}
+
+/// Snapshots of the emulator state. Only the newest one is kept in full;
+/// older ones are stored as a sparse XOR delta against their successor, since
+/// most of EWRAM / VRAM does not change between frames.
+pub struct RewindBuffer {
+    config: RewindConfig,
+    counter: u32,
+    latest: Option<(u64, Vec<u8>)>,
+    // Oldest first. Each delta reconstructs its snapshot from the next newer one.
+    deltas: VecDeque<(u64, Vec<u8>)>,
+    delta_bytes: usize,
+}
+
+impl RewindBuffer {
+    pub fn new(config: RewindConfig) -> Self {
+        Self {
+            config,
+            counter: 0,
+            latest: None,
+            deltas: VecDeque::new(),
+            delta_bytes: 0,
+        }
+    }
+
+    pub fn config(&self) -> &RewindConfig {
+        &self.config
+    }
+
+    /// Number of snapshots held
+    pub fn len(&self) -> usize {
+        self.deltas.len() + self.latest.is_some() as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.latest.is_none()
+    }
+
+    pub fn memory_usage(&self) -> usize {
+        self.delta_bytes + self.latest.as_ref().map_or(0, |(_, s)| s.len())
+    }
+
+    pub fn clear(&mut self) {
+        self.counter = 0;
+        self.latest = None;
+        self.deltas.clear();
+        self.delta_bytes = 0;
+    }
+
+    /// Counts a frame, returns true when a snapshot should be captured.
+    pub fn tick(&mut self) -> bool {
+        self.counter += 1;
+        if self.counter >= self.config.interval.max(1) {
+            self.counter = 0;
+            true
+        } else {
+            false
+        }
+    }
+
+    pub fn push(&mut self, frame: u64, snapshot: Vec<u8>) {
+        if let Some((prev_frame, prev)) = self.latest.take() {
+            let delta = encode_delta(&snapshot, &prev);
+            self.delta_bytes += delta.len();
+            self.deltas.push_back((prev_frame, delta));
+        }
+        self.latest = Some((frame, snapshot));
+
+        while self.memory_usage() > self.config.memory_budget {
+            match self.deltas.pop_front() {
+                Some((_, delta)) => self.delta_bytes -= delta.len(),
+                None => break,
+            }
+        }
+    }
+
+    /// Discards snapshots newer than `frame` and returns the newest remaining
+    /// one (or the oldest held, if none is old enough).
+    pub fn rewind_to(&mut self, frame: u64) -> Option<(u64, Vec<u8>)> {
+        loop {
+            let (latest_frame, latest) = self.latest.as_ref()?;
+            if *latest_frame <= frame {
+                break;
+            }
+            let Some((prev_frame, delta)) = self.deltas.pop_back() else {
+                break;
+            };
+            self.delta_bytes -= delta.len();
+            let prev = apply_delta(latest, &delta);
+            self.latest = Some((prev_frame, prev));
+        }
+        self.counter = 0;
+        self.latest.clone()
+    }
+}
+
+// Layout: older_len: u32, then runs of (skip: u32, len: u32, xor bytes)
+fn encode_delta(newer: &[u8], older: &[u8]) -> Vec<u8> {
+    let mut ret = (older.len() as u32).to_le_bytes().to_vec();
+
+    let xor = |i: usize| older[i] ^ newer.get(i).copied().unwrap_or(0);
+
+    let mut pos = 0;
+    let mut i = 0;
+    while i < older.len() {
+        if xor(i) == 0 {
+            i += 1;
+            continue;
+        }
+        let start = i;
+        // Short runs of equal bytes are cheaper to include than a new header
+        let mut zeros = 0;
+        while i < older.len() && zeros < 8 {
+            zeros = if xor(i) == 0 { zeros + 1 } else { 0 };
+            i += 1;
+        }
+        let end = i - zeros;
+
+        ret.extend_from_slice(&((start - pos) as u32).to_le_bytes());
+        ret.extend_from_slice(&((end - start) as u32).to_le_bytes());
+        ret.extend((start..end).map(xor));
+        pos = end;
+    }
+    ret
+}
+
+fn apply_delta(newer: &[u8], delta: &[u8]) -> Vec<u8> {
+    let read_u32 = |p: usize| u32::from_le_bytes(delta[p..p + 4].try_into().unwrap()) as usize;
+
+    let older_len = read_u32(0);
+    let mut ret = newer[..older_len.min(newer.len())].to_vec();
+    ret.resize(older_len, 0);
+
+    let mut p = 4;
+    let mut pos = 0;
+    while p < delta.len() {
+        let start = pos + read_u32(p);
+        let len = read_u32(p + 4);
+        p += 8;
+        for (b, x) in ret[start..start + len].iter_mut().zip(&delta[p..p + len]) {
+            *b ^= x;
+        }
+        p += len;
+        pos = start + len;
+    }
+    ret
+}
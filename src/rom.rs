@@ -1,7 +1,84 @@
-use anyhow::{bail, Result};
+use anyhow::{bail, Context as _, Result};
 use log::error;
+use sha1::{Digest, Sha1};
+use std::path::Path;
 
-use crate::backup::Backup;
+use crate::{archive::extract_rom, backup::Backup, patch::apply_patch, util::crc32};
+
+const HEADER_SIZE: usize = 0xC0;
+const LOGO_RANGE: std::ops::Range<usize> = 0x04..0xA0;
+const FIXED_VALUE: u8 = 0x96;
+
+// Compressed bitmap checked by the BIOS on boot
+const NINTENDO_LOGO: [u8; 156] = [
+    0x24, 0xFF, 0xAE, 0x51, 0x69, 0x9A, 0xA2, 0x21, 0x3D, 0x84, 0x82, 0x0A, 0x84, 0xE4, 0x09, 0xAD,
+    0x11, 0x24, 0x8B, 0x98, 0xC0, 0x81, 0x7F, 0x21, 0xA3, 0x52, 0xBE, 0x19, 0x93, 0x09, 0xCE, 0x20,
+    0x10, 0x46, 0x4A, 0x4A, 0xF8, 0x27, 0x31, 0xEC, 0x58, 0xC7, 0xE8, 0x33, 0x82, 0xE3, 0xCE, 0xBF,
+    0x85, 0xF4, 0xDF, 0x94, 0xCE, 0x4B, 0x09, 0xC1, 0x94, 0x56, 0x8A, 0xC0, 0x13, 0x72, 0xA7, 0xFC,
+    0x9F, 0x84, 0x4D, 0x73, 0xA3, 0xCA, 0x9A, 0x61, 0x58, 0x97, 0xA3, 0x27, 0xFC, 0x03, 0x98, 0x76,
+    0x23, 0x1D, 0xC7, 0x61, 0x03, 0x04, 0xAE, 0x56, 0xBF, 0x38, 0x84, 0x00, 0x40, 0xA7, 0x0E, 0xFD,
+    0xFF, 0x52, 0xFE, 0x03, 0x6F, 0x95, 0x30, 0xF1, 0x97, 0xFB, 0xC0, 0x85, 0x60, 0xD6, 0x80, 0x25,
+    0xA9, 0x63, 0xBE, 0x03, 0x01, 0x4E, 0x38, 0xE2, 0xF9, 0xA2, 0x34, 0xFF, 0xBB, 0x3E, 0x03, 0x44,
+    0x78, 0x00, 0x90, 0xCB, 0x88, 0x11, 0x3A, 0x94, 0x65, 0xC0, 0x7C, 0x63, 0x87, 0xF0, 0x3C, 0xAF,
+    0xD6, 0x25, 0xE4, 0x8B, 0x38, 0x0A, 0xAC, 0x72, 0x21, 0xD4, 0xF8, 0x07,
+];
+
+/// Region, from the last character of the game code
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Region {
+    Japan,
+    NorthAmerica,
+    Europe,
+    Germany,
+    France,
+    Italy,
+    Spain,
+    Netherlands,
+    Australia,
+    Korea,
+    China,
+    Unknown(u8),
+}
+
+impl Region {
+    pub fn from_game_code(game_code: &[u8; 4]) -> Self {
+        match game_code[3] {
+            b'J' => Region::Japan,
+            b'E' => Region::NorthAmerica,
+            b'P' | b'X' | b'Y' | b'Z' => Region::Europe,
+            b'D' => Region::Germany,
+            b'F' => Region::France,
+            b'I' => Region::Italy,
+            b'S' => Region::Spain,
+            b'H' => Region::Netherlands,
+            b'U' => Region::Australia,
+            b'K' => Region::Korea,
+            b'C' => Region::China,
+            c => Region::Unknown(c),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct RomInfo {
+    pub title: String,
+    pub game_code: [u8; 4],
+    pub maker_code: [u8; 2],
+    pub region: Region,
+    pub main_unit_code: u8,
+    pub device_type: u8,
+    pub version: u8,
+    /// The BIOS refuses to boot ROMs without the Nintendo logo
+    pub logo_valid: bool,
+    /// The BIOS also checks the fixed value 0x96 at 0xB2
+    pub fixed_value_valid: bool,
+    /// Complement check byte at 0xBD
+    pub header_checksum: u8,
+    pub header_checksum_valid: bool,
+    pub size: usize,
+    pub crc32: u32,
+    pub sha1: [u8; 20],
+}
 
 #[derive(Default, Clone)]
 pub struct Rom {
@@ -16,15 +93,22 @@ pub struct Rom {
 
 impl Rom {
     pub fn from_bytes(data: &[u8]) -> Result<Self> {
-        let header = &data[0xA0..0xC0];
+        if data.len() < HEADER_SIZE {
+            bail!(
+                "ROM is too small: {} bytes, expected at least {HEADER_SIZE} bytes",
+                data.len()
+            );
+        }
+        let header = &data[0xA0..HEADER_SIZE];
 
         let title = header[..0xC].to_vec();
         let game_code = header[0xC..0x10].try_into()?;
         let maker_code = header[0x10..0x12].try_into()?;
 
+        // Many homebrew builds leave the header blank, see `fix_header`
         let magic = header[0x12];
-        if magic != 0x96 {
-            bail!("Invalid magic number: 0x{magic:02X}, expected: 0x96");
+        if magic != FIXED_VALUE {
+            error!("Invalid fixed value: 0x{magic:02X}, expected 0x{FIXED_VALUE:02X}");
         }
 
         let main_unit_code = header[0x13];
@@ -39,13 +123,9 @@ impl Rom {
             }
         }
 
-        let sum = header[..=0x1C]
-            .iter()
-            .fold(0_u8, |a, b| a.wrapping_add(*b))
-            .wrapping_add(0x19);
-
-        if sum.wrapping_add(complement_check) != 0 {
-            error!("Invalid complement check: {sum:02X} + {complement_check:02X} != 0");
+        let expected = header_checksum(data);
+        if complement_check != expected {
+            error!("Invalid complement check: {complement_check:02X}, expected {expected:02X}");
         }
 
         let ret = Rom {
@@ -61,7 +141,164 @@ impl Rom {
         Ok(ret)
     }
 
+    /// Hashes the whole ROM; `Agb::rom_info` returns a cached copy
+    pub fn info(&self) -> RomInfo {
+        // An empty cartridge slot reads as no header at all
+        let header = self.data.get(..HEADER_SIZE);
+        let header_checksum = header.map_or(0, |h| h[0xBD]);
+        RomInfo {
+            title: String::from_utf8_lossy(&self.title)
+                .trim_end_matches('\0')
+                .to_string(),
+            game_code: self.game_code,
+            maker_code: self.maker_code,
+            region: Region::from_game_code(&self.game_code),
+            main_unit_code: self.main_unit_code,
+            device_type: self.device_type,
+            version: self.rom_version,
+            logo_valid: header.is_some_and(|h| h[LOGO_RANGE] == NINTENDO_LOGO),
+            fixed_value_valid: header.is_some_and(|h| h[0xB2] == FIXED_VALUE),
+            header_checksum,
+            header_checksum_valid: header.is_some_and(|h| h[0xBD] == self::header_checksum(h)),
+            size: self.data.len(),
+            crc32: crc32(&self.data),
+            sha1: Sha1::digest(&self.data).into(),
+        }
+    }
+
+    /// Writes the Nintendo logo, the fixed value and the complement check
+    /// into the header of `data`, as needed for homebrew builds to boot
+    /// through the BIOS. Returns whether anything changed.
+    pub fn fix_header(data: &mut [u8]) -> Result<bool> {
+        if data.len() < HEADER_SIZE {
+            bail!(
+                "ROM is too small: {} bytes, expected at least {HEADER_SIZE} bytes",
+                data.len()
+            );
+        }
+        let orig = data[..HEADER_SIZE].to_vec();
+
+        data[LOGO_RANGE].copy_from_slice(&NINTENDO_LOGO);
+        data[0xB2] = FIXED_VALUE;
+        data[0xBD] = header_checksum(data);
+
+        Ok(data[..HEADER_SIZE] != orig[..])
+    }
+
+    /// Accepts a plain ROM image or a .zip, .gz or .7z archive containing one
+    pub fn from_archive(data: &[u8]) -> Result<Self> {
+        Self::from_bytes(&extract_rom(data)?)
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let data =
+            std::fs::read(path).with_context(|| format!("Failed to read {}", path.display()))?;
+        Self::from_archive(&data)
+    }
+
+    /// Returns the ROM with an IPS, UPS or BPS patch applied
+    pub fn patched(&self, patch: &[u8]) -> Result<Self> {
+        Self::from_bytes(&apply_patch(&self.data, patch)?)
+    }
+
     pub fn backup_type(&self) -> &'static str {
         Backup::detect_backup(&self.data, None).backup_type()
     }
 }
+
+/// Complement check of the header at 0xA0..=0xBC
+fn header_checksum(data: &[u8]) -> u8 {
+    let sum = data[0xA0..=0xBC]
+        .iter()
+        .fold(0_u8, |a, b| a.wrapping_add(*b))
+        .wrapping_add(0x19);
+    0_u8.wrapping_sub(sum)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(title: &[u8], game_code: &[u8; 4]) -> Vec<u8> {
+        let mut data = vec![0; HEADER_SIZE];
+        data[0xA0..0xA0 + title.len()].copy_from_slice(title);
+        data[0xAC..0xB0].copy_from_slice(game_code);
+        data[0xB0..0xB2].copy_from_slice(b"01");
+        data[0xBC] = 1;
+        data
+    }
+
+    #[test]
+    fn fix_blank_header() {
+        let mut data = vec![0; HEADER_SIZE];
+        assert!(Rom::fix_header(&mut data).unwrap());
+        assert_eq!(data[LOGO_RANGE], NINTENDO_LOGO);
+        assert_eq!(data[0xB2], 0x96);
+        // -(0x96 + 0x19)
+        assert_eq!(data[0xBD], 0x51);
+        // Nothing left to fix
+        assert!(!Rom::fix_header(&mut data).unwrap());
+
+        let info = Rom::from_bytes(&data).unwrap().info();
+        assert!(info.logo_valid);
+        assert!(info.fixed_value_valid);
+        assert!(info.header_checksum_valid);
+        assert_eq!(info.header_checksum, 0x51);
+
+        assert!(Rom::fix_header(&mut [0; HEADER_SIZE - 1]).is_err());
+    }
+
+    #[test]
+    fn unfixed_header_loads() {
+        let data = header(b"HOMEBREW", b"HBRE");
+        let info = Rom::from_bytes(&data).unwrap().info();
+        assert!(!info.logo_valid);
+        assert!(!info.fixed_value_valid);
+        assert!(!info.header_checksum_valid);
+
+        assert!(Rom::from_bytes(&data[..HEADER_SIZE - 1]).is_err());
+    }
+
+    #[test]
+    fn rom_info() {
+        let mut data = header(b"POKEMON EMER", b"BPEE");
+        Rom::fix_header(&mut data).unwrap();
+        data.resize(0x400, 0xFF);
+        let info = Rom::from_bytes(&data).unwrap().info();
+
+        assert_eq!(info.title, "POKEMON EMER");
+        assert_eq!(&info.game_code, b"BPEE");
+        assert_eq!(&info.maker_code, b"01");
+        assert_eq!(info.region, Region::NorthAmerica);
+        assert_eq!(info.version, 1);
+        assert_eq!(info.size, 0x400);
+        assert_eq!(info.crc32, crc32(&data));
+        assert!(info.header_checksum_valid);
+
+        // Padding is not part of the title
+        let data = header(b"SHORT", b"SHTJ");
+        assert_eq!(Rom::from_bytes(&data).unwrap().info().title, "SHORT");
+    }
+
+    #[test]
+    fn region() {
+        for (code, region) in [
+            (b"AXVJ", Region::Japan),
+            (b"AXVE", Region::NorthAmerica),
+            (b"AXVP", Region::Europe),
+            (b"AXVX", Region::Europe),
+            (b"AXVD", Region::Germany),
+            (b"AXVF", Region::France),
+            (b"AXVI", Region::Italy),
+            (b"AXVS", Region::Spain),
+            (b"AXVH", Region::Netherlands),
+            (b"AXVU", Region::Australia),
+            (b"AXVK", Region::Korea),
+            (b"AXVC", Region::China),
+            (b"AXVQ", Region::Unknown(b'Q')),
+        ] {
+            assert_eq!(Region::from_game_code(code), region);
+        }
+    }
+}
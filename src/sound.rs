@@ -8,11 +8,51 @@ use crate::{
     consts::AUDIO_SAMPLES_PER_SECOND,
     context::{Interrupt, SoundDma, Timing},
     interface::{AudioBuf, AudioSample},
+    mp2k::Mp2kMixer,
+    output_filter::{FilterState, OutputFilter},
+    resampler::Resampler,
     util::{pack, trait_alias},
+    vgm::{dmg_register, VgmRecorder, DMG_WAVE_RAM},
 };
 
 trait_alias!(pub trait Context = Timing + SoundDma + Interrupt);
 
+// Rate of `tick_1m`
+const TICKS_PER_SECOND: u32 = 1024 * 1024;
+
+pub const MAX_AUDIO_SAMPLE_RATE: u32 = TICKS_PER_SECOND / 2;
+
+// Range of the PWM output, the mixer output plus the bias is clipped to it
+const PWM_MAX: i32 = 0x3FF;
+
+// The mixer output is 64x the PWM level
+const PWM_SCALE: i32 = 64;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum AudioChannel {
+    Pulse1,
+    Pulse2,
+    Wave,
+    Noise,
+    DirectSoundA,
+    DirectSoundB,
+}
+
+impl AudioChannel {
+    pub const ALL: [AudioChannel; 6] = [
+        AudioChannel::Pulse1,
+        AudioChannel::Pulse2,
+        AudioChannel::Wave,
+        AudioChannel::Noise,
+        AudioChannel::DirectSoundA,
+        AudioChannel::DirectSoundB,
+    ];
+
+    fn bit(self) -> u8 {
+        1 << self as u8
+    }
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct Sound {
     power_on: bool,
@@ -32,10 +72,38 @@ pub struct Sound {
     prev_clock: u64,
     freq_counter: u64,
     frame_counter: u64,
+    // No longer used, kept for save state compatibility
     sampling_counter: u32,
 
     #[serde(skip)]
     audio_buffer: AudioBuf,
+    #[serde(skip, default = "default_resampler")]
+    resampler: Resampler,
+    #[serde(skip)]
+    filter: Option<FilterState>,
+    // Replaces the DirectSound output of games using the MP2K driver
+    #[serde(skip)]
+    mp2k: Option<Mp2kMixer>,
+    #[serde(skip)]
+    vgm: Option<VgmRecorder>,
+
+    // PWM sample-and-hold, only affects the host-side output
+    #[serde(skip)]
+    pwm_counter: u32,
+    #[serde(skip)]
+    pwm_output: [i32; 2],
+
+    // Debugging aids, one bit per `AudioChannel`
+    #[serde(skip)]
+    muted: u8,
+    #[serde(skip)]
+    solo: u8,
+    #[serde(skip)]
+    channel_buffers: bool,
+}
+
+fn default_resampler() -> Resampler {
+    Resampler::new(TICKS_PER_SECOND, AUDIO_SAMPLES_PER_SECOND)
 }
 
 impl Sound {
@@ -60,6 +128,187 @@ impl Sound {
             frame_counter: 0,
             sampling_counter: 0,
             audio_buffer: Default::default(),
+            resampler: default_resampler(),
+            filter: None,
+            mp2k: None,
+            vgm: None,
+            pwm_counter: 0,
+            pwm_output: [0; 2],
+            muted: 0,
+            solo: 0,
+            channel_buffers: false,
+        }
+    }
+
+    /// Takes over the host-side settings, which are not part of save states
+    pub fn copy_output_settings(&mut self, other: &Sound) {
+        if self.sample_rate() != other.sample_rate() {
+            self.set_sample_rate(other.sample_rate());
+        }
+        self.set_output_filter(other.output_filter());
+        self.set_mp2k_mixer(other.mp2k.is_some());
+        self.muted = other.muted;
+        self.solo = other.solo;
+        self.channel_buffers = other.channel_buffers;
+    }
+
+    pub fn channel_muted(&self, ch: AudioChannel) -> bool {
+        self.muted & ch.bit() != 0
+    }
+
+    pub fn set_channel_muted(&mut self, ch: AudioChannel, muted: bool) {
+        self.muted = self.muted & !ch.bit() | if muted { ch.bit() } else { 0 };
+    }
+
+    pub fn channel_solo(&self, ch: AudioChannel) -> bool {
+        self.solo & ch.bit() != 0
+    }
+
+    /// While any channel is soloed, only soloed channels are mixed
+    pub fn set_channel_solo(&mut self, ch: AudioChannel, solo: bool) {
+        self.solo = self.solo & !ch.bit() | if solo { ch.bit() } else { 0 };
+    }
+
+    fn channel_audible(&self, ch: AudioChannel) -> bool {
+        if self.solo != 0 {
+            self.channel_solo(ch)
+        } else {
+            !self.channel_muted(ch)
+        }
+    }
+
+    pub fn set_channel_buffers(&mut self, enable: bool) {
+        self.channel_buffers = enable;
+        if !enable {
+            self.audio_buffer.channels.clear();
+        }
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.resampler.out_rate()
+    }
+
+    pub fn set_sample_rate(&mut self, rate: u32) {
+        assert!(rate > 0 && rate <= MAX_AUDIO_SAMPLE_RATE);
+        self.resampler.set_out_rate(rate);
+        self.set_output_filter(self.output_filter());
+        if self.mp2k.is_some() {
+            self.mp2k = Some(Mp2kMixer::new(rate));
+        }
+    }
+
+    pub fn output_filter(&self) -> Option<OutputFilter> {
+        self.filter.as_ref().map(|f| f.config())
+    }
+
+    pub fn set_output_filter(&mut self, filter: Option<OutputFilter>) {
+        self.filter = filter.map(|f| FilterState::new(f, self.sample_rate()));
+    }
+
+    pub fn set_mp2k_mixer(&mut self, enable: bool) {
+        if enable != self.mp2k.is_some() {
+            self.mp2k = enable.then(|| Mp2kMixer::new(self.sample_rate()));
+        }
+    }
+
+    pub fn mp2k_mixer_mut(&mut self) -> Option<&mut Mp2kMixer> {
+        self.mp2k.as_mut()
+    }
+
+    fn mp2k_active(&self) -> bool {
+        self.mp2k.as_ref().is_some_and(|m| m.active())
+    }
+
+    /// Starts logging register writes to `vgm`, beginning with the current
+    /// register values
+    pub fn start_vgm(&mut self, mut vgm: VgmRecorder, now: u64) {
+        vgm.set_wave_bank(None);
+        for ch in 0..2 {
+            vgm.dac_pan(now, ch, self.direct_sound[ch].output);
+        }
+        self.vgm = Some(vgm);
+
+        let mut regs = vec![
+            (0x084, (self.power_on as u8) << 7),
+            (0x080, self.nr50()),
+            (0x081, self.nr51()),
+            (0x060, self.pulse[0].read(0)),
+        ];
+        for (ch, base) in [(0, 0x060), (1, 0x066)] {
+            let freq = self.pulse[ch].frequency;
+            regs.extend([
+                (base + 2, self.pulse[ch].read(1)),
+                (base + 3, self.pulse[ch].read(2)),
+                (base + 4, freq as u8),
+                (base + 5, (freq >> 8) as u8 | self.pulse[ch].read(4)),
+            ]);
+        }
+        regs.extend([
+            (0x070, self.wave.read(0)),
+            (0x072, self.wave.read(1)),
+            (0x073, self.wave.read(2)),
+            (0x074, self.wave.frequency as u8),
+            (0x075, (self.wave.frequency >> 8) as u8 | self.wave.read(4)),
+            (0x078, self.noise.read(1)),
+            (0x079, self.noise.read(2)),
+            (0x07C, self.noise.read(3)),
+            (0x07D, self.noise.read(4)),
+        ]);
+        for (addr, data) in regs {
+            self.log_vgm(now, addr, data);
+        }
+    }
+
+    pub fn take_vgm(&mut self) -> Option<VgmRecorder> {
+        self.vgm.take()
+    }
+
+    pub fn vgm_recording(&self) -> bool {
+        self.vgm.is_some()
+    }
+
+    // Translates a register write to the DMG
+    fn log_vgm(&mut self, now: u64, addr: u32, data: u8) {
+        let Some(vgm) = &mut self.vgm else {
+            return;
+        };
+        match addr {
+            // The DMG has one bank and no 64 step mode, the playing bank is
+            // copied to its wave RAM when it changes
+            0x070 => {
+                if self.wave.steps {
+                    vgm.warn_64_steps();
+                }
+                if vgm.wave_bank() == Some(self.wave.ram_bank) {
+                    vgm.dmg_write(now, 0x0A, data & 0x80);
+                    return;
+                }
+                vgm.set_wave_bank(Some(self.wave.ram_bank));
+                vgm.dmg_write(now, 0x0A, 0);
+                let bank = self.wave.ram_bank as usize * 0x10;
+                for (i, &b) in self.wave.ram[bank..bank + 0x10].iter().enumerate() {
+                    vgm.dmg_write(now, DMG_WAVE_RAM + i as u8, b);
+                }
+                vgm.dmg_write(now, 0x0A, data & 0x80);
+                // Turning off the DAC stopped the channel
+                if self.wave.on {
+                    let hi = (self.wave.frequency >> 8) as u8 | self.wave.read(4);
+                    vgm.dmg_write(now, 0x0E, 0x80 | hi);
+                }
+            }
+            // No forced 75% volume on the DMG, use 100%
+            0x073 if data & 0x80 != 0 => vgm.dmg_write(now, 0x0C, 0x20),
+            0x083 => {
+                for ch in 0..2 {
+                    vgm.dac_pan(now, ch, self.direct_sound[ch].output);
+                }
+            }
+            0x084 => vgm.dmg_write(now, 0x16, data & 0x80),
+            _ => {
+                if let Some(reg) = dmg_register(addr) {
+                    vgm.dmg_write(now, reg, data);
+                }
+            }
         }
     }
 
@@ -69,6 +318,7 @@ impl Sound {
 
     pub fn clear_buf(&mut self) {
         self.audio_buffer.buf.clear();
+        self.audio_buffer.channels.clear();
     }
 
     fn set_power(&mut self, on: bool) {
@@ -104,6 +354,13 @@ impl Sound {
 
         for sound_ch in 0..2 {
             self.direct_sound[sound_ch].timer_overflow(ctx, timer_ch);
+
+            if let Some(vgm) = &mut self.vgm {
+                let ds = &self.direct_sound[sound_ch];
+                if ds.timer_ch == timer_ch {
+                    vgm.dac_write(ctx.now(), sound_ch, ds.current_output.unwrap_or(0));
+                }
+            }
         }
     }
 
@@ -155,35 +412,71 @@ impl Sound {
             self.noise.tick(length_tick, envelope_tick);
         }
 
-        // AUDIO_SAMPLE_PER_FRAME samples per DOTS_PER_LINE * LINES_PER_FRAME
-        const TICKS_PER_SECOND: u32 = 1024 * 1024;
+        let ch_output = self.channel_outputs();
+
+        // The PWM output is resampled at 32768 Hz for 9-bit resolution,
+        // doubling for each bit less
+        self.pwm_counter += 1;
+        if self.pwm_counter >= 32 >> self.amplitude_resolution {
+            self.pwm_counter = 0;
+            self.pwm_output = self.pwm_sample(self.mix_output(&ch_output));
+        }
 
-        self.sampling_counter += AUDIO_SAMPLES_PER_SECOND;
-        if self.sampling_counter >= TICKS_PER_SECOND {
-            self.sampling_counter -= TICKS_PER_SECOND;
-            let sample = self.mix_output();
-            self.audio_buffer.buf.push(sample);
+        let len = self.audio_buffer.buf.len();
+        self.resampler
+            .push(self.pwm_output, &mut self.audio_buffer.buf);
+        if self.mp2k_active() {
+            self.mix_mp2k(len);
+        }
+        if let Some(filter) = &mut self.filter {
+            filter.process(&mut self.audio_buffer.buf[len..]);
+        }
+
+        if self.channel_buffers {
+            // Point sampled at the output rate; meant for display, not playback
+            let channels = ch_output.map(|v| (v * 8).clamp(-32768, 32767) as i16);
+            for _ in len..self.audio_buffer.buf.len() {
+                self.audio_buffer.channels.push(channels);
+            }
         }
     }
 
-    fn mix_output(&mut self) -> AudioSample {
+    // Outputs of each `AudioChannel` before panning and volume control,
+    // normalized to -4096..4096
+    fn channel_outputs(&mut self) -> [i32; 6] {
         if !self.power_on {
-            return AudioSample::new(0, 0);
+            return [0; 6];
         }
 
-        let ch_output = [
-            self.pulse[0].output(),
-            self.pulse[1].output(),
-            self.wave.output(),
-            self.noise.output(),
-        ];
+        let ds_output = |ds: &DirectSound| {
+            // agb channel outputs' range is -128..127
+            ds.current_output
+                .map_or(0, |output| output as i8 as i32 * 32)
+        };
+
+        [
+            self.pulse[0].output() as i32,
+            self.pulse[1].output() as i32,
+            self.wave.output() as i32,
+            self.noise.output() as i32,
+            ds_output(&self.direct_sound[0]),
+            ds_output(&self.direct_sound[1]),
+        ]
+    }
 
-        // each channel's output are normalized to -4096..4096
+    // Right and left mixer output, before the bias is added
+    fn mix_output(&self, ch_output: &[i32; 6]) -> [i32; 2] {
+        if !self.power_on {
+            return [0; 2];
+        }
 
         let mut cgb_output = [0, 0];
 
         for ch in 0..4 {
-            let output = ch_output[ch] as i32;
+            if !self.channel_audible(AudioChannel::ALL[ch]) {
+                continue;
+            }
+            let output = ch_output[ch];
             for lr in 0..2 {
                 if self.channel_ctrl[lr].output_ch[ch] {
                     cgb_output[lr] += output * self.channel_ctrl[lr].volume as i32 / 8;
@@ -194,13 +487,13 @@ impl Sound {
         let mut agb_output = [0, 0];
 
         for ch in 0..2 {
-            if let Some(output) = self.direct_sound[ch].current_output {
-                // agb channel outputs' range is -128..127
-                let output = output as i8 as i32 * 32;
-                for lr in 0..2 {
-                    if self.direct_sound[ch].output[lr] {
-                        agb_output[lr] += output;
-                    }
+            if !self.channel_audible(AudioChannel::ALL[4 + ch]) || self.mp2k_active() {
+                continue;
+            }
+            let output = ch_output[4 + ch];
+            for lr in 0..2 {
+                if self.direct_sound[ch].output[lr] {
+                    agb_output[lr] += output;
                 }
             }
         }
@@ -211,13 +504,52 @@ impl Sound {
             0
         };
 
-        let output = [0, 1].map(|i| {
+        [0, 1].map(|i| {
             let agb_amp = self.output_ratio_direct_sound[i] as i32 + 1;
             let output = cgb_output[i] * cgb_amp / 4 + agb_output[i] * agb_amp * 4 / 2;
-            (output * 2).clamp(-32768, 32767) as i16
+            output * 2
+        })
+    }
+
+    // Adds the MP2K voices to the output samples from `start`, routed and
+    // scaled like the DirectSound channels they replace
+    fn mix_mp2k(&mut self, start: usize) {
+        if !self.power_on {
+            return;
+        }
+        let amp = [0, 1].map(|lr| {
+            (0..2)
+                .find(|&ch| {
+                    self.direct_sound[ch].output[lr]
+                        && self.channel_audible(AudioChannel::ALL[4 + ch])
+                })
+                .map_or(0.0, |ch| {
+                    (self.output_ratio_direct_sound[ch] as i32 + 1) as f32 * 128.0
+                })
         });
 
-        AudioSample::new(output[0], output[1])
+        let mixer = self.mp2k.as_mut().unwrap();
+        for s in &mut self.audio_buffer.buf[start..] {
+            let out = mixer.render();
+            let [right, left] = [(s.right, 0), (s.left, 1)].map(|(v, lr)| {
+                (v as f32 + out[lr] * amp[lr])
+                    .round()
+                    .clamp(-32768.0, 32767.0) as i16
+            });
+            *s = AudioSample::new(right, left);
+        }
+    }
+
+    // The mixer output plus the bias is clipped to 10 bits and truncated to
+    // the amplitude resolution. The bias itself is removed again by the
+    // coupling capacitor.
+    fn pwm_sample(&self, output: [i32; 2]) -> [i32; 2] {
+        let bias = self.bias_level as i32;
+        let mask = !((2 << self.amplitude_resolution) - 1);
+        output.map(|v| {
+            let level = ((v.div_euclid(PWM_SCALE) + bias).clamp(0, PWM_MAX) & mask) - bias;
+            level * PWM_SCALE
+        })
     }
 }
 
@@ -965,22 +1297,10 @@ impl Sound {
             0x07E..=0x07F => 0,
 
             // NR50: Channel control / ON-OFF / Volume (R/W)
-            0x080 => pack! {
-                0..=2 => self.channel_ctrl[0].volume,
-                4..=6 => self.channel_ctrl[1].volume,
-            },
+            0x080 => self.nr50(),
 
             // NR51: Selection of Sound output terminal (R/W)
-            0x081 => pack! {
-                7 => self.channel_ctrl[1].output_ch[3],
-                6 => self.channel_ctrl[1].output_ch[2],
-                5 => self.channel_ctrl[1].output_ch[1],
-                4 => self.channel_ctrl[1].output_ch[0],
-                3 => self.channel_ctrl[0].output_ch[3],
-                2 => self.channel_ctrl[0].output_ch[2],
-                1 => self.channel_ctrl[0].output_ch[1],
-                0 => self.channel_ctrl[0].output_ch[0],
-            },
+            0x081 => self.nr51(),
 
             // SOUNDCNT_H
             0x082 => pack! {
@@ -1028,7 +1348,34 @@ impl Sound {
         })
     }
 
-    pub fn write(&mut self, _ctx: &mut impl Context, addr: u32, data: u8) {
+    fn nr50(&self) -> u8 {
+        pack! {
+            0..=2 => self.channel_ctrl[0].volume,
+            4..=6 => self.channel_ctrl[1].volume,
+        }
+    }
+
+    fn nr51(&self) -> u8 {
+        pack! {
+            7 => self.channel_ctrl[1].output_ch[3],
+            6 => self.channel_ctrl[1].output_ch[2],
+            5 => self.channel_ctrl[1].output_ch[1],
+            4 => self.channel_ctrl[1].output_ch[0],
+            3 => self.channel_ctrl[0].output_ch[3],
+            2 => self.channel_ctrl[0].output_ch[2],
+            1 => self.channel_ctrl[0].output_ch[1],
+            0 => self.channel_ctrl[0].output_ch[0],
+        }
+    }
+
+    pub fn write(&mut self, ctx: &mut impl Context, addr: u32, data: u8) {
+        self.write_reg(addr, data);
+        if self.vgm.is_some() {
+            self.log_vgm(ctx.now(), addr, data);
+        }
+    }
+
+    fn write_reg(&mut self, addr: u32, data: u8) {
         match addr {
             0x060 => self.pulse[0].write(0, data),
             0x061 => {}
@@ -0,0 +1,438 @@
+use anyhow::Result;
+use flate2::{read::DeflateDecoder, write::DeflateEncoder};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt,
+    io::{Read, Write},
+};
+
+use crate::interface::FrameBuf;
+
+pub const STATE_MAGIC: &[u8; 8] = b"TGBASTAT";
+
+/// Bump this whenever the container or the serialized `Context` layout
+/// changes, and add a migration from the previous version to `MIGRATIONS`.
+pub const STATE_FORMAT_VERSION: u32 = 1;
+
+type Migration = fn(Vec<u8>) -> Result<Vec<u8>>;
+
+// MIGRATIONS[n - 1] converts a version n body into a version n + 1 body.
+// The headerless `bincode::serialize(&Context)` states of earlier releases
+// are not migrated: the `Context` layout changed along with the container.
+const MIGRATIONS: &[Migration] = &[];
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub enum StateCompression {
+    None,
+    Deflate,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct StateMetadata {
+    /// Seconds since the UNIX epoch
+    pub timestamp: u64,
+    pub frame: u64,
+    pub label: String,
+    /// Position in the movie being recorded or played back when saved
+    pub movie_frame: Option<u64>,
+}
+
+/// Half resolution RGB888 image of the screen at save time
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Thumbnail {
+    pub width: u32,
+    pub height: u32,
+    #[serde(with = "serde_bytes")]
+    pub rgb: Vec<u8>,
+}
+
+impl Thumbnail {
+    pub fn from_frame_buf(frame_buf: &FrameBuf) -> Self {
+        let width = frame_buf.width() / 2;
+        let height = frame_buf.height() / 2;
+        let mut rgb = Vec::with_capacity((width * height * 3) as usize);
+
+        for y in 0..height {
+            for x in 0..width {
+                let mut sum = [0_u32; 3];
+                for (dx, dy) in [(0, 0), (1, 0), (0, 1), (1, 1)] {
+                    let p = frame_buf.pixel(x * 2 + dx, y * 2 + dy);
+                    sum[0] += p.r as u32;
+                    sum[1] += p.g as u32;
+                    sum[2] += p.b as u32;
+                }
+                rgb.extend(sum.iter().map(|c| (c / 4) as u8));
+            }
+        }
+
+        Self { width, height, rgb }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct StateHeader {
+    pub emulator_version: String,
+    pub game_code: [u8; 4],
+    pub rom_crc32: u32,
+    pub compression: StateCompression,
+    pub metadata: StateMetadata,
+    pub thumbnail: Option<Thumbnail>,
+}
+
+#[derive(Clone, Debug)]
+pub struct SaveStateOptions {
+    pub compress: bool,
+    pub thumbnail: bool,
+    pub label: String,
+}
+
+impl Default for SaveStateOptions {
+    fn default() -> Self {
+        Self {
+            compress: true,
+            thumbnail: true,
+            label: String::new(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum StateError {
+    TooShort,
+    /// Headerless state of a release before format version 1
+    Legacy,
+    UnsupportedVersion {
+        found: u32,
+        supported: u32,
+    },
+    RomMismatch {
+        state_game_code: [u8; 4],
+        state_crc32: u32,
+        rom_game_code: [u8; 4],
+        rom_crc32: u32,
+    },
+    Corrupted(String),
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StateError::TooShort => write!(f, "Save state is truncated"),
+            StateError::Legacy => write!(
+                f,
+                "Save state was created by an older release without a versioned format and can not be loaded"
+            ),
+            StateError::UnsupportedVersion { found, supported } => write!(
+                f,
+                "Save state format version {found} is newer than supported version {supported}"
+            ),
+            StateError::RomMismatch {
+                state_game_code,
+                state_crc32,
+                rom_game_code,
+                rom_crc32,
+            } => write!(
+                f,
+                "Save state is for a different ROM: state: {} (CRC32: {state_crc32:08X}), loaded: {} (CRC32: {rom_crc32:08X})",
+                String::from_utf8_lossy(state_game_code),
+                String::from_utf8_lossy(rom_game_code),
+            ),
+            StateError::Corrupted(msg) => write!(f, "Save state is corrupted: {msg}"),
+        }
+    }
+}
+
+impl std::error::Error for StateError {}
+
+pub fn encode_state(header: &StateHeader, ctx: &[u8], frame_buf: Option<&FrameBuf>) -> Vec<u8> {
+    let mut body = (ctx.len() as u32).to_le_bytes().to_vec();
+    body.extend_from_slice(ctx);
+    if let Some(frame_buf) = frame_buf {
+        encode_frame(frame_buf, &mut body);
+    }
+
+    let body = match header.compression {
+        StateCompression::None => body,
+        StateCompression::Deflate => {
+            let mut enc = DeflateEncoder::new(vec![], flate2::Compression::fast());
+            enc.write_all(&body).unwrap();
+            enc.finish().unwrap()
+        }
+    };
+
+    let header = bincode::serialize(header).unwrap();
+
+    let mut ret = Vec::with_capacity(16 + header.len() + body.len());
+    ret.extend_from_slice(STATE_MAGIC);
+    ret.extend_from_slice(&STATE_FORMAT_VERSION.to_le_bytes());
+    ret.extend_from_slice(&(header.len() as u32).to_le_bytes());
+    ret.extend_from_slice(&header);
+    ret.extend_from_slice(&body);
+    ret
+}
+
+/// Reads the header (metadata and thumbnail) of a save state without
+/// decoding the body. `None` for headerless states of older releases.
+pub fn read_state_header(data: &[u8]) -> Result<Option<(u32, StateHeader)>, StateError> {
+    if !data.starts_with(STATE_MAGIC) {
+        return Ok(None);
+    }
+    let (version, header, _) = split_state(data)?;
+    Ok(Some((version, header)))
+}
+
+fn split_state(data: &[u8]) -> Result<(u32, StateHeader, &[u8]), StateError> {
+    if data.len() < 16 {
+        return Err(StateError::TooShort);
+    }
+    let version = u32::from_le_bytes(data[8..12].try_into().unwrap());
+    if version == 0 {
+        return Err(StateError::Corrupted(
+            "invalid format version 0".to_string(),
+        ));
+    }
+    if version > STATE_FORMAT_VERSION {
+        return Err(StateError::UnsupportedVersion {
+            found: version,
+            supported: STATE_FORMAT_VERSION,
+        });
+    }
+    let header_len = u32::from_le_bytes(data[12..16].try_into().unwrap()) as usize;
+    let header = data.get(16..16 + header_len).ok_or(StateError::TooShort)?;
+    // Add a match on `version` here when the header changes
+    let header = bincode::deserialize::<StateHeader>(header)
+        .map_err(|e| StateError::Corrupted(e.to_string()))?;
+    Ok((version, header, &data[16 + header_len..]))
+}
+
+/// Appends the BGR555 colours of `frame_buf`, little endian
+pub fn encode_frame(frame_buf: &FrameBuf, out: &mut Vec<u8>) {
+    out.extend(frame_buf.bgr555().iter().flat_map(|c| c.to_le_bytes()));
+}
+
+/// Inverse of `encode_frame`
+pub fn decode_frame(data: &[u8]) -> Vec<u16> {
+    data.chunks_exact(2)
+        .map(|c| u16::from_le_bytes([c[0], c[1]]))
+        .collect()
+}
+
+pub struct DecodedState {
+    pub ctx: Vec<u8>,
+    /// BGR555 frame buffer at save time, if embedded
+    pub frame: Option<Vec<u16>>,
+}
+
+/// Validates the container against the loaded ROM and returns the body
+/// migrated to the current format version.
+pub fn decode_state(
+    data: &[u8],
+    game_code: &[u8; 4],
+    rom_crc32: u32,
+) -> Result<DecodedState, StateError> {
+    if !data.starts_with(STATE_MAGIC) {
+        return Err(StateError::Legacy);
+    }
+    let (version, header, body) = split_state(data)?;
+
+    if &header.game_code != game_code || header.rom_crc32 != rom_crc32 {
+        return Err(StateError::RomMismatch {
+            state_game_code: header.game_code,
+            state_crc32: header.rom_crc32,
+            rom_game_code: *game_code,
+            rom_crc32,
+        });
+    }
+    if header.emulator_version != env!("CARGO_PKG_VERSION") {
+        warn!(
+            "Save state was created by tgba {}, running {}",
+            header.emulator_version,
+            env!("CARGO_PKG_VERSION")
+        );
+    }
+
+    let body = match header.compression {
+        StateCompression::None => body.to_vec(),
+        StateCompression::Deflate => {
+            let mut ret = vec![];
+            DeflateDecoder::new(body)
+                .read_to_end(&mut ret)
+                .map_err(|e| StateError::Corrupted(e.to_string()))?;
+            ret
+        }
+    };
+
+    let body = MIGRATIONS[version as usize - 1..]
+        .iter()
+        .try_fold(body, |body, migrate| migrate(body))
+        .map_err(|e| StateError::Corrupted(format!("migration failed: {e}")))?;
+
+    let ctx_len = body
+        .get(..4)
+        .map(|b| u32::from_le_bytes(b.try_into().unwrap()) as usize)
+        .ok_or(StateError::TooShort)?;
+    let ctx = body.get(4..4 + ctx_len).ok_or(StateError::TooShort)?;
+    let frame = &body[4 + ctx_len..];
+
+    Ok(DecodedState {
+        ctx: ctx.to_vec(),
+        frame: (!frame.is_empty()).then(|| decode_frame(frame)),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GAME_CODE: [u8; 4] = *b"ABCE";
+    const CRC32: u32 = 0x1234_5678;
+
+    fn header(compression: StateCompression, thumbnail: Option<Thumbnail>) -> StateHeader {
+        StateHeader {
+            emulator_version: env!("CARGO_PKG_VERSION").to_string(),
+            game_code: GAME_CODE,
+            rom_crc32: CRC32,
+            compression,
+            metadata: StateMetadata {
+                timestamp: 1_700_000_000,
+                frame: 42,
+                label: "boss".to_string(),
+                movie_frame: Some(7),
+            },
+            thumbnail,
+        }
+    }
+
+    fn frame_buf() -> FrameBuf {
+        let mut ret = FrameBuf::new(4, 2);
+        ret.set_bgr555(0, 0, 0x001F);
+        ret.set_bgr555(1, 0, 0x03E0);
+        ret.set_bgr555(3, 1, 0x7C00);
+        ret
+    }
+
+    // Container with a hand-written header and version
+    fn container(version: u32, header: &[u8], body: &[u8]) -> Vec<u8> {
+        let mut ret = STATE_MAGIC.to_vec();
+        ret.extend_from_slice(&version.to_le_bytes());
+        ret.extend_from_slice(&(header.len() as u32).to_le_bytes());
+        ret.extend_from_slice(header);
+        ret.extend_from_slice(body);
+        ret
+    }
+
+    #[test]
+    fn round_trip() {
+        let ctx = b"context bytes".repeat(100);
+        let mut fb = frame_buf();
+        // Transparent pixel of a layer buffer
+        fb.set_bgr555(2, 1, 0x8000 | 0x1234);
+        for compression in [StateCompression::None, StateCompression::Deflate] {
+            let data = encode_state(&header(compression, None), &ctx, Some(&fb));
+            let decoded = decode_state(&data, &GAME_CODE, CRC32).unwrap();
+            assert_eq!(decoded.ctx, ctx);
+            assert_eq!(decoded.frame.unwrap(), fb.bgr555());
+        }
+    }
+
+    #[test]
+    fn round_trip_without_frame() {
+        let data = encode_state(&header(StateCompression::Deflate, None), b"ctx", None);
+        let decoded = decode_state(&data, &GAME_CODE, CRC32).unwrap();
+        assert_eq!(decoded.ctx, b"ctx");
+        assert!(decoded.frame.is_none());
+    }
+
+    #[test]
+    fn deflate_is_smaller() {
+        let ctx = vec![0; 0x10000];
+        let plain = encode_state(&header(StateCompression::None, None), &ctx, None);
+        let packed = encode_state(&header(StateCompression::Deflate, None), &ctx, None);
+        assert!(packed.len() < plain.len() / 10);
+    }
+
+    #[test]
+    fn header_and_thumbnail() {
+        let thumbnail = Thumbnail::from_frame_buf(&frame_buf());
+        let data = encode_state(
+            &header(StateCompression::Deflate, Some(thumbnail)),
+            b"ctx",
+            None,
+        );
+        let (version, header) = read_state_header(&data).unwrap().unwrap();
+        assert_eq!(version, STATE_FORMAT_VERSION);
+        assert_eq!(header.game_code, GAME_CODE);
+        assert_eq!(header.rom_crc32, CRC32);
+        assert_eq!(header.compression, StateCompression::Deflate);
+        assert_eq!(header.metadata.frame, 42);
+        assert_eq!(header.metadata.label, "boss");
+        assert_eq!(header.metadata.movie_frame, Some(7));
+
+        let thumbnail = header.thumbnail.unwrap();
+        assert_eq!((thumbnail.width, thumbnail.height), (2, 1));
+        // Average of red, green and two black pixels, then of blue and three
+        // black pixels
+        assert_eq!(thumbnail.rgb, [63, 63, 0, 0, 0, 63]);
+    }
+
+    #[test]
+    fn headerless_state_rejected() {
+        let data = b"old context".to_vec();
+        assert!(read_state_header(&data).unwrap().is_none());
+        let err = decode_state(&data, &GAME_CODE, CRC32).err().unwrap();
+        assert!(matches!(err, StateError::Legacy));
+    }
+
+    #[test]
+    fn version_zero_container() {
+        let header = bincode::serialize(&header(StateCompression::None, None)).unwrap();
+        let data = container(0, &header, b"ctx");
+        let err = decode_state(&data, &GAME_CODE, CRC32).err().unwrap();
+        assert!(matches!(err, StateError::Corrupted(_)));
+    }
+
+    #[test]
+    fn rom_mismatch() {
+        let data = encode_state(&header(StateCompression::None, None), b"ctx", None);
+        let err = decode_state(&data, b"XYZE", CRC32).err().unwrap();
+        assert!(matches!(err, StateError::RomMismatch { .. }));
+        let err = decode_state(&data, &GAME_CODE, !CRC32).err().unwrap();
+        assert!(matches!(err, StateError::RomMismatch { .. }));
+    }
+
+    #[test]
+    fn newer_version() {
+        let mut data = encode_state(&header(StateCompression::None, None), b"ctx", None);
+        data[8..12].copy_from_slice(&(STATE_FORMAT_VERSION + 1).to_le_bytes());
+        let err = decode_state(&data, &GAME_CODE, CRC32).err().unwrap();
+        assert!(matches!(
+            err,
+            StateError::UnsupportedVersion { found, supported }
+                if found == STATE_FORMAT_VERSION + 1 && supported == STATE_FORMAT_VERSION
+        ));
+    }
+
+    #[test]
+    fn truncated() {
+        let data = encode_state(&header(StateCompression::None, None), b"ctx", None);
+        let err = decode_state(&data[..12], &GAME_CODE, CRC32).err().unwrap();
+        assert!(matches!(err, StateError::TooShort));
+        let err = decode_state(&data[..20], &GAME_CODE, CRC32).err().unwrap();
+        assert!(matches!(err, StateError::TooShort));
+        // Context length beyond the end of the body
+        let err = decode_state(&data[..data.len() - 1], &GAME_CODE, CRC32)
+            .err()
+            .unwrap();
+        assert!(matches!(err, StateError::TooShort));
+    }
+
+    #[test]
+    fn corrupted_deflate_body() {
+        let mut data = encode_state(&header(StateCompression::Deflate, None), b"ctx", None);
+        let len = data.len();
+        data[len - 4..].fill(0xFF);
+        let err = decode_state(&data, &GAME_CODE, CRC32).err().unwrap();
+        assert!(matches!(err, StateError::Corrupted(_)));
+    }
+}
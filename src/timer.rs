@@ -63,6 +63,16 @@ impl Timers {
         }
     }
 
+    /// Cycles until the next overflow of a running timer. Count-up timers
+    /// only overflow along with the timer before them.
+    pub fn cycles_to_overflow(&self) -> Option<u64> {
+        self.timer
+            .iter()
+            .filter(|t| t.enable)
+            .filter_map(|t| Some((0x10000 - t.counter as u64) * t.prescaler()? - t.fraction))
+            .min()
+    }
+
     pub fn tick(&mut self, ctx: &mut impl Context) {
         let prev_cycle = self.prev_cycle;
         let cur_cycle = ctx.now();
@@ -0,0 +1,190 @@
+use std::io::{self, Write};
+
+use crate::cpu::mode_name;
+
+/// Snapshot of the CPU state taken just before an instruction is executed.
+#[derive(Clone, Debug)]
+pub struct TraceRecord {
+    /// Address of the executed instruction
+    pub pc: u32,
+    pub opcode: u32,
+    pub thumb: bool,
+    pub mode: u8,
+    /// r0-r15 as seen by the instruction (r15 includes the pipeline offset)
+    pub regs: [u32; 16],
+    pub cpsr: u32,
+    pub cycle: u64,
+}
+
+pub trait TraceSink {
+    fn write_record(&mut self, record: &TraceRecord) -> io::Result<()>;
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum TraceFormat {
+    /// Fixed size little-endian records, preceded by a header
+    Binary,
+    /// Human readable text
+    Text,
+    /// Compatible with mGBA's debugger `trace` command
+    Mgba,
+    /// Compatible with NanoBoyAdvance's instruction trace
+    NanoBoyAdvance,
+}
+
+pub fn trace_writer<W: Write + Send + 'static>(
+    format: TraceFormat,
+    w: W,
+) -> Box<dyn TraceSink + Send> {
+    match format {
+        TraceFormat::Binary => Box::new(BinaryTraceWriter::new(w)),
+        TraceFormat::Text => Box::new(TextTraceWriter::new(w)),
+        TraceFormat::Mgba => Box::new(MgbaTraceWriter::new(w)),
+        TraceFormat::NanoBoyAdvance => Box::new(NbaTraceWriter::new(w)),
+    }
+}
+
+pub const BINARY_TRACE_MAGIC: &[u8; 8] = b"TGBATRC1";
+
+// Record layout (little-endian, 88 bytes):
+//   pc: u32, opcode: u32, cpsr: u32, flags: u32 (bit0: thumb, bit8-12: mode),
+//   regs: [u32; 16], cycle: u64
+pub struct BinaryTraceWriter<W: Write> {
+    w: W,
+    header_written: bool,
+}
+
+impl<W: Write> BinaryTraceWriter<W> {
+    pub fn new(w: W) -> Self {
+        Self {
+            w,
+            header_written: false,
+        }
+    }
+}
+
+impl<W: Write> TraceSink for BinaryTraceWriter<W> {
+    fn write_record(&mut self, record: &TraceRecord) -> io::Result<()> {
+        if !self.header_written {
+            self.w.write_all(BINARY_TRACE_MAGIC)?;
+            self.header_written = true;
+        }
+
+        let mut buf = [0_u8; 88];
+        let flags = record.thumb as u32 | (record.mode as u32) << 8;
+        for (i, v) in [record.pc, record.opcode, record.cpsr, flags]
+            .iter()
+            .chain(record.regs.iter())
+            .enumerate()
+        {
+            buf[i * 4..i * 4 + 4].copy_from_slice(&v.to_le_bytes());
+        }
+        buf[80..].copy_from_slice(&record.cycle.to_le_bytes());
+        self.w.write_all(&buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.w.flush()
+    }
+}
+
+pub struct TextTraceWriter<W: Write> {
+    w: W,
+}
+
+impl<W: Write> TextTraceWriter<W> {
+    pub fn new(w: W) -> Self {
+        Self { w }
+    }
+}
+
+impl<W: Write> TraceSink for TextTraceWriter<W> {
+    fn write_record(&mut self, record: &TraceRecord) -> io::Result<()> {
+        write!(self.w, "{:10} {:08X}: ", record.cycle, record.pc)?;
+        if record.thumb {
+            write!(self.w, "    {:04X}", record.opcode)?;
+        } else {
+            write!(self.w, "{:08X}", record.opcode)?;
+        }
+        write!(
+            self.w,
+            " {} CPSR:{:08X}",
+            mode_name(record.mode),
+            record.cpsr
+        )?;
+        for (i, r) in record.regs.iter().enumerate() {
+            write!(self.w, " r{i}:{r:08X}")?;
+        }
+        writeln!(self.w)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.w.flush()
+    }
+}
+
+// `r0 .. r15 cpsr: XXXXXXXX | XXXXXXXX:` as printed by mGBA's `trace` command.
+// mGBA appends its own disassembly after the opcode, so strip that column from
+// mGBA's log before diffing.
+pub struct MgbaTraceWriter<W: Write> {
+    w: W,
+}
+
+impl<W: Write> MgbaTraceWriter<W> {
+    pub fn new(w: W) -> Self {
+        Self { w }
+    }
+}
+
+impl<W: Write> TraceSink for MgbaTraceWriter<W> {
+    fn write_record(&mut self, record: &TraceRecord) -> io::Result<()> {
+        for r in record.regs.iter() {
+            write!(self.w, "{r:08X} ")?;
+        }
+        write!(self.w, "cpsr: {:08X} | ", record.cpsr)?;
+        if record.thumb {
+            writeln!(self.w, "    {:04X}:", record.opcode)
+        } else {
+            writeln!(self.w, "{:08X}:", record.opcode)
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.w.flush()
+    }
+}
+
+// `[T|A] PC: XXXXXXXX OP: XXXXXXXX R0: .. R15: CPSR: XXXXXXXX` as emitted by
+// NanoBoyAdvance's trace build.
+pub struct NbaTraceWriter<W: Write> {
+    w: W,
+}
+
+impl<W: Write> NbaTraceWriter<W> {
+    pub fn new(w: W) -> Self {
+        Self { w }
+    }
+}
+
+impl<W: Write> TraceSink for NbaTraceWriter<W> {
+    fn write_record(&mut self, record: &TraceRecord) -> io::Result<()> {
+        let state = if record.thumb { 'T' } else { 'A' };
+        write!(
+            self.w,
+            "[{state}] PC: {:08X} OP: {:08X}",
+            record.pc, record.opcode
+        )?;
+        for (i, r) in record.regs.iter().enumerate() {
+            write!(self.w, " R{i}: {r:08X}")?;
+        }
+        writeln!(self.w, " CPSR: {:08X}", record.cpsr)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.w.flush()
+    }
+}
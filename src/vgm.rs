@@ -0,0 +1,332 @@
+//! Recording of the sound registers to VGM files.
+//!
+//! The PSG is logged as a Game Boy DMG chip. DirectSound can optionally be
+//! logged as well, as writes to the DAC of two YM2612 chips (FIFO A and B),
+//! which is how VGM stores raw PCM.
+
+use log::warn;
+
+use crate::consts::SYSTEM_CLOCK;
+
+const VGM_MAGIC: &[u8] = b"Vgm ";
+const VGM_VERSION: u32 = 0x171;
+const HEADER_SIZE: usize = 0x100;
+const SAMPLE_RATE: u64 = 44100;
+
+const DMG_CLOCK: u32 = 4_194_304;
+const YM2612_CLOCK: u32 = 7_670_453;
+const DUAL_CHIP: u32 = 1 << 30;
+
+// Header offsets
+const EOF_OFFSET: usize = 0x04;
+const VERSION: usize = 0x08;
+const TOTAL_SAMPLES: usize = 0x18;
+const YM2612_CLOCK_OFFSET: usize = 0x2C;
+const DATA_OFFSET: usize = 0x34;
+const DMG_CLOCK_OFFSET: usize = 0x80;
+
+const CMD_WAIT: u8 = 0x61;
+const CMD_WAIT_NTSC: u8 = 0x62;
+const CMD_WAIT_PAL: u8 = 0x63;
+const CMD_WAIT_SHORT: u8 = 0x70;
+const CMD_END: u8 = 0x66;
+const CMD_DMG: u8 = 0xB3;
+// YM2612 port 0/1 of the first chip; the second chip is +0x50
+const CMD_YM2612: [u8; 2] = [0x52, 0x53];
+const YM2612_SECOND_CHIP: u8 = 0x50;
+
+const YM2612_DAC: u8 = 0x2A;
+const YM2612_DAC_ENABLE: u8 = 0x2B;
+// Panning of channel 6, which the DAC replaces
+const YM2612_PAN_CH6: u8 = 0xB6;
+
+/// DMG register (offset from NR10) of a GBA PSG register
+pub fn dmg_register(addr: u32) -> Option<u8> {
+    Some(match addr {
+        0x060 => 0x00,
+        0x062..=0x065 => (addr - 0x061) as u8,
+        0x068 | 0x069 => (addr - 0x062) as u8,
+        0x06C | 0x06D => (addr - 0x064) as u8,
+        0x070 => 0x0A,
+        0x072..=0x075 => (addr - 0x067) as u8,
+        0x078 | 0x079 => (addr - 0x068) as u8,
+        0x07C | 0x07D => (addr - 0x06A) as u8,
+        0x080 | 0x081 => (addr - 0x06C) as u8,
+        0x084 => 0x16,
+        _ => return None,
+    })
+}
+
+/// DMG register of the first byte of wave RAM
+pub const DMG_WAVE_RAM: u8 = 0x20;
+
+pub struct VgmRecorder {
+    pcm: bool,
+    // Clock at which `base` samples had been recorded
+    start: u64,
+    base: u64,
+    samples: u64,
+    data: Vec<u8>,
+    // Wave RAM bank last loaded into the DMG
+    wave_bank: Option<bool>,
+    dac: [Option<u8>; 2],
+    warned_64_steps: bool,
+}
+
+impl VgmRecorder {
+    pub fn new(now: u64, pcm: bool) -> Self {
+        let mut ret = Self {
+            pcm,
+            start: now,
+            base: 0,
+            samples: 0,
+            data: vec![],
+            wave_bank: None,
+            dac: [None; 2],
+            warned_64_steps: false,
+        };
+        if pcm {
+            for chip in 0..2 {
+                ret.ym2612_write(chip, 0, YM2612_DAC_ENABLE, 0x80);
+            }
+        }
+        ret
+    }
+
+    /// Continues at `new_now` what was recorded until `old_now`, for when
+    /// the clock restarts on resets and state loads
+    pub fn rebase(&mut self, old_now: u64, new_now: u64) {
+        self.base = self.sample_at(old_now);
+        self.start = new_now;
+    }
+
+    fn sample_at(&self, now: u64) -> u64 {
+        self.base + (now - self.start) * SAMPLE_RATE / SYSTEM_CLOCK
+    }
+
+    fn wait_until(&mut self, now: u64) {
+        let target = self.sample_at(now);
+        while self.samples < target {
+            let n = (target - self.samples).min(0xFFFF);
+            match n {
+                735 => self.data.push(CMD_WAIT_NTSC),
+                882 => self.data.push(CMD_WAIT_PAL),
+                1..=16 => self.data.push(CMD_WAIT_SHORT + n as u8 - 1),
+                _ => {
+                    self.data.push(CMD_WAIT);
+                    self.data.extend_from_slice(&(n as u16).to_le_bytes());
+                }
+            }
+            self.samples += n;
+        }
+    }
+
+    pub fn dmg_write(&mut self, now: u64, reg: u8, data: u8) {
+        self.wait_until(now);
+        self.data.extend_from_slice(&[CMD_DMG, reg, data]);
+    }
+
+    pub fn wave_bank(&self) -> Option<bool> {
+        self.wave_bank
+    }
+
+    pub fn set_wave_bank(&mut self, bank: Option<bool>) {
+        self.wave_bank = bank;
+    }
+
+    /// Only one bank fits in the DMG's wave RAM
+    pub fn warn_64_steps(&mut self) {
+        if !self.warned_64_steps {
+            warn!("VGM: 64 step wave playback is recorded as 32 steps");
+            self.warned_64_steps = true;
+        }
+    }
+
+    fn ym2612_write(&mut self, chip: usize, port: usize, reg: u8, data: u8) {
+        let cmd = CMD_YM2612[port] + if chip == 1 { YM2612_SECOND_CHIP } else { 0 };
+        self.data.extend_from_slice(&[cmd, reg, data]);
+    }
+
+    /// Routing of DirectSound `ch` to the right and left output
+    pub fn dac_pan(&mut self, now: u64, ch: usize, output: [bool; 2]) {
+        if self.pcm {
+            self.wait_until(now);
+            let pan = (output[1] as u8) << 7 | (output[0] as u8) << 6;
+            self.ym2612_write(ch, 1, YM2612_PAN_CH6, pan);
+        }
+    }
+
+    /// A sample played by DirectSound `ch`
+    pub fn dac_write(&mut self, now: u64, ch: usize, sample: u8) {
+        if self.pcm && self.dac[ch] != Some(sample) {
+            self.wait_until(now);
+            self.ym2612_write(ch, 0, YM2612_DAC, sample ^ 0x80);
+            self.dac[ch] = Some(sample);
+        }
+    }
+
+    /// Returns the VGM file
+    pub fn finish(mut self, now: u64) -> Vec<u8> {
+        self.wait_until(now);
+        self.data.push(CMD_END);
+
+        let mut ret = vec![0; HEADER_SIZE];
+        let mut put =
+            |offset: usize, v: u32| ret[offset..offset + 4].copy_from_slice(&v.to_le_bytes());
+        put(
+            EOF_OFFSET,
+            (HEADER_SIZE + self.data.len() - EOF_OFFSET) as u32,
+        );
+        put(VERSION, VGM_VERSION);
+        put(TOTAL_SAMPLES, self.samples as u32);
+        put(DATA_OFFSET, (HEADER_SIZE - DATA_OFFSET) as u32);
+        put(DMG_CLOCK_OFFSET, DMG_CLOCK);
+        if self.pcm {
+            put(YM2612_CLOCK_OFFSET, YM2612_CLOCK | DUAL_CHIP);
+        }
+        ret[..4].copy_from_slice(VGM_MAGIC);
+        ret.extend_from_slice(&self.data);
+        ret
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Clock at which `samples` samples have elapsed
+    fn at(samples: u64) -> u64 {
+        (samples * SYSTEM_CLOCK).div_ceil(SAMPLE_RATE)
+    }
+
+    fn u32_at(vgm: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(vgm[offset..offset + 4].try_into().unwrap())
+    }
+
+    fn commands(vgm: &[u8]) -> &[u8] {
+        &vgm[DATA_OFFSET + u32_at(vgm, DATA_OFFSET) as usize..]
+    }
+
+    #[test]
+    fn dmg_registers() {
+        let expected = [
+            // NR10 - NR14
+            (0x060, 0x00),
+            (0x062, 0x01),
+            (0x063, 0x02),
+            (0x064, 0x03),
+            (0x065, 0x04),
+            // NR21 - NR24
+            (0x068, 0x06),
+            (0x069, 0x07),
+            (0x06C, 0x08),
+            (0x06D, 0x09),
+            // NR30 - NR34
+            (0x070, 0x0A),
+            (0x072, 0x0B),
+            (0x073, 0x0C),
+            (0x074, 0x0D),
+            (0x075, 0x0E),
+            // NR41 - NR44
+            (0x078, 0x10),
+            (0x079, 0x11),
+            (0x07C, 0x12),
+            (0x07D, 0x13),
+            // NR50 - NR52
+            (0x080, 0x14),
+            (0x081, 0x15),
+            (0x084, 0x16),
+        ];
+        for addr in 0x060..0x0A0 {
+            let reg = expected.iter().find(|e| e.0 == addr).map(|e| e.1);
+            assert_eq!(dmg_register(addr), reg, "0x{addr:03X}");
+        }
+    }
+
+    #[test]
+    fn header() {
+        let vgm = VgmRecorder::new(0, false).finish(at(44100));
+        assert_eq!(&vgm[..4], VGM_MAGIC);
+        assert_eq!(u32_at(&vgm, EOF_OFFSET) as usize, vgm.len() - EOF_OFFSET);
+        assert_eq!(u32_at(&vgm, VERSION), 0x171);
+        assert_eq!(u32_at(&vgm, TOTAL_SAMPLES), 44100);
+        assert_eq!(u32_at(&vgm, DATA_OFFSET), 0xCC);
+        assert_eq!(u32_at(&vgm, YM2612_CLOCK_OFFSET), 0);
+        assert_eq!(u32_at(&vgm, DMG_CLOCK_OFFSET), 4_194_304);
+        assert_eq!(vgm.len(), HEADER_SIZE + 4);
+    }
+
+    #[test]
+    fn waits() {
+        let mut rec = VgmRecorder::new(0, false);
+        let mut samples = 0;
+        for wait in [0, 735, 882, 5, 16, 17, 1000] {
+            samples += wait;
+            rec.dmg_write(at(samples), 0x16, 0x80);
+        }
+        let vgm = rec.finish(at(samples + 70000));
+
+        #[rustfmt::skip]
+        let expected = [
+            0xB3, 0x16, 0x80,
+            0x62, 0xB3, 0x16, 0x80,
+            0x63, 0xB3, 0x16, 0x80,
+            0x74, 0xB3, 0x16, 0x80,
+            0x7F, 0xB3, 0x16, 0x80,
+            0x61, 0x11, 0x00, 0xB3, 0x16, 0x80,
+            0x61, 0xE8, 0x03, 0xB3, 0x16, 0x80,
+            // 70000 = 0xFFFF + 0x1171
+            0x61, 0xFF, 0xFF, 0x61, 0x71, 0x11,
+            0x66,
+        ];
+        assert_eq!(commands(&vgm), expected);
+        assert_eq!(u32_at(&vgm, TOTAL_SAMPLES), samples as u32 + 70000);
+    }
+
+    #[test]
+    fn rebase() {
+        let mut rec = VgmRecorder::new(1000, false);
+        rec.dmg_write(1000 + at(100), 0x00, 0x01);
+        rec.rebase(1000 + at(100), 0);
+        rec.dmg_write(at(10), 0x00, 0x02);
+        let vgm = rec.finish(at(10));
+        assert_eq!(
+            commands(&vgm),
+            [0x61, 100, 0, 0xB3, 0x00, 0x01, 0x79, 0xB3, 0x00, 0x02, 0x66]
+        );
+        assert_eq!(u32_at(&vgm, TOTAL_SAMPLES), 110);
+    }
+
+    #[test]
+    fn pcm() {
+        let mut rec = VgmRecorder::new(0, true);
+        rec.dac_write(0, 0, 0x00);
+        rec.dac_write(0, 0, 0x00);
+        rec.dac_write(at(1), 1, 0xFF);
+        rec.dac_pan(at(1), 0, [true, false]);
+        rec.dac_pan(at(1), 1, [true, true]);
+        let vgm = rec.finish(at(1));
+
+        assert_eq!(u32_at(&vgm, YM2612_CLOCK_OFFSET), 7_670_453 | DUAL_CHIP);
+        #[rustfmt::skip]
+        let expected = [
+            // DAC enable on both chips
+            0x52, 0x2B, 0x80, 0xA2, 0x2B, 0x80,
+            // Signed samples are stored unsigned, repeats are dropped
+            0x52, 0x2A, 0x80,
+            0x70, 0xA2, 0x2A, 0x7F,
+            0x53, 0xB6, 0x40,
+            0xA3, 0xB6, 0xC0,
+            0x66,
+        ];
+        assert_eq!(commands(&vgm), expected);
+    }
+
+    #[test]
+    fn pcm_disabled() {
+        let mut rec = VgmRecorder::new(0, false);
+        rec.dac_write(at(5), 0, 0x10);
+        rec.dac_pan(at(5), 0, [true, true]);
+        assert_eq!(commands(&rec.finish(at(5))), [0x74, 0x66]);
+    }
+}